@@ -0,0 +1,47 @@
+use futures::{Future, Stream};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request};
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use tokio::runtime::current_thread::Runtime;
+
+/// Makes JSON-RPC calls to a node, one at a time
+pub struct RpcClient {
+    address: SocketAddr,
+}
+
+impl RpcClient {
+    pub fn new(address: SocketAddr) -> RpcClient {
+        RpcClient { address }
+    }
+
+    /// Returns the call's result, or the node's error as an error
+    pub fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+            "id": 1,
+        });
+        let request = Request::post(format!("http://{}/", self.address))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .map_err(Error::other)?;
+        let response = Client::new()
+            .request(request)
+            .and_then(|response| response.into_body().concat2())
+            .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e));
+        let body = Runtime::new()?.block_on(response)?;
+
+        let mut response: Value = serde_json::from_slice(&body)?;
+        match response.get("error") {
+            Some(error) => Err(Error::other(format!(
+                "{} (code {})",
+                error["message"].as_str().unwrap_or("Unknown error"),
+                error["code"]
+            ))),
+            None => Ok(response["result"].take()),
+        }
+    }
+}
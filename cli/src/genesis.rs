@@ -0,0 +1,80 @@
+use redistribution::{Genesis, Output};
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{amount, print_json, usage};
+
+/// Runs `genesis`, whose arguments follow the word `genesis`
+pub fn run(args: &[String]) -> Result<()> {
+    let mut genesis = Genesis {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(Error::other)?,
+        allocations: vec![],
+        difficulty: 0,
+    };
+    // whole seconds, as the hash only commits to microseconds and the params are written in seconds
+    genesis.timestamp = Duration::from_secs(genesis.timestamp.as_secs());
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| usage(&format!("Missing {} after {}", name, arg)))
+        };
+        match arg.as_str() {
+            "--timestamp" => {
+                let seconds = value("SECONDS")?.parse().map_err(|_e| {
+                    usage("Could not parse the timestamp, expected seconds since the Unix epoch")
+                })?;
+                genesis.timestamp = Duration::from_secs(seconds);
+            }
+            "--difficulty" => {
+                genesis.difficulty = value("BITS")?.parse().map_err(|_e| {
+                    usage("Could not parse the difficulty, expected a number of leading zero bits")
+                })?;
+            }
+            allocation => {
+                let (address, amount_paid) = allocation.split_once(':').ok_or_else(|| {
+                    usage(&format!(
+                        "Could not parse allocation {}, expected ADDRESS:AMOUNT",
+                        allocation
+                    ))
+                })?;
+                genesis.allocations.push(Output {
+                    address: address.to_string(),
+                    amount: amount(amount_paid)?,
+                });
+            }
+        }
+    }
+    if genesis.difficulty > 32 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "A difficulty above 32 bits would take far too long to mine",
+        ));
+    }
+
+    // checked before what may be a long wait
+    genesis.data()?;
+    eprintln!(
+        "Mining a genesis block at difficulty {}...",
+        genesis.difficulty
+    );
+    let block = genesis.mine()?;
+    print_json(&serde_json::to_value(&block)?)?;
+    println!(
+        "\nChainParams fields for the network starting from it:
+    genesis_timestamp: Duration::from_secs({}),
+    genesis_data: {:?},
+    genesis_nonce: {},
+    genesis_hash: {:?},
+    initial_difficulty: {},",
+        block.timestamp.as_secs(),
+        block.data,
+        block.nonce,
+        block.hash,
+        block.difficulty
+    );
+    Ok(())
+}
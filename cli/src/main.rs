@@ -0,0 +1,256 @@
+use redistribution::{select_outputs, KeyPair, OutPoint, Output, Transaction, Unspent};
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+
+use client::RpcClient;
+
+mod client;
+mod genesis;
+mod wallet;
+
+/// Where the JSON-RPC server of a node on the default port listens
+pub const DEFAULT_RPC_ADDRESS: &str = "127.0.0.1:8878";
+
+/// Where key pairs are kept when no key file is given
+pub const DEFAULT_KEY_FILE: &str = "wallet.pem";
+
+const USAGE: &str = "Usage: redistribution-cli [--rpc ADDRESS] COMMAND
+
+Talks to the node whose JSON-RPC server listens at ADDRESS, which is set by --rpc
+or the RPC_ADDRESS environment variable and is 127.0.0.1:8878 by default.
+
+Commands:
+    keygen [KEY_FILE]          Generates a key pair, stored at KEY_FILE or wallet.pem
+    address [KEY_FILE]         Shows the address of a stored key pair
+    balance ADDRESS            Shows how much an address holds
+    unspent ADDRESS            Lists the outputs paid to an address not yet spent
+    build FROM TO AMOUNT       Prints an unsigned transaction paying AMOUNT from FROM to TO
+    sign KEY_FILE TRANSACTION  Prints the transaction with its inputs signed by the key pair
+    submit TRANSACTION         Submits a signed transaction, printing its ID
+    send KEY_FILE TO AMOUNT    Builds, signs and submits a payment from the key pair's address
+    block HEIGHT|HASH          Shows a block
+    tip                        Shows the height and hash of the node's latest block
+    peers                      Lists the node's peers
+    mempool                    Shows how many transactions are waiting for a block
+
+    genesis [--timestamp SECONDS] [--difficulty BITS] [ADDRESS:AMOUNT ...]
+        Mines a genesis block for a new network, paying each ADDRESS its AMOUNT up front,
+        and prints the ChainParams fields that make nodes start from it. Needs no node.
+
+Wallet commands keep keys in a password-encrypted keystore, wallet.json unless WALLET_FILE is given.
+The password is read from the WALLET_PASSWORD environment variable, or asked for.
+    wallet create [WALLET_FILE]             Creates a wallet, showing the seed phrase that restores it
+    wallet restore [WALLET_FILE]            Restores a wallet from a seed phrase, finding its used addresses
+    wallet address [WALLET_FILE]            Derives a new address to be paid at
+    wallet addresses [WALLET_FILE]          Lists the wallet's addresses and what each holds
+    wallet balance [WALLET_FILE]            Shows how much the wallet holds
+    wallet send WALLET_FILE TO AMOUNT       Pays from the wallet, with change to a new address of its own";
+
+fn main() {
+    if let Err(err) = run(env::args().skip(1).collect()) {
+        eprintln!("{}", err);
+        process::exit(1)
+    }
+}
+
+fn run(args: Vec<String>) -> Result<()> {
+    let mut args = args.into_iter();
+    let mut command = args.next();
+    let mut rpc_address =
+        env::var("RPC_ADDRESS").unwrap_or_else(|_e| DEFAULT_RPC_ADDRESS.to_string());
+    if command.as_deref() == Some("--rpc") {
+        rpc_address = args
+            .next()
+            .ok_or_else(|| usage("Missing the RPC address"))?;
+        command = args.next();
+    }
+    let rpc_address: SocketAddr = rpc_address
+        .parse()
+        .map_err(|_e| usage("Could not parse the RPC address"))?;
+    let client = RpcClient::new(rpc_address);
+
+    let args: Vec<String> = args.collect();
+    let arg = |index: usize, name: &str| -> Result<&str> {
+        args.get(index)
+            .map(String::as_str)
+            .ok_or_else(|| usage(&format!("Missing {}", name)))
+    };
+    let key_file = args.first().map(String::as_str).unwrap_or(DEFAULT_KEY_FILE);
+
+    match command.as_deref() {
+        Some("keygen") => {
+            let path = Path::new(key_file);
+            if path.exists() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} already exists, not overwriting it", path.display()),
+                ));
+            }
+            let key = KeyPair::generate()?;
+            key.save(path)?;
+            println!("{}", key.address()?);
+        }
+        Some("address") => println!("{}", load_key(key_file)?.address()?),
+        Some("balance") => {
+            let balance = client.call("getbalance", json!([arg(0, "ADDRESS")?]))?;
+            println!("{}", balance);
+        }
+        Some("unspent") => {
+            let unspent = client.call("listunspent", json!([arg(0, "ADDRESS")?]))?;
+            print_json(&unspent)?;
+        }
+        Some("build") => {
+            let (from, to) = (arg(0, "FROM")?, arg(1, "TO")?);
+            let transaction = build(&client, from, to, amount(arg(2, "AMOUNT")?)?)?;
+            println!("{}", transaction.to_data()?);
+        }
+        Some("sign") => {
+            let key = load_key(arg(0, "KEY_FILE")?)?;
+            let mut transaction = parse_transaction(arg(1, "TRANSACTION")?)?;
+            sign_all(&mut transaction, &key)?;
+            println!("{}", transaction.to_data()?);
+        }
+        Some("submit") => {
+            let transaction = parse_transaction(arg(0, "TRANSACTION")?)?;
+            println!("{}", submit(&client, &transaction)?);
+        }
+        Some("send") => {
+            let key = load_key(arg(0, "KEY_FILE")?)?;
+            let (to, amount) = (arg(1, "TO")?, amount(arg(2, "AMOUNT")?)?);
+            let mut transaction = build(&client, &key.address()?, to, amount)?;
+            sign_all(&mut transaction, &key)?;
+            println!("{}", submit(&client, &transaction)?);
+        }
+        Some("block") => {
+            let block = arg(0, "HEIGHT or HASH")?;
+            let param = match block.parse::<u64>() {
+                Ok(height) => json!(height),
+                Err(_e) => json!(block),
+            };
+            print_json(&client.call("getblock", json!([param]))?)?;
+        }
+        Some("tip") => {
+            let height = client.call("getblockcount", json!([]))?;
+            let hash = client.call("getbestblockhash", json!([]))?;
+            println!("{} {}", height, hash.as_str().unwrap_or_default());
+        }
+        Some("wallet") => wallet::run(&client, &args)?,
+        Some("genesis") => genesis::run(&args)?,
+        Some("peers") => print_json(&client.call("getpeerinfo", json!([]))?)?,
+        Some("mempool") => print_json(&client.call("getmempoolinfo", json!([]))?)?,
+        Some(command) => return Err(usage(&format!("Unknown command {}", command))),
+        None => return Err(usage("Missing a command")),
+    }
+    Ok(())
+}
+
+fn usage(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("{}\n\n{}", message, USAGE))
+}
+
+fn load_key(path: &str) -> Result<KeyPair> {
+    let pem = fs::read(path).map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    KeyPair::from_pem(&pem)
+}
+
+fn amount(amount: &str) -> Result<u64> {
+    amount
+        .parse()
+        .map_err(|_e| usage("Could not parse the amount, expected a whole number"))
+}
+
+fn parse_transaction(data: &str) -> Result<Transaction> {
+    Transaction::from_data(data)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Could not parse the transaction"))
+}
+
+fn print_json(value: &Value) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Pays `amount` to `to` from the outputs `from` holds on the node's chain
+fn build(client: &RpcClient, from: &str, to: &str, amount: u64) -> Result<Transaction> {
+    let unspent = client.call("listunspent", json!([from]))?;
+    let unspent: Vec<Unspent> = serde_json::from_value(unspent)?;
+    select(unspent, from, to, amount)
+}
+
+/// Pays from one address's outputs, returning what's left over to it
+fn select(unspent: Vec<Unspent>, from: &str, to: &str, amount: u64) -> Result<Transaction> {
+    let available: u64 = unspent.iter().map(|u| u.output.amount).sum();
+    let (selected, total) = select_outputs(unspent, amount).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} holds {}, not enough to pay {}", from, available, amount),
+        )
+    })?;
+    let mut outputs = vec![Output {
+        address: to.to_string(),
+        amount,
+    }];
+    if total > amount {
+        outputs.push(Output {
+            address: from.to_string(),
+            amount: total - amount,
+        });
+    }
+    let spent = selected.into_iter().map(|u| u.out_point).collect();
+    Ok(Transaction::new(spent, outputs))
+}
+
+/// Signs every input with the key pair, which should own them all as `build` only spends from one address
+fn sign_all(transaction: &mut Transaction, key: &KeyPair) -> Result<()> {
+    let owned: Vec<OutPoint> = transaction
+        .inputs
+        .iter()
+        .map(|input| input.previous.clone())
+        .collect();
+    transaction.sign(key, &owned)?;
+    Ok(())
+}
+
+fn submit(client: &RpcClient, transaction: &Transaction) -> Result<String> {
+    let id = client.call("sendrawtransaction", json!([transaction.to_data()?]))?;
+    Ok(id.as_str().unwrap_or_default().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_returns_change() {
+        let unspent = |transaction: &str, amount: u64| Unspent {
+            out_point: OutPoint {
+                transaction: transaction.to_string(),
+                output: 0,
+            },
+            output: Output {
+                address: "from".to_string(),
+                amount,
+            },
+        };
+        let unspent = vec![unspent("a", 20), unspent("b", 20), unspent("c", 20)];
+
+        let transaction = select(unspent.clone(), "from", "to", 30).unwrap();
+        assert_eq!(transaction.inputs.len(), 2);
+        assert_eq!(transaction.outputs[0].amount, 30);
+        assert_eq!(transaction.outputs[1].address, "from");
+        assert_eq!(transaction.outputs[1].amount, 10);
+
+        assert_eq!(
+            select(unspent.clone(), "from", "to", 40)
+                .unwrap()
+                .outputs
+                .len(),
+            1
+        );
+        assert!(select(unspent, "from", "to", 61).is_err());
+    }
+}
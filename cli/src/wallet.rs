@@ -0,0 +1,124 @@
+use redistribution::{Chain, Unspent, Wallet};
+use serde_json::json;
+use std::env;
+use std::io::{self, BufRead, Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+use crate::client::RpcClient;
+use crate::{amount, submit, usage};
+
+/// Where the wallet is kept when no wallet file is given
+pub const DEFAULT_WALLET_FILE: &str = "wallet.json";
+
+/// Runs `wallet` commands, whose arguments follow the word `wallet`
+pub fn run(client: &RpcClient, args: &[String]) -> Result<()> {
+    let arg = |index: usize, name: &str| -> Result<&str> {
+        args.get(index)
+            .map(String::as_str)
+            .ok_or_else(|| usage(&format!("Missing {}", name)))
+    };
+    let path = Path::new(
+        args.get(1)
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_WALLET_FILE),
+    );
+
+    match arg(0, "a wallet command")? {
+        "create" => {
+            refuse_overwrite(path)?;
+            let mut wallet = Wallet::generate()?;
+            let address = wallet.derive(Chain::Receiving)?;
+            wallet.save(path, &password()?)?;
+            println!(
+                "Seed phrase, write it down and keep it safe:\n{}",
+                wallet.phrase()
+            );
+            println!("Address: {}", address);
+        }
+        "restore" => {
+            refuse_overwrite(path)?;
+            let mut wallet = Wallet::from_phrase(&prompt("Seed phrase: ")?)?;
+            wallet.discover(|address| {
+                let info = client.call("getaddressinfo", json!([address]))?;
+                Ok(info["received"].as_bool().unwrap_or(false))
+            })?;
+            wallet.save(path, &password()?)?;
+            let unspent = unspent(client, &wallet)?;
+            println!(
+                "Found {} used addresses holding {}",
+                wallet.addresses().len(),
+                total(&unspent)
+            );
+        }
+        "address" => {
+            let password = password()?;
+            let mut wallet = Wallet::open(path, &password)?;
+            let address = wallet.derive(Chain::Receiving)?;
+            wallet.save(path, &password)?;
+            println!("{}", address);
+        }
+        "addresses" => {
+            let wallet = Wallet::open(path, &password()?)?;
+            for address in wallet.addresses() {
+                let balance = client.call("getbalance", json!([address]))?;
+                println!("{} {}", address, balance);
+            }
+        }
+        "balance" => {
+            let wallet = Wallet::open(path, &password()?)?;
+            println!("{}", total(&unspent(client, &wallet)?));
+        }
+        "send" => {
+            let path = Path::new(arg(1, "WALLET_FILE")?);
+            let (to, amount) = (arg(2, "TO")?, amount(arg(3, "AMOUNT")?)?);
+            let password = password()?;
+            let mut wallet = Wallet::open(path, &password)?;
+            let transaction = wallet.pay(unspent(client, &wallet)?, to, amount)?;
+            // saved first, so a change address is never lost once it may have been paid
+            wallet.save(path, &password)?;
+            println!("{}", submit(client, &transaction)?);
+        }
+        command => return Err(usage(&format!("Unknown wallet command {}", command))),
+    }
+    Ok(())
+}
+
+/// The outputs the wallet's addresses hold on the node's chain
+fn unspent(client: &RpcClient, wallet: &Wallet) -> Result<Vec<Unspent>> {
+    let mut unspent = vec![];
+    for address in wallet.addresses() {
+        let held = client.call("listunspent", json!([address]))?;
+        unspent.extend(serde_json::from_value::<Vec<Unspent>>(held)?);
+    }
+    Ok(unspent)
+}
+
+fn total(unspent: &[Unspent]) -> u64 {
+    unspent.iter().map(|u| u.output.amount).sum()
+}
+
+fn refuse_overwrite(path: &Path) -> Result<()> {
+    if path.exists() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists, not overwriting it", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+fn password() -> Result<String> {
+    match env::var("WALLET_PASSWORD") {
+        Ok(password) => Ok(password),
+        Err(_e) => prompt("Wallet password: "),
+    }
+}
+
+/// Asks for a line on standard input. It is echoed, so set `WALLET_PASSWORD` where that matters.
+fn prompt(question: &str) -> Result<String> {
+    eprint!("{}", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
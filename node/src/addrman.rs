@@ -0,0 +1,281 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// The most addresses remembered, the least promising are forgotten beyond this
+pub const MAX_ADDRESSES: usize = 2000;
+
+/// The most addresses sent or accepted in a single `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 250;
+
+/// How many outbound connections the node tries to keep open
+pub const TARGET_OUTBOUND: usize = 8;
+
+/// How long a dial has to complete its handshake before it no longer counts towards the target
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before dialling an address again
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A peer's address and when it was last known to be reachable, in seconds since the unix epoch
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AddressEntry {
+    pub address: SocketAddr,
+    pub last_seen: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AddressInfo {
+    last_seen: u64,
+    successes: u32,
+    /// Connection attempts since the last that succeeded
+    failures: u32,
+    #[serde(skip)]
+    last_attempt: Option<Instant>,
+}
+
+/// Every peer address we have heard of, with how reliable it has been,
+/// so outbound connections can be chosen from more than a single root node.
+/// Addresses are written to the address file as they connect, so they outlast a restart.
+#[derive(Debug, Default)]
+pub struct AddressManager {
+    addresses: HashMap<SocketAddr, AddressInfo>,
+    path: Option<PathBuf>,
+}
+
+impl AddressManager {
+    /// An address manager which isn't persisted
+    #[cfg(test)]
+    pub fn new() -> AddressManager {
+        AddressManager::default()
+    }
+
+    /// Reads known addresses from a file, which doesn't need to exist yet
+    pub fn load(path: PathBuf) -> Result<AddressManager> {
+        let addresses = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(AddressManager {
+            addresses,
+            path: Some(path),
+        })
+    }
+
+    /// Remembers an address, returning false if it was already known or can't be dialled
+    pub fn add(&mut self, address: SocketAddr, last_seen: u64) -> bool {
+        if address.port() == 0 || address.ip().is_unspecified() {
+            return false;
+        }
+        if let Some(info) = self.addresses.get_mut(&address) {
+            info.last_seen = info.last_seen.max(last_seen);
+            return false;
+        }
+        if self.addresses.len() >= MAX_ADDRESSES {
+            self.forget_worst();
+        }
+        self.addresses.insert(
+            address,
+            AddressInfo {
+                last_seen,
+                ..AddressInfo::default()
+            },
+        );
+        true
+    }
+
+    /// Records a dial, which counts as a failure until its handshake completes
+    pub fn mark_attempt(&mut self, address: SocketAddr, now: Instant) {
+        let info = self.addresses.entry(address).or_default();
+        info.failures += 1;
+        info.last_attempt = Some(now);
+    }
+
+    /// Records a completed handshake with a peer we dialled
+    pub fn mark_connected(&mut self, address: SocketAddr, last_seen: u64) -> Result<()> {
+        let info = self.addresses.entry(address).or_default();
+        info.successes += 1;
+        info.failures = 0;
+        info.last_seen = last_seen;
+        info.last_attempt = None;
+        self.save()
+    }
+
+    /// Dials still waiting on their handshake, which count towards the outbound target
+    pub fn pending(&self, now: Instant) -> usize {
+        self.addresses
+            .values()
+            .filter(|info| match info.last_attempt {
+                Some(attempt) => now.duration_since(attempt) < CONNECT_TIMEOUT,
+                None => false,
+            })
+            .count()
+    }
+
+    /// Chooses up to `count` addresses to dial, other than those excluded or tried recently.
+    /// The most reliable addresses come first, but addresses in network groups we aren't yet
+    /// connected to are preferred, so our peers can't all be run by one operator.
+    pub fn select(
+        &self,
+        count: usize,
+        exclude: &HashSet<SocketAddr>,
+        now: Instant,
+    ) -> Vec<SocketAddr> {
+        let mut candidates: Vec<(&SocketAddr, &AddressInfo)> = self
+            .addresses
+            .iter()
+            .filter(|(address, _)| !exclude.contains(address))
+            .filter(|(_, info)| match info.last_attempt {
+                Some(attempt) => now.duration_since(attempt) >= RETRY_INTERVAL,
+                None => true,
+            })
+            .collect();
+        candidates.sort_by_key(|(address, info)| {
+            (
+                info.failures,
+                u32::MAX - info.successes,
+                u64::MAX - info.last_seen,
+                **address,
+            )
+        });
+
+        let mut groups: HashSet<Vec<u8>> = exclude.iter().map(network_group).collect();
+        let (diverse, rest): (Vec<_>, Vec<_>) = candidates
+            .into_iter()
+            .map(|(address, _)| *address)
+            .partition(|address| groups.insert(network_group(address)));
+        diverse.into_iter().chain(rest).take(count).collect()
+    }
+
+    /// The most recently seen addresses, to answer a peer's `GetAddr`
+    pub fn to_share(&self) -> Vec<AddressEntry> {
+        let mut entries: Vec<AddressEntry> = self
+            .addresses
+            .iter()
+            .map(|(address, info)| AddressEntry {
+                address: *address,
+                last_seen: info.last_seen,
+            })
+            .collect();
+        entries.sort_by_key(|entry| (u64::MAX - entry.last_seen, entry.address));
+        entries.truncate(MAX_ADDR_PER_MESSAGE);
+        entries
+    }
+
+    pub fn save(&self) -> Result<()> {
+        match &self.path {
+            Some(path) => fs::write(path, serde_json::to_string(&self.addresses)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Drops every port known at an address, such as one that has been banned
+    pub fn forget(&mut self, address: IpAddr) {
+        self.addresses.retain(|known, _| known.ip() != address);
+    }
+
+    /// Makes room by dropping the address that has failed the most, then the one seen longest ago
+    fn forget_worst(&mut self) {
+        let worst = self
+            .addresses
+            .iter()
+            .min_by_key(|(address, info)| {
+                (
+                    info.successes > 0,
+                    u32::MAX - info.failures,
+                    info.last_seen,
+                    **address,
+                )
+            })
+            .map(|(address, _)| *address);
+        if let Some(address) = worst {
+            self.addresses.remove(&address);
+        }
+    }
+}
+
+/// The block an address belongs to, a /16 for IPv4 and a /32 for IPv6,
+/// as addresses within one are likely to be run by the same operator
+fn network_group(address: &SocketAddr) -> Vec<u8> {
+    match address.ip() {
+        IpAddr::V4(ip) => ip.octets()[..2].to_vec(),
+        IpAddr::V6(ip) => ip.octets()[..4].to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_selection_prefers_reliable_and_diverse_addresses() {
+        let now = Instant::now();
+        let mut addresses = AddressManager::new();
+        for known in [
+            "10.0.0.1:7878",
+            "10.0.0.2:7878",
+            "10.1.0.1:7878",
+            "10.2.0.1:7878",
+        ]
+        .iter()
+        {
+            addresses.add(address(known), 100);
+        }
+        assert!(!addresses.add(address("0.0.0.0:7878"), 100));
+        addresses.mark_attempt(address("10.0.0.1:7878"), now);
+        addresses
+            .mark_connected(address("10.0.0.1:7878"), 200)
+            .unwrap();
+        addresses.mark_attempt(address("10.2.0.1:7878"), now);
+        assert_eq!(addresses.pending(now), 1);
+
+        // the failed address is still backing off, and the connected group is picked last
+        let connected: HashSet<SocketAddr> = [address("10.0.0.1:7878")].iter().cloned().collect();
+        assert_eq!(
+            addresses.select(3, &connected, now),
+            vec![address("10.1.0.1:7878"), address("10.0.0.2:7878")]
+        );
+        let later = now + RETRY_INTERVAL;
+        assert_eq!(addresses.pending(later), 0);
+        assert_eq!(
+            addresses.select(3, &connected, later),
+            vec![
+                address("10.1.0.1:7878"),
+                address("10.2.0.1:7878"),
+                address("10.0.0.2:7878")
+            ]
+        );
+        assert_eq!(addresses.to_share()[0].address, address("10.0.0.1:7878"));
+    }
+
+    #[test]
+    fn test_addresses_persisted_once_connected() {
+        let path = std::env::temp_dir().join(format!("peers-test-{}.json", std::process::id()));
+        let mut addresses = AddressManager::load(path.clone()).unwrap();
+        addresses.add(address("10.0.0.1:7878"), 100);
+        addresses
+            .mark_connected(address("10.0.0.2:7878"), 200)
+            .unwrap();
+
+        let addresses = AddressManager::load(path.clone()).unwrap();
+        fs::remove_file(path).unwrap();
+        let shared: Vec<SocketAddr> = addresses
+            .to_share()
+            .into_iter()
+            .map(|entry| entry.address)
+            .collect();
+        assert_eq!(
+            shared,
+            vec![address("10.0.0.2:7878"), address("10.0.0.1:7878")]
+        );
+    }
+}
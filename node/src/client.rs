@@ -1,20 +1,37 @@
 extern crate ws;
-use crate::node;
-use std::sync::{Arc, Mutex};
+use crate::processor::{ConnectionId, NodeHandle};
+use crate::tls::{SessionKey, Tls};
+use crate::websocket::WebSocket;
+use log::{debug, warn};
+use openssl::ssl::SslStream;
+use std::sync::Arc;
 
-use ws::{Error, ErrorKind, Handler, Handshake, Message, Result, Sender};
+use ws::util::TcpStream;
+use ws::{CloseCode, Error, Handler, Handshake, Message, Result, Sender};
 
 // Our Handler struct.
 // Here we explicity indicate that the Client needs a Sender,
 // whereas a closure captures the Sender for us automatically.
 pub struct Client {
     out: Sender,
-    node: Arc<Mutex<node::Node>>,
+    handle: NodeHandle,
+    /// Encrypts the connection when peers are required to use TLS
+    tls: Option<Arc<Tls>>,
+    /// Filled in with the key from the peer's certificate, which the handshake checks
+    session_key: SessionKey,
+    /// Set once the session is open on the node
+    connection: Option<ConnectionId>,
 }
 
 impl Client {
-    pub fn new(out: Sender, node: Arc<Mutex<node::Node>>) -> Client {
-        Client { out, node }
+    pub fn new(out: Sender, handle: NodeHandle, tls: Option<Arc<Tls>>) -> Client {
+        Client {
+            out,
+            handle,
+            tls,
+            session_key: SessionKey::default(),
+            connection: None,
+        }
     }
 }
 
@@ -29,66 +46,51 @@ impl Handler for Client {
     fn on_open(&mut self, shake: Handshake) -> Result<()> {
         // Now we don't need to call unwrap since `on_open` returns a `Result<()>`.
         // If this call fails, it will only result in this connection disconnecting.
-        println!("CLIENT: Opening new connection to: {:?}", shake.peer_addr);
-
-        let mut node = self.node.lock().unwrap();
-
-        // initial set-up involves a request to be added to the networ
-        let add_me_message = node.add_me().unwrap();
-        self.out.send(add_me_message)?;
-        // then discovering and adding peers
-        let get_peers_message = node.get_peers().unwrap();
-        self.out.send(get_peers_message)?;
-
-        // TODO: handle the pruning and selection of peers
-
-        let send_transactions_message = node.send_transactions().unwrap();
-        self.out.send(send_transactions_message)?;
-        let get_chain_message = node.get_chain().unwrap();
-        self.out.send(get_chain_message)?;
+        debug!(address:? = shake.peer_addr; "Opened outbound connection");
+        // the client only opens connections we dialled, so the address it reached is the one dialled
+        let connection = WebSocket::new(self.out.clone());
+        let id = self.handle.open(
+            Arc::new(connection),
+            self.session_key.clone(),
+            shake.peer_addr,
+            shake.peer_addr.map(|address| address.ip()),
+        )?;
+        self.connection = Some(id);
         Ok(())
     }
 
     // `on_message` is roughly equivalent to the Handler closure. It takes a `Message`
     // and returns a `Result<()>`.
     fn on_message(&mut self, msg: Message) -> Result<()> {
-        let mut node = self.node.lock().unwrap();
-        let result = node.handle_message(&mut msg.into_data());
-        match result {
-            Ok(message) => {
-                match message.connect {
-                    Some(connection) => {
-                        let mut peer_url = String::from("ws://");
-                        peer_url.push_str(&connection.to_string());
-                        let url = url::Url::parse(&peer_url).unwrap();
-                        self.out.connect(url)?;
-                    }
-                    _ => {}
-                }
-                if message.broadcast {
-                    match message.raw_message {
-                        Some(data) => {
-                            return self.out.broadcast(data);
-                        }
-                        None => {
-                            return Err(Error::new(
-                                ErrorKind::Internal,
-                                "Asking to broadcast message with no data",
-                            ));
-                        }
-                    }
-                } else {
-                    match message.raw_message {
-                        Some(data) => {
-                            return self.out.send(data);
-                        }
-                        _ => {}
-                    }
-                }
+        match self.connection {
+            Some(id) => Ok(self.handle.receive(id, msg.into_data())?),
+            None => Ok(()),
+        }
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        debug!(code:? = code, reason = reason; "Outbound connection closed");
+        if let Some(id) = self.connection.take() {
+            if let Err(e) = self.handle.close(id) {
+                warn!(error:% = e; "Failed to close connection");
+            }
+        }
+    }
 
-                Ok(())
+    fn upgrade_ssl_client(
+        &mut self,
+        stream: TcpStream,
+        _url: &url::Url,
+    ) -> Result<SslStream<TcpStream>> {
+        match &self.tls {
+            Some(tls) => {
+                let connector = tls.connector(self.session_key.clone())?;
+                Ok(connector.connect("peer", stream)?)
             }
-            Err(e) => Err(Error::from(e)),
+            None => Err(Error::new(
+                ws::ErrorKind::Protocol,
+                "Encryption is not enabled for peer connections",
+            )),
         }
     }
 }
@@ -1,35 +1,530 @@
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Clone, Copy)]
+use redistribution::{Address, Network, ADDRESS_LENGTH};
+
+use crate::logger::{self, LogFilter, LogFormat};
+use crate::reputation;
+use crate::transport::Transport;
+
+/// What every environment variable the node reads starts with, so it can't pick up another program's
+pub const ENV_PREFIX: &str = "REDISTRIBUTION_";
+
+/// The config file read from the working directory, if there is one, when none is given
+pub const DEFAULT_CONFIG_FILE: &str = "redistribution.toml";
+
+/// How far above the node's port the JSON-RPC server listens when no `rpc_port` is given
+pub const RPC_PORT_OFFSET: u16 = 1000;
+
+/// How far above the node's port the REST explorer listens when no `rest_port` is given
+pub const REST_PORT_OFFSET: u16 = 2000;
+
+/// How far above the node's port Prometheus metrics are served when no `metrics_port` is given
+pub const METRICS_PORT_OFFSET: u16 = 3000;
+
+pub const USAGE: &str = "Usage: node [PORT] [OPTIONS]
+
+Each option can also be set in the config file, by the key before it,
+or by the environment variable named REDISTRIBUTION_ then the key in capitals,
+like REDISTRIBUTION_DATA_DIR. REDISTRIBUTION_CONFIG sets the config file.
+Flags override the environment, which overrides the config file.
+
+    --config FILE                 Config file, redistribution.toml in the working directory by default
+    bind = --bind ADDRESS         Where to listen for peers, 127.0.0.1 on the network's port by default.
+                                  PORT sets just the port.
+    external = --external ADDRESS Where peers reach us, the bind address by default
+    seeds = --seeds ADDRESSES     Comma-separated addresses to first look for peers at, the network's by default
+    data_dir = --data-dir DIR     Where the node keeps its files, the working directory by default
+    network = --network NETWORK   mainnet, testnet or regtest. Other than mainnet's, data is kept in DIR/NETWORK.
+                                  Nodes only connect to peers on the same network.
+    rpc_port = --rpc-port PORT    Where the local JSON-RPC server listens, 1000 above the node's port by default
+    rest_port = --rest-port PORT  Where the REST explorer listens, 2000 above the node's port by default
+    metrics_port = --metrics-port PORT
+                                  Where Prometheus metrics are served locally, 3000 above the node's port by default
+    mining = --mining BOOL        Whether submitted transactions are mined here or passed to peers that do, true by default
+    reward_address = --reward-address ADDRESS
+                                  Who the blocks mined here pay their reward to, nobody by default
+    threads = --threads COUNT     How many threads run the node, one per CPU by default
+    ban_duration = --ban-duration SECONDS
+    require_encryption = --require-encryption BOOL
+    transport = --transport websocket|tcp
+    log_level = --log-level LEVELS
+                                  A level, then levels for targets like info,node::sync=debug. info,ws=warn by default.
+    log_format = --log-format human|json";
+
+/// Every setting, by its key in the config file and as a flag.
+/// Its environment variable is `ENV_PREFIX` then the key in capitals.
+const SETTINGS: [(&str, &str); 16] = [
+    ("bind", "--bind"),
+    ("external", "--external"),
+    ("seeds", "--seeds"),
+    ("data_dir", "--data-dir"),
+    ("network", "--network"),
+    ("rpc_port", "--rpc-port"),
+    ("rest_port", "--rest-port"),
+    ("metrics_port", "--metrics-port"),
+    ("mining", "--mining"),
+    ("reward_address", "--reward-address"),
+    ("threads", "--threads"),
+    ("ban_duration", "--ban-duration"),
+    ("require_encryption", "--require-encryption"),
+    ("transport", "--transport"),
+    ("log_level", "--log-level"),
+    ("log_format", "--log-format"),
+];
+
+#[derive(Clone)]
 pub struct Config {
+    /// Where the node listens for peers
     pub address: SocketAddr,
     pub port: u16,
+    /// Where peers reach the node, which differs from `address` behind NAT or when binding to every interface
+    pub external: SocketAddr,
+    pub network: Network,
+    /// Where bans, keys and addresses are kept, already separated by network
+    pub data_dir: PathBuf,
+    /// How long misbehaving peers are banned for
+    pub ban_duration: Duration,
+    /// Whether connections between nodes are encrypted with TLS.
+    /// Every node in a network must agree, as encrypted nodes neither accept nor make plain connections.
+    pub require_encryption: bool,
+    /// How nodes connect, over WebSockets or plain TCP
+    pub transport: Transport,
+    /// Addresses to first look for peers at
+    pub seeds: Vec<SocketAddr>,
+    /// Where the JSON-RPC server listens, only locally. The port is `RPC_PORT_OFFSET` above the node's by default.
+    pub rpc_address: SocketAddr,
+    /// Where the read-only REST explorer listens, on the node's own interface.
+    /// The port is `REST_PORT_OFFSET` above the node's by default.
+    pub rest_address: SocketAddr,
+    /// Where Prometheus scrapes metrics from, only locally.
+    /// The port is `METRICS_PORT_OFFSET` above the node's by default.
+    pub metrics_address: SocketAddr,
+    /// Whether transactions submitted to this node are mined here, rather than passed on to the peers that mine
+    pub mining: bool,
+    /// Who the blocks mined here pay their reward to
+    pub reward_address: Option<Address>,
+    /// How many threads the runtime uses, or one per CPU
+    pub threads: Option<usize>,
+    pub log_filter: LogFilter,
+    pub log_format: LogFormat,
 }
 
-impl Config {
-    pub fn new(mut args: env::Args) -> Result<Config, &'static str> {
-        args.next();
+/// The settings a config file can hold, all top-level and each optional.
+/// Values are checked along with the environment's and flags', so only their TOML types are checked here.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    bind: Option<String>,
+    external: Option<String>,
+    seeds: Option<Vec<String>>,
+    data_dir: Option<String>,
+    network: Option<String>,
+    rpc_port: Option<u16>,
+    rest_port: Option<u16>,
+    metrics_port: Option<u16>,
+    mining: Option<bool>,
+    reward_address: Option<String>,
+    threads: Option<usize>,
+    ban_duration: Option<u64>,
+    require_encryption: Option<bool>,
+    transport: Option<String>,
+    log_level: Option<String>,
+    log_format: Option<String>,
+}
+
+impl ConfigFile {
+    /// The settings that were set, as the text they'd have as an environment variable
+    fn values(self) -> Vec<(&'static str, String)> {
+        fn text<T: ToString>(value: Option<T>) -> Option<String> {
+            value.map(|value| value.to_string())
+        }
+        vec![
+            ("bind", self.bind),
+            ("external", self.external),
+            ("seeds", self.seeds.map(|seeds| seeds.join(","))),
+            ("data_dir", self.data_dir),
+            ("network", self.network),
+            ("rpc_port", text(self.rpc_port)),
+            ("rest_port", text(self.rest_port)),
+            ("metrics_port", text(self.metrics_port)),
+            ("mining", text(self.mining)),
+            ("reward_address", self.reward_address),
+            ("threads", text(self.threads)),
+            ("ban_duration", text(self.ban_duration)),
+            ("require_encryption", text(self.require_encryption)),
+            ("transport", self.transport),
+            ("log_level", self.log_level),
+            ("log_format", self.log_format),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key, value)))
+        .collect()
+    }
+}
+
+/// Settings gathered from the config file, environment and flags, each with where it came from for errors
+struct Settings {
+    values: HashMap<&'static str, (String, String)>,
+}
 
-        let port = match args.next() {
-            Some(port) => match port.parse() {
-                Ok(port) => port,
-                Err(_e) => return Err("Could not parse port number: {}"),
+impl Settings {
+    fn set(&mut self, key: &str, value: String, source: String) -> Result<(), String> {
+        let key = SETTINGS
+            .iter()
+            .map(|(key, _)| *key)
+            .find(|known| *known == key)
+            .ok_or_else(|| format!("Unknown setting {} in {}", key, source))?;
+        self.values.insert(key, (value, source));
+        Ok(())
+    }
+
+    /// The setting parsed, if it was set anywhere
+    fn get<T: FromStr>(&self, key: &str, expected: &str) -> Result<Option<T>, String> {
+        match self.values.get(key) {
+            Some((value, source)) => match value.trim().parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_e) => Err(format!(
+                    "Could not parse {} {:?} from {}, expected {}",
+                    key, value, source, expected
+                )),
             },
-            None => return Err("Didn't get a port number"),
+            None => Ok(None),
+        }
+    }
+
+    fn get_bool(&self, key: &str) -> Result<Option<bool>, String> {
+        Ok(self
+            .get::<Switch>(key, "true or false")?
+            .map(|switch| switch.0))
+    }
+
+    fn get_addresses(&self, key: &str) -> Result<Option<Vec<SocketAddr>>, String> {
+        let (value, source) = match self.values.get(key) {
+            Some(setting) => setting,
+            None => return Ok(None),
         };
+        value
+            .split(',')
+            .filter(|address| !address.trim().is_empty())
+            .map(|address| {
+                address.trim().parse().map_err(|_e| {
+                    format!(
+                        "Could not parse {} address {:?} from {}, expected one like 127.0.0.1:7878",
+                        key, address, source
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
 
-        let address = match args.next() {
-            Some(_address) => {
-                println!("Defaulting to local host..."); // TODO: this should actually use a different port
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+    fn source(&self, key: &str) -> &str {
+        self.values
+            .get(key)
+            .map(|(_, source)| source.as_str())
+            .unwrap_or("the defaults")
+    }
+}
+
+/// A boolean written any of the ways people tend to
+struct Switch(bool);
+
+impl FromStr for Switch {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Switch, ()> {
+        match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(Switch(true)),
+            "0" | "false" | "no" | "off" => Ok(Switch(false)),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Config {
+    pub fn new(args: env::Args) -> Result<Config, String> {
+        Config::load(args.skip(1).collect(), |name| env::var(name).ok())
+    }
+
+    /// Layers the config file, the environment read through `var`, then the arguments, over the defaults
+    pub fn load(args: Vec<String>, var: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+        let mut flags = vec![];
+        let mut port = None;
+        let config_variable = format!("{}CONFIG", ENV_PREFIX);
+        let mut config_file = var(&config_variable).map(|file| (file, config_variable.clone()));
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                return Err(USAGE.to_string());
+            }
+            if !arg.starts_with("--") {
+                if port.is_some() {
+                    return Err(format!("Unexpected argument {}\n\n{}", arg, USAGE));
+                }
+                let parsed = arg
+                    .parse::<u16>()
+                    .map_err(|_e| format!("Could not parse port number {:?}\n\n{}", arg, USAGE))?;
+                port = Some(parsed);
+                continue;
+            }
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), value.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("Missing a value for {}\n\n{}", arg, USAGE))?;
+                    (arg, value)
+                }
+            };
+            if flag == "--config" {
+                config_file = Some((value, String::from("--config")));
+                continue;
+            }
+            match SETTINGS.iter().find(|(_, known)| *known == flag) {
+                Some((key, _)) => flags.push((*key, value, flag)),
+                None => return Err(format!("Unknown option {}\n\n{}", flag, USAGE)),
+            }
+        }
+
+        let mut settings = Settings {
+            values: HashMap::new(),
+        };
+        let config_file = match config_file {
+            Some((file, source)) => Some((PathBuf::from(file), source)),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Some((PathBuf::from(DEFAULT_CONFIG_FILE), String::new()))
+            }
+            None => None,
+        };
+        if let Some((file, source)) = config_file {
+            let contents = fs::read_to_string(&file).map_err(|e| match source.is_empty() {
+                true => format!("Could not read {}: {}", file.display(), e),
+                false => format!("Could not read {} set by {}: {}", file.display(), source, e),
+            })?;
+            let values: ConfigFile = toml::from_str(&contents)
+                .map_err(|e| format!("Could not parse {}, {}", file.display(), e))?;
+            for (key, value) in values.values() {
+                settings.set(key, value, file.display().to_string())?;
+            }
+        }
+        for (key, _) in SETTINGS.iter() {
+            let name = format!("{}{}", ENV_PREFIX, key.to_ascii_uppercase());
+            if let Some(value) = var(&name) {
+                settings.set(key, value, format!("the {} environment variable", name))?;
+            }
+        }
+        for (key, value, flag) in flags {
+            settings.set(key, value, flag)?;
+        }
+
+        let network = settings
+            .get("network", "mainnet, testnet or regtest")?
+            .unwrap_or(Network::Mainnet);
+        let params = network.params();
+        let mut address = settings
+            .get::<SocketAddr>("bind", "an address like 127.0.0.1:7878")?
+            .unwrap_or_else(|| {
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), params.default_port)
+            });
+        if let Some(port) = port {
+            address.set_port(port);
+        }
+        let port = address.port();
+        let external = match settings.get::<SocketAddr>("external", "an address like 1.2.3.4:7878")? {
+            Some(external) => external,
+            None if address.ip().is_unspecified() => {
+                return Err(format!(
+                    "Binding to {} from {} listens on every interface, so set external to the address peers reach us at",
+                    address,
+                    settings.source("bind")
+                ))
+            }
+            None => address,
+        };
+
+        let data_dir = settings
+            .get::<PathBuf>("data_dir", "a directory")?
+            .unwrap_or_else(|| PathBuf::from("."));
+        let data_dir = match network {
+            Network::Mainnet => data_dir,
+            network => data_dir.join(network.to_string()),
+        };
+
+        let ban_duration = settings
+            .get("ban_duration", "a whole number of seconds")?
+            .map(Duration::from_secs)
+            .unwrap_or(reputation::DEFAULT_BAN_DURATION);
+
+        let require_encryption = settings.get_bool("require_encryption")?.unwrap_or(false);
+        let transport = match settings
+            .values
+            .get("transport")
+            .map(|(value, _)| value.trim())
+        {
+            Some("websocket") | Some("ws") | None => Transport::WebSocket,
+            Some("tcp") => Transport::Tcp,
+            Some(value) => {
+                return Err(format!(
+                    "Could not parse transport {:?} from {}, expected websocket or tcp",
+                    value,
+                    settings.source("transport")
+                ))
             }
-            None => {
-                println!("Defaulting to local host...");
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+        };
+        if require_encryption && transport == Transport::Tcp {
+            return Err(String::from(
+                "Encryption is only supported over the WebSocket transport",
+            ));
+        }
+
+        let seeds = settings.get_addresses("seeds")?.unwrap_or_else(|| {
+            params
+                .seeds
+                .iter()
+                .filter_map(|seed| seed.parse().ok())
+                .collect()
+        });
+
+        let offset_port = |key: &str, offset: u16| -> Result<u16, String> {
+            match settings.get(key, "a port number")? {
+                Some(port) => Ok(port),
+                None => port.checked_add(offset).ok_or_else(|| {
+                    format!(
+                        "Port {} is too high to default {}, set it explicitly",
+                        port, key
+                    )
+                }),
             }
         };
+        let rpc_port = offset_port("rpc_port", RPC_PORT_OFFSET)?;
+        let rest_port = offset_port("rest_port", REST_PORT_OFFSET)?;
+        let metrics_port = offset_port("metrics_port", METRICS_PORT_OFFSET)?;
+        let ports = [port, rpc_port, rest_port, metrics_port];
+        if (1..ports.len()).any(|i| ports[..i].contains(&ports[i])) {
+            return Err(format!(
+                "The node ({}), rpc_port ({}), rest_port ({}) and metrics_port ({}) must all use different ports",
+                port, rpc_port, rest_port, metrics_port
+            ));
+        }
+        let rpc_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), rpc_port);
+        let rest_address = SocketAddr::new(address.ip(), rest_port);
+        let metrics_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), metrics_port);
+
+        let mining = settings.get_bool("mining")?.unwrap_or(true);
+        let reward_address = settings.get::<Address>("reward_address", "an address")?;
+        if let Some(address) = &reward_address {
+            if address.len() != ADDRESS_LENGTH * 2 || hex::decode(address).is_err() {
+                return Err(format!(
+                    "reward_address {:?} from {} is not an address, expected {} hex characters",
+                    address,
+                    settings.source("reward_address"),
+                    ADDRESS_LENGTH * 2
+                ));
+            }
+        }
+        let threads = settings.get::<usize>("threads", "a whole number of threads")?;
+        if threads == Some(0) {
+            return Err(format!(
+                "threads from {} must be at least 1",
+                settings.source("threads")
+            ));
+        }
+
+        let log_filter = settings
+            .get("log_level", "levels like info,node::sync=debug")?
+            .unwrap_or_else(|| logger::DEFAULT_LOG_LEVEL.parse().unwrap());
+        let log_format = settings
+            .get("log_format", "human or json")?
+            .unwrap_or(LogFormat::Human);
+
+        Ok(Config {
+            address,
+            port,
+            external,
+            network,
+            data_dir,
+            ban_duration,
+            require_encryption,
+            transport,
+            seeds,
+            rpc_address,
+            rest_address,
+            metrics_address,
+            mining,
+            reward_address,
+            threads,
+            log_filter,
+            log_format,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_override_environment_over_file() {
+        let file = env::temp_dir().join(format!("config-test-{}.toml", std::process::id()));
+        fs::write(
+            &file,
+            "# a testnet node\nnetwork = \"testnet\"\nseeds = [\"10.0.0.1:7878\", \"10.0.0.2:7878\"]\n\
+             mining = false\nthreads = 2 # at most\ndata_dir = '/var/lib/node'\n",
+        )
+        .unwrap();
+        let environment = |threads: &'static str| {
+            move |name: &str| match name {
+                "REDISTRIBUTION_THREADS" => Some(threads.to_string()),
+                // only prefixed variables are read
+                "THREADS" | "NETWORK" => Some(String::from("host")),
+                _ => None,
+            }
+        };
+        let args = |args: &[&str]| -> Vec<String> {
+            let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            args.extend(vec![String::from("--config"), file.display().to_string()]);
+            args
+        };
+
+        let config = Config::load(args(&["8000", "--threads", "4"]), environment("3")).unwrap();
+        assert_eq!(config.threads, Some(4));
+        assert_eq!(config.port, 8000);
+        assert_eq!(config.rpc_address.port(), 9000);
+        assert_eq!(config.metrics_address.port(), 11000);
+        assert_eq!(config.network, Network::Testnet);
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/node/testnet"));
+        assert_eq!(config.seeds.len(), 2);
+        assert!(!config.mining);
+        assert_eq!(config.reward_address, None);
+        let config = Config::load(args(&["--mining=on"]), environment("3")).unwrap();
+        assert_eq!(config.threads, Some(3));
+        assert!(config.mining);
+
+        // errors say where the bad value came from
+        let error = Config::load(args(&[]), environment("lots")).err().unwrap();
+        assert!(error.contains("REDISTRIBUTION_THREADS environment variable"));
+        let error = Config::load(args(&["--bind", "0.0.0.0:7878"]), environment("1"))
+            .err()
+            .unwrap();
+        assert!(error.contains("external"));
+        assert!(Config::load(args(&["8000", "8001"]), environment("1")).is_err());
+        let error = Config::load(args(&["--reward-address", "me"]), environment("1"))
+            .err()
+            .unwrap();
+        assert!(error.contains("--reward-address"));
 
-        Ok(Config { address, port })
+        fs::write(&file, "miners = 2\n").unwrap();
+        let error = Config::load(args(&[]), environment("1")).err().unwrap();
+        assert!(error.contains("unknown field `miners`"));
+        fs::write(&file, "rpc_port = \"high\"\n").unwrap();
+        assert!(Config::load(args(&[]), environment("1")).is_err());
+        fs::remove_file(&file).unwrap();
     }
 }
@@ -0,0 +1,293 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Result;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::transport::Connection;
+use crate::version::NODE_MINING;
+
+/// The most peers that connected to us we stay connected to
+pub const MAX_INBOUND: usize = 32;
+
+/// How long after its last ping was answered a peer is pinged again
+pub const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a peer has to answer a ping before it is disconnected
+pub const PING_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Peer {
+    sender: Arc<dyn Connection>,
+    /// The address we dialled, if we opened the connection
+    outbound: Option<SocketAddr>,
+    /// The address the connection came from, if the transport knows it
+    remote: Option<IpAddr>,
+    /// The service flags from the peer's version
+    services: u64,
+    connected_at: Instant,
+    /// When the peer last sent us a block or transaction we didn't have
+    last_useful: Option<Instant>,
+    /// The nonce of the ping we are waiting on, and when it was sent
+    ping_sent: Option<(u128, Instant)>,
+    last_pong: Instant,
+    /// How long the peer took to answer its last ping
+    latency: Option<Duration>,
+}
+
+/// What we know of a connected peer, as reported over RPC
+#[derive(Debug, Serialize)]
+pub struct PeerInfo {
+    pub id: Uuid,
+    /// The address we dialled, or the one the peer asked to be added with
+    pub address: Option<SocketAddr>,
+    pub inbound: bool,
+    /// Seconds since the handshake completed
+    pub connected_for: u64,
+    /// Milliseconds the peer took to answer its last ping
+    pub latency: Option<u128>,
+}
+
+/// Every peer we have completed a handshake with, so messages can be addressed to a particular peer
+/// rather than only answering the connection a message arrived on.
+/// Also keeps what is needed to tell live and useful peers from the rest.
+#[derive(Default)]
+pub struct Connections {
+    peers: HashMap<Uuid, Peer>,
+    next_nonce: u128,
+}
+
+impl Connections {
+    pub fn new() -> Connections {
+        Connections::default()
+    }
+
+    /// `address` is the one we dialled, if we opened the connection
+    pub fn add(
+        &mut self,
+        peer: Uuid,
+        sender: Arc<dyn Connection>,
+        address: Option<SocketAddr>,
+        remote: Option<IpAddr>,
+        services: u64,
+        now: Instant,
+    ) {
+        self.peers.insert(
+            peer,
+            Peer {
+                sender,
+                outbound: address,
+                remote,
+                services,
+                connected_at: now,
+                last_useful: None,
+                ping_sent: None,
+                last_pong: now,
+                latency: None,
+            },
+        );
+    }
+
+    /// Forgets a peer if this is still its connection, returning whether it was
+    pub fn remove(&mut self, peer: &Uuid, sender: &Arc<dyn Connection>) -> bool {
+        match self.peers.get(peer) {
+            Some(current) if Arc::ptr_eq(&current.sender, sender) => {
+                self.peers.remove(peer);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn contains(&self, peer: &Uuid) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    pub fn peers(&self) -> Vec<Uuid> {
+        self.peers.keys().cloned().collect()
+    }
+
+    /// The peers that mine the transactions passed to them
+    pub fn miners(&self) -> Vec<Uuid> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.services & NODE_MINING != 0)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    pub fn is_outbound(&self, peer: &Uuid) -> bool {
+        match self.peers.get(peer) {
+            Some(peer) => peer.outbound.is_some(),
+            None => false,
+        }
+    }
+
+    /// The address a peer's connection came from
+    pub fn remote(&self, peer: &Uuid) -> Option<IpAddr> {
+        self.peers.get(peer).and_then(|peer| peer.remote)
+    }
+
+    /// The addresses we dialled for the peers we connected out to
+    pub fn outbound_addresses(&self) -> Vec<SocketAddr> {
+        self.peers
+            .values()
+            .filter_map(|peer| peer.outbound)
+            .collect()
+    }
+
+    pub fn inbound_count(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.outbound.is_none())
+            .count()
+    }
+
+    /// Records a peer sending us something new, which protects it from eviction
+    pub fn mark_useful(&mut self, peer: &Uuid, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(peer) {
+            peer.last_useful = Some(now);
+        }
+    }
+
+    /// The peer that connected to us which we would miss least, to make room for another.
+    /// Only peers that have never sent us anything new are evicted, the slowest to answer pings first,
+    /// so a flood of new connections can't push out the peers we rely on.
+    pub fn least_useful_inbound(&self) -> Option<Uuid> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.outbound.is_none() && peer.last_useful.is_none())
+            .max_by_key(|(id, peer)| {
+                (
+                    peer.latency.unwrap_or(PING_TIMEOUT),
+                    peer.connected_at,
+                    **id,
+                )
+            })
+            .map(|(id, _)| *id)
+    }
+
+    /// Starts a ping to every peer that is due one, returning the nonce each must answer with
+    pub fn pings_due(&mut self, now: Instant) -> Vec<(Uuid, u128)> {
+        let mut pings = vec![];
+        for (id, peer) in self.peers.iter_mut() {
+            if peer.ping_sent.is_none() && now.duration_since(peer.last_pong) >= PING_INTERVAL {
+                self.next_nonce += 1;
+                peer.ping_sent = Some((self.next_nonce, now));
+                pings.push((*id, self.next_nonce));
+            }
+        }
+        pings
+    }
+
+    /// Peers which haven't answered a ping in time
+    pub fn timed_out(&self, now: Instant) -> Vec<Uuid> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| match peer.ping_sent {
+                Some((_, sent)) => now.duration_since(sent) > PING_TIMEOUT,
+                None => false,
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Records a peer answering our ping, pongs that don't match the ping we sent are ignored
+    pub fn pong(&mut self, peer: &Uuid, nonce: u128, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(peer) {
+            if let Some((expected, sent)) = peer.ping_sent {
+                if nonce == expected {
+                    peer.ping_sent = None;
+                    peer.last_pong = now;
+                    peer.latency = Some(now.duration_since(sent));
+                }
+            }
+        }
+    }
+
+    /// Describes every connected peer, with the addresses of those we dialled
+    pub fn info(&self, now: Instant) -> Vec<PeerInfo> {
+        self.peers
+            .iter()
+            .map(|(id, peer)| PeerInfo {
+                id: *id,
+                address: peer.outbound,
+                inbound: peer.outbound.is_none(),
+                connected_for: now.duration_since(peer.connected_at).as_secs(),
+                latency: peer.latency.map(|latency| latency.as_millis()),
+            })
+            .collect()
+    }
+
+    /// Sends to a connected peer, messages for peers which have since disconnected are dropped
+    pub fn send(&self, peer: &Uuid, data: Vec<u8>) -> Result<()> {
+        match self.peers.get(peer) {
+            Some(peer) => peer.sender.send(data),
+            None => Ok(()),
+        }
+    }
+
+    /// Closes the connection to a peer, which is forgotten once the close completes
+    pub fn disconnect(&self, peer: &Uuid, reason: &str) -> Result<()> {
+        match self.peers.get(peer) {
+            Some(peer) => peer.sender.close(reason.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Connections {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.peers.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Closed;
+
+    impl Connection for Closed {
+        fn send(&self, _data: Vec<u8>) -> Result<()> {
+            Ok(())
+        }
+
+        fn close(&self, _reason: String) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pings_time_out_and_useful_peers_kept() {
+        let now = Instant::now();
+        let mut connections = Connections::new();
+        let (useful, slow, silent) = (
+            Uuid::new_v5(&Uuid::nil(), b"useful"),
+            Uuid::new_v5(&Uuid::nil(), b"slow"),
+            Uuid::new_v5(&Uuid::nil(), b"silent"),
+        );
+        for peer in [useful, slow, silent].iter() {
+            connections.add(*peer, Arc::new(Closed), None, None, 0, now);
+        }
+        assert!(connections.pings_due(now).is_empty());
+
+        let later = now + PING_INTERVAL;
+        let pings: HashMap<Uuid, u128> = connections.pings_due(later).into_iter().collect();
+        assert_eq!(pings.len(), 3);
+        assert!(connections.pings_due(later).is_empty());
+        connections.pong(&useful, pings[&useful], later);
+        connections.pong(&slow, pings[&slow], later + Duration::from_secs(5));
+        connections.pong(&silent, pings[&silent] + 1, later);
+        connections.mark_useful(&useful, later);
+
+        let timeout = later + PING_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(connections.timed_out(timeout), vec![silent]);
+        // the silent peer's ping is unanswered, so it counts as slowest
+        assert_eq!(connections.least_useful_inbound(), Some(silent));
+        connections.remove(&silent, &(Arc::new(Closed) as Arc<dyn Connection>));
+        assert!(connections.contains(&silent));
+    }
+}
@@ -1,25 +1,30 @@
+use crate::addrman::AddressEntry;
+use crate::inventory::InventoryItem;
 use crate::peerlist;
 use crate::protocol_message::ProtocolMessage;
+use crate::version::Version;
 use peerlist::PeerList;
 use redistribution::Decodable;
-use redistribution::{Block, BlockData, Blockchain};
+use redistribution::{Block, BlockData, BlockHeader};
 use std::convert::TryFrom;
 use std::io::{Error, ErrorKind, Result};
 use std::net::SocketAddr;
 
 /// Stores the first index of each header. Used to break up raw message into relevant sections.
 pub enum Headers {
-    ProtocolType = 0,
-    PeerEncoding = 4,
-    MessageLength = 20,
-    Data = 36,
+    Magic = 0,
+    ProtocolType = 4,
+    PeerEncoding = 8,
+    MessageLength = 24,
+    Data = 40,
 }
 
 /// Handles reading/writing encoding structure
 /// Must be passed the protocol to avoid incorrect parsing
 /// Uses the following encoding schema
-///     First 4 bytes: Opcode
-///     Second 16 bytes: Opcode
+///     First 4 bytes: Network magic
+///     Next 4 bytes: Opcode
+///     Next 16 bytes: Peer ID
 pub struct Decoder<'a> {
     raw_bytes: &'a mut [u8],
     protocol: ProtocolMessage,
@@ -29,15 +34,23 @@ pub struct Decoder<'a> {
 pub enum DecodedType {
     BlockData(BlockData),
     PeerList(PeerList),
-    Blockchain(Blockchain),
+    BlockHashes(Vec<String>),
+    Inventory(Vec<InventoryItem>),
+    Blocks(Vec<Block>),
+    Headers(Vec<BlockHeader>),
     NewPeer(PeerIP),
     UpdatePeer(uuid::Uuid, PeerIP),
     NewBlock(Block),
+    Version(Version),
+    Signature(Vec<u8>),
+    Addresses(Vec<AddressEntry>),
+    Nonce(u128),
 }
 
 pub type PeerIP = SocketAddr;
 
 /// Assumes messages apply to format
+/// 4 bytes - network magic
 /// 4 bytes - opcode
 /// 16 bytes - peer_id TODO: might want to also include keysize
 /// 4 bytes - Message length
@@ -52,14 +65,25 @@ impl<'a> Decoder<'a> {
         }
     }
 
+    /// Whether the message was sent by a node on the network `magic` belongs to
+    pub fn has_magic(raw_bytes: &[u8], magic: &[u8; 4]) -> bool {
+        raw_bytes[Headers::Magic as usize..].starts_with(magic)
+    }
+
     pub fn protocol(raw_bytes: &mut [u8]) -> Result<ProtocolMessage> {
+        if raw_bytes.len() < Headers::Data as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Message is too short to contain headers",
+            ));
+        }
         ProtocolMessage::from_bytes(
             &mut raw_bytes[Headers::ProtocolType as usize..Headers::PeerEncoding as usize],
         )
     }
 
     /// Reads raw data passed to parser
-    /// Ignores first 4 bytes (opcode)
+    /// Ignores first 8 bytes (magic and opcode)
     /// Returns next 16 bytes as u128 - peer ID - removes these from parser
     pub fn peer_id(&mut self) -> uuid::Uuid {
         let mut bytes_id = [0; 16];
@@ -79,15 +103,21 @@ impl<'a> Decoder<'a> {
     }
 
     /// Reads raw data passed to parser
-    /// Ignores first 4 bytes (opcode)
+    /// Ignores first 8 bytes (magic and opcode)
     /// Ignores next 16 bytes (peer ID)
     /// Parses remainer as blockdata and returns string
-    // TODO: what about when the data is bigger than the 512 buffer? How to refactor this?
     fn decode_raw(&mut self) -> Result<Vec<u8>> {
         let index_result = usize::try_from(self.message_length());
         match index_result {
             Ok(index) => {
-                Ok(self.raw_bytes[Headers::Data as usize..Headers::Data as usize + index].to_vec())
+                let end = (Headers::Data as usize).saturating_add(index);
+                if end > self.raw_bytes.len() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Could not decode raw data - message length exceeds received bytes",
+                    ));
+                }
+                Ok(self.raw_bytes[Headers::Data as usize..end].to_vec())
             }
             Err(_) => Err(Error::new(
                 ErrorKind::InvalidData,
@@ -113,20 +143,18 @@ impl<'a> Decoder<'a> {
                 }
             }
             ProtocolMessage::UpdatePeer => {
-                let decoded_data = self.decode_raw()?;
-                let json_str_result = String::from_utf8(decoded_data);
-                match json_str_result {
-                    Ok(json_str) => {
-                        let deserialised_data: PeerIP = serde_json::from_str(&json_str)?;
-                        Ok(DecodedType::UpdatePeer(self.peer_id(), deserialised_data))
-                    }
-                    Err(_) => Err(Error::new(
+                // carries the new peer's ID with its address, the header holds the ID of the peer announcing it
+                let raw_data = self.decode_raw()?;
+                let peerlist = PeerList::decode(&raw_data)?;
+                match peerlist.peers.into_iter().next() {
+                    Some((peer_id, peer_ip)) => Ok(DecodedType::UpdatePeer(peer_id, peer_ip)),
+                    None => Err(Error::new(
                         ErrorKind::InvalidData,
-                        "Could not create string from decoded data",
+                        "UpdatePeer did not contain a peer",
                     )),
                 }
             }
-            ProtocolMessage::AddTransaction => {
+            ProtocolMessage::AddTransaction | ProtocolMessage::Transaction => {
                 let decoded_data = self.decode_raw()?;
                 let json_str_result = String::from_utf8(decoded_data);
                 match json_str_result {
@@ -159,10 +187,51 @@ impl<'a> Decoder<'a> {
                 let peerlist = PeerList::decode(&raw_data)?;
                 Ok(DecodedType::PeerList(peerlist))
             }
-            ProtocolMessage::SendBlockchain => {
+            ProtocolMessage::GetHeaders => {
+                let raw_data = self.decode_raw()?;
+                let hashes = Vec::<String>::decode(&raw_data)?;
+                Ok(DecodedType::BlockHashes(hashes))
+            }
+            ProtocolMessage::Inventory | ProtocolMessage::GetData => {
+                let raw_data = self.decode_raw()?;
+                let items = Vec::<InventoryItem>::decode(&raw_data)?;
+                Ok(DecodedType::Inventory(items))
+            }
+            ProtocolMessage::Blocks => {
+                let raw_data = self.decode_raw()?;
+                let blocks = Vec::<Block>::decode(&raw_data)?;
+                Ok(DecodedType::Blocks(blocks))
+            }
+            ProtocolMessage::Headers => {
+                let raw_data = self.decode_raw()?;
+                let headers = Vec::<BlockHeader>::decode(&raw_data)?;
+                Ok(DecodedType::Headers(headers))
+            }
+            ProtocolMessage::Addr => {
+                let raw_data = self.decode_raw()?;
+                let addresses = Vec::<AddressEntry>::decode(&raw_data)?;
+                Ok(DecodedType::Addresses(addresses))
+            }
+            ProtocolMessage::Ping | ProtocolMessage::Pong => {
                 let raw_data = self.decode_raw()?;
-                let blockchain = Blockchain::decode(&raw_data)?;
-                Ok(DecodedType::Blockchain(blockchain))
+                let nonce = u128::decode(&raw_data)?;
+                Ok(DecodedType::Nonce(nonce))
+            }
+            ProtocolMessage::Version => {
+                let raw_data = self.decode_raw()?;
+                let version = Version::decode(&raw_data)?;
+                Ok(DecodedType::Version(version))
+            }
+            ProtocolMessage::VerAck => {
+                let raw_data = self.decode_raw()?;
+                let signature = String::decode(&raw_data)?;
+                match hex::decode(signature) {
+                    Ok(signature) => Ok(DecodedType::Signature(signature)),
+                    Err(_) => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "VerAck signature is not hex",
+                    )),
+                }
             }
             _ => Err(Error::new(
                 ErrorKind::Other,
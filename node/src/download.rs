@@ -0,0 +1,278 @@
+use redistribution::Block;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Result;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::reputation::Misbehaviour;
+
+/// The number of blocks requested in a single `GetData` message
+pub const BLOCKS_PER_REQUEST: usize = 16;
+
+/// The most blocks we will wait on from a single peer at once
+pub const MAX_IN_FLIGHT_PER_PEER: usize = 32;
+
+/// How far past the next block to connect we will download,
+/// so one slow peer can't make us buffer the rest of the chain while we wait for it
+pub const DOWNLOAD_WINDOW: usize = 1024;
+
+/// How long a peer has to deliver a block before the request is given to someone else
+pub const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A peer which times out this many times is no longer asked for blocks
+pub const MAX_STALLS: u32 = 3;
+
+#[derive(Debug)]
+struct Request {
+    peer: Uuid,
+    requested_at: Instant,
+}
+
+/// Schedules the download of block bodies across all connected peers.
+///
+/// Blocks are requested in batches from every peer with spare capacity, may arrive in any order,
+/// and are handed back strictly in chain order so they can be validated and connected one at a time.
+/// Requests that time out, or were made to a peer that disconnects, are reassigned.
+#[derive(Debug, Default)]
+pub struct BlockDownloader {
+    order: VecDeque<String>,
+    positions: HashMap<String, usize>,
+    queue: VecDeque<String>,
+    in_flight: HashMap<String, Request>,
+    /// Every hash asked for, so a block arriving after its request was given to another peer isn't unsolicited
+    requested: HashSet<String>,
+    received: HashMap<String, Block>,
+    stalls: HashMap<Uuid, u32>,
+    connected: usize,
+}
+
+impl BlockDownloader {
+    pub fn new() -> BlockDownloader {
+        BlockDownloader::default()
+    }
+
+    /// Adds hashes to download, in the order they must be connected
+    pub fn enqueue(&mut self, hashes: Vec<String>) {
+        for hash in hashes {
+            self.positions
+                .insert(hash.clone(), self.positions.len() + self.connected);
+            self.order.push_back(hash.clone());
+            self.queue.push_back(hash);
+        }
+    }
+
+    /// Shares out queued hashes between the peers with spare capacity, one batch per peer per round.
+    /// Each batch is sent as its own `GetData` message.
+    pub fn assign(&mut self, peers: &[Uuid], now: Instant) -> Vec<(Uuid, Vec<String>)> {
+        let mut assignments = vec![];
+        let available: Vec<&Uuid> = peers
+            .iter()
+            .filter(|peer| self.stalls.get(peer).cloned().unwrap_or(0) < MAX_STALLS)
+            .collect();
+
+        let mut assigned = true;
+        while assigned {
+            assigned = false;
+            for peer in available.iter() {
+                let capacity = MAX_IN_FLIGHT_PER_PEER - self.in_flight_for(peer);
+                let window = DOWNLOAD_WINDOW - (self.in_flight.len() + self.received.len());
+                let batch_size = BLOCKS_PER_REQUEST
+                    .min(capacity)
+                    .min(window)
+                    .min(self.queue.len());
+                if batch_size == 0 {
+                    continue;
+                }
+                let batch: Vec<String> = self.queue.drain(..batch_size).collect();
+                for hash in batch.iter() {
+                    self.requested.insert(hash.clone());
+                    self.in_flight.insert(
+                        hash.clone(),
+                        Request {
+                            peer: **peer,
+                            requested_at: now,
+                        },
+                    );
+                }
+                assignments.push((**peer, batch));
+                assigned = true;
+            }
+        }
+        assignments
+    }
+
+    /// Accepts blocks, which must only contain blocks we asked some peer for, intact.
+    /// A slow peer may answer after its request was given to another, so whoever delivers a block first
+    /// supplies it, and later copies are ignored.
+    pub fn receive(&mut self, blocks: Vec<Block>) -> Result<()> {
+        if !blocks
+            .iter()
+            .all(|block| self.requested.contains(&block.hash))
+        {
+            return Err(
+                Misbehaviour::UnsolicitedData.error("Received a block that was not requested")
+            );
+        }
+        if blocks
+            .iter()
+            .any(|block| Block::calculate_hash_for_block(block) != block.hash)
+        {
+            return Err(
+                Misbehaviour::InvalidBlock.error("Received a block that does not match its hash")
+            );
+        }
+        for block in blocks {
+            let needed = self.positions.contains_key(&block.hash)
+                && !self.received.contains_key(&block.hash);
+            if !needed {
+                continue;
+            }
+            if self.in_flight.remove(&block.hash).is_none() {
+                // it was waiting to be asked for again
+                self.queue.retain(|queued| *queued != block.hash);
+            }
+            self.received.insert(block.hash.clone(), block);
+        }
+        Ok(())
+    }
+
+    /// The next block to connect, if it has arrived
+    pub fn next_block(&mut self) -> Option<Block> {
+        let block = self.received.remove(self.order.front()?)?;
+        self.order.pop_front();
+        self.positions.remove(&block.hash);
+        self.connected += 1;
+        Some(block)
+    }
+
+    /// Returns requests the peer has taken too long to answer to the front of the queue
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| now.duration_since(request.requested_at) > BLOCK_REQUEST_TIMEOUT)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in expired {
+            if let Some(request) = self.in_flight.remove(&hash) {
+                *self.stalls.entry(request.peer).or_insert(0) += 1;
+                self.requeue(hash);
+            }
+        }
+    }
+
+    /// Returns everything requested from a peer that has gone away to the queue
+    pub fn remove_peer(&mut self, peer: &Uuid) {
+        let abandoned: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.peer == *peer)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in abandoned {
+            self.in_flight.remove(&hash);
+            self.requeue(hash);
+        }
+        self.stalls.remove(peer);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    fn in_flight_for(&self, peer: &Uuid) -> usize {
+        self.in_flight
+            .values()
+            .filter(|request| request.peer == *peer)
+            .count()
+    }
+
+    /// Requeued hashes keep their place relative to the rest of the chain,
+    /// so the blocks we are waiting on to connect are always requested first
+    fn requeue(&mut self, hash: String) {
+        let position = self.positions[&hash];
+        let index = self
+            .queue
+            .partition_point(|queued| self.positions[queued] < position);
+        self.queue.insert(index, hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(count: u32) -> Vec<Block> {
+        let mut blockchain = redistribution::Blockchain::new(redistribution::Network::Regtest);
+        (1..=count)
+            .map(|i| {
+                let block = blockchain
+                    .generate_next_block(&[i.to_string()], None)
+                    .unwrap();
+                blockchain.add_block(block.clone()).unwrap();
+                block
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_blocks_shared_between_peers_and_connected_in_order() {
+        let blocks = blocks(40);
+        let (first_peer, second_peer) = (Uuid::from_bytes([1; 16]), Uuid::from_bytes([2; 16]));
+        let mut downloader = BlockDownloader::new();
+        downloader.enqueue(blocks.iter().map(|block| block.hash.clone()).collect());
+
+        let assignments = downloader.assign(&[first_peer, second_peer], Instant::now());
+        let total: usize = assignments.iter().map(|(_, hashes)| hashes.len()).sum();
+        assert_eq!(total, 40);
+        assert!(assignments
+            .iter()
+            .all(|(_, hashes)| hashes.len() <= BLOCKS_PER_REQUEST));
+
+        // deliver the second peer's blocks first, nothing can connect until the first peer answers
+        let (second, first): (Vec<Block>, Vec<Block>) = blocks.iter().cloned().partition(|block| {
+            assignments
+                .iter()
+                .any(|(peer, hashes)| *peer == second_peer && hashes.contains(&block.hash))
+        });
+        downloader.receive(second).unwrap();
+        assert!(downloader.next_block().is_none());
+        downloader.receive(first).unwrap();
+
+        let connected: Vec<u32> = std::iter::from_fn(|| downloader.next_block())
+            .map(|block| block.index)
+            .collect();
+        assert_eq!(connected, (1..=40).collect::<Vec<u32>>());
+        assert!(downloader.is_done());
+    }
+
+    #[test]
+    fn test_stalled_requests_reassigned() {
+        let blocks = blocks(4);
+        let (slow_peer, fast_peer) = (Uuid::from_bytes([1; 16]), Uuid::from_bytes([2; 16]));
+        let mut downloader = BlockDownloader::new();
+        downloader.enqueue(blocks.iter().map(|block| block.hash.clone()).collect());
+
+        let start = Instant::now();
+        downloader.assign(&[slow_peer], start);
+        downloader.expire(start + BLOCK_REQUEST_TIMEOUT + Duration::from_secs(1));
+        let assignments = downloader.assign(&[fast_peer], start);
+        assert_eq!(assignments[0].1.len(), 4);
+
+        // the slow peer's late answer is still welcome, and the copies that follow are ignored
+        downloader.receive(blocks[..2].to_vec()).unwrap();
+        downloader.receive(blocks.clone()).unwrap();
+        downloader.receive(blocks[..2].to_vec()).unwrap();
+        let connected: Vec<u32> = std::iter::from_fn(|| downloader.next_block())
+            .map(|block| block.index)
+            .collect();
+        assert_eq!(connected, vec![1, 2, 3, 4]);
+        assert!(downloader.is_done());
+        downloader.receive(blocks[..1].to_vec()).unwrap();
+
+        // blocks nobody was asked for are still unsolicited
+        let mut downloader = BlockDownloader::new();
+        downloader.enqueue(blocks.iter().map(|block| block.hash.clone()).collect());
+        assert!(downloader.receive(blocks[..1].to_vec()).is_err());
+    }
+}
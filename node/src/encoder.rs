@@ -9,11 +9,12 @@ pub struct Encoder {}
 
 impl Encoder {
     fn encode_raw(
+        magic: &[u8; 4],
         protocol: ProtocolMessage,
         peer_id: uuid::Uuid,
         data: Vec<u8>,
     ) -> Result<EncodedMessage> {
-        let mut raw_encoded = vec![];
+        let mut raw_encoded = magic.to_vec();
         protocol
             .as_bytes()
             .iter()
@@ -40,12 +41,14 @@ impl Encoder {
         }
     }
 
+    /// Encodes a message for a peer on the network `magic` belongs to
     pub fn encode<T: Encodable>(
+        magic: &[u8; 4],
         protocol: ProtocolMessage,
         peer_id: uuid::Uuid,
         data: &T,
     ) -> Result<EncodedMessage> {
-        Encoder::encode_raw(protocol, peer_id, data.encode()?.to_vec())
+        Encoder::encode_raw(magic, protocol, peer_id, data.encode()?.to_vec())
     }
 }
 
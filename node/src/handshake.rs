@@ -0,0 +1,156 @@
+use redistribution::verify;
+use std::io::{Error, ErrorKind, Result};
+use uuid::Uuid;
+
+use crate::decoder::{DecodedType, Decoder};
+use crate::identity;
+use crate::protocol_message::ProtocolMessage;
+use crate::tls::SessionKey;
+
+/// Tracks the `Version`/`VerAck` exchange on a single connection.
+/// Each side sends its `Version` when the connection opens and answers the other's with a `VerAck`,
+/// so the handshake is complete once we have both received a version and had ours acknowledged.
+/// The acknowledgement signs the challenge in our version, proving the peer holds the key its ID comes from.
+/// On an encrypted connection the peer's certificate must be for that same key.
+#[derive(Debug, Default)]
+pub struct Handshake {
+    version_received: bool,
+    verack_received: bool,
+    challenge: Vec<u8>,
+    peer: Option<Uuid>,
+    peer_key: Vec<u8>,
+    session_key: SessionKey,
+}
+
+impl Handshake {
+    pub fn new() -> Handshake {
+        Handshake::default()
+    }
+
+    /// A handshake on a connection whose TLS layer leaves the key from the peer's certificate here
+    pub fn with_session_key(session_key: SessionKey) -> Handshake {
+        Handshake {
+            session_key,
+            ..Handshake::new()
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.version_received && self.verack_received
+    }
+
+    /// A fresh challenge for the peer to sign, to send in our `Version`
+    pub fn new_challenge(&mut self) -> Result<Vec<u8>> {
+        self.challenge = identity::new_challenge()?;
+        Ok(self.challenge.clone())
+    }
+
+    /// Whether a message may be processed at this stage of the handshake.
+    /// Each handshake message is only accepted once, and the peer's `VerAck` must follow its `Version`.
+    /// Everything else must wait for completion.
+    pub fn permits(&self, protocol: &ProtocolMessage) -> bool {
+        match protocol {
+            ProtocolMessage::Version => !self.version_received,
+            ProtocolMessage::VerAck => self.version_received && !self.verack_received,
+            _ => self.is_complete(),
+        }
+    }
+
+    /// Checks a message is permitted and comes from the peer that authenticated itself.
+    /// The peer's `Version` must carry the public key its ID is derived from,
+    /// and its `VerAck` must be signed with that key.
+    pub fn check(&mut self, protocol: &ProtocolMessage, message: &mut [u8]) -> Result<()> {
+        if !self.permits(protocol) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Unexpected message during handshake",
+            ));
+        }
+        let mut decoder = Decoder::new(message, protocol.clone());
+        match protocol {
+            ProtocolMessage::Version => match decoder.decode_json()? {
+                DecodedType::Version(version) => {
+                    let public_key = version.public_key()?;
+                    if identity::node_id(&public_key) != decoder.peer_id() {
+                        return Err(Error::new(
+                            ErrorKind::PermissionDenied,
+                            "Peer ID does not match its public key",
+                        ));
+                    }
+                    if let Some(session_key) = self.session_key.lock().unwrap().as_ref() {
+                        if *session_key != public_key {
+                            return Err(Error::new(
+                                ErrorKind::PermissionDenied,
+                                "Peer's TLS certificate does not match its public key",
+                            ));
+                        }
+                    }
+                    self.peer = Some(decoder.peer_id());
+                    self.peer_key = public_key;
+                    Ok(())
+                }
+                _ => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Wrong decoding type used in Version command",
+                )),
+            },
+            ProtocolMessage::VerAck => match decoder.decode_json()? {
+                DecodedType::Signature(signature) => {
+                    if !verify(&self.peer_key, &self.challenge, &signature) {
+                        return Err(Error::new(
+                            ErrorKind::PermissionDenied,
+                            "Peer did not sign our challenge with its key",
+                        ));
+                    }
+                    Ok(())
+                }
+                _ => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Wrong decoding type used in VerAck command",
+                )),
+            },
+            _ => {
+                if Some(decoder.peer_id()) != self.peer {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        "Message sent under another peer's ID",
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Records a successfully handled message, returning true if it completed the handshake
+    pub fn record(&mut self, protocol: &ProtocolMessage) -> bool {
+        let was_complete = self.is_complete();
+        match protocol {
+            ProtocolMessage::Version => self.version_received = true,
+            ProtocolMessage::VerAck => self.verack_received = true,
+            _ => {}
+        }
+        !was_complete && self.is_complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_held_until_complete() {
+        let mut handshake = Handshake::new();
+        assert!(!handshake.permits(&ProtocolMessage::GetHeaders));
+        assert!(!handshake.permits(&ProtocolMessage::VerAck));
+        assert!(handshake.permits(&ProtocolMessage::Version));
+
+        assert!(!handshake.record(&ProtocolMessage::Version));
+        assert!(!handshake.permits(&ProtocolMessage::Version));
+        assert!(!handshake.permits(&ProtocolMessage::AddMe));
+
+        assert!(handshake.record(&ProtocolMessage::VerAck));
+        assert!(handshake.is_complete());
+        assert!(handshake.permits(&ProtocolMessage::AddMe));
+        assert!(!handshake.permits(&ProtocolMessage::VerAck));
+    }
+}
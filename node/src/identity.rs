@@ -0,0 +1,108 @@
+use openssl::pkey::{PKey, Private};
+use openssl::rand::rand_bytes;
+use redistribution::KeyPair;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use uuid::Uuid;
+
+use crate::peerlist::PeerList;
+
+/// The length of the random challenge each side signs during the handshake
+pub const CHALLENGE_LENGTH: usize = 32;
+
+/// A node's long-term Ed25519 key pair. The node ID is derived from the public key,
+/// so a peer can prove an ID is its own by signing the challenge we send it.
+pub struct Identity {
+    key: KeyPair,
+}
+
+impl Identity {
+    pub fn generate() -> Result<Identity> {
+        Ok(Identity {
+            key: KeyPair::generate()?,
+        })
+    }
+
+    /// Reads the key pair stored at the path, generating and storing a new one if there isn't one yet
+    pub fn load_or_generate(path: &Path) -> Result<Identity> {
+        match fs::read(path) {
+            Ok(pem) => Ok(Identity {
+                key: KeyPair::from_pem(&pem)?,
+            }),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                let identity = Identity::generate()?;
+                identity.key.save(path)?;
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        self.key.public_key()
+    }
+
+    pub fn private_key(&self) -> &PKey<Private> {
+        self.key.private_key()
+    }
+
+    pub fn node_id(&self) -> Result<Uuid> {
+        Ok(node_id(&self.public_key()?))
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.key.sign(data)
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.node_id() {
+            Ok(id) => write!(f, "Identity({})", id),
+            Err(_) => write!(f, "Identity"),
+        }
+    }
+}
+
+/// The ID belonging to the node holding a public key
+pub fn node_id(public_key: &[u8]) -> Uuid {
+    PeerList::get_new_peer_id(public_key)
+}
+
+pub fn new_challenge() -> Result<Vec<u8>> {
+    let mut challenge = vec![0; CHALLENGE_LENGTH];
+    rand_bytes(&mut challenge).map_err(to_io_error)?;
+    Ok(challenge)
+}
+
+pub fn to_io_error(e: openssl::error::ErrorStack) -> Error {
+    Error::other(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redistribution::verify;
+
+    #[test]
+    fn test_challenge_signature_proves_node_id() {
+        let identity = Identity::generate().unwrap();
+        let impostor = Identity::generate().unwrap();
+        let challenge = new_challenge().unwrap();
+
+        let public_key = identity.public_key().unwrap();
+        assert_eq!(node_id(&public_key), identity.node_id().unwrap());
+        assert!(verify(
+            &public_key,
+            &challenge,
+            &identity.sign(&challenge).unwrap()
+        ));
+        assert!(!verify(
+            &public_key,
+            &challenge,
+            &impostor.sign(&challenge).unwrap()
+        ));
+    }
+}
@@ -0,0 +1,148 @@
+use redistribution::BlockData;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The most hashes remembered as seen, the oldest are forgotten beyond this
+pub const MAX_SEEN: usize = 20000;
+
+/// The most items announced or requested in a single message
+pub const MAX_INVENTORY: usize = 500;
+
+/// How long an announcing peer has to send an item before another announcement of it is followed up
+pub const INVENTORY_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum InventoryType {
+    Block,
+    Transaction,
+}
+
+/// Identifies a block or transaction by hash, so peers can announce it without sending it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InventoryItem {
+    pub kind: InventoryType,
+    pub hash: String,
+}
+
+impl InventoryItem {
+    pub fn block(hash: &str) -> InventoryItem {
+        InventoryItem {
+            kind: InventoryType::Block,
+            hash: hash.to_string(),
+        }
+    }
+
+    pub fn transaction(transaction: &BlockData) -> InventoryItem {
+        InventoryItem {
+            kind: InventoryType::Transaction,
+            hash: transaction_hash(transaction),
+        }
+    }
+}
+
+pub fn transaction_hash(transaction: &BlockData) -> String {
+    redistribution::transaction_id(transaction)
+}
+
+/// What we have already seen or asked for, so announced items are only fetched once
+/// and never announced back to the network a second time.
+#[derive(Debug, Default)]
+pub struct Inventory {
+    seen: HashSet<String>,
+    seen_order: VecDeque<String>,
+    requested: HashMap<String, (Uuid, Instant)>,
+}
+
+impl Inventory {
+    pub fn new() -> Inventory {
+        Inventory::default()
+    }
+
+    /// Returns false if the hash had already been seen
+    pub fn mark_seen(&mut self, hash: &str) -> bool {
+        if !self.seen.insert(hash.to_string()) {
+            return false;
+        }
+        self.seen_order.push_back(hash.to_string());
+        if self.seen_order.len() > MAX_SEEN {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn has_seen(&self, hash: &str) -> bool {
+        self.seen.contains(hash)
+    }
+
+    /// Filters a peer's announcement down to the items we still need,
+    /// remembering that we asked this peer for them
+    pub fn request(
+        &mut self,
+        peer: Uuid,
+        items: Vec<InventoryItem>,
+        now: Instant,
+    ) -> Vec<InventoryItem> {
+        let mut wanted = vec![];
+        for item in items {
+            if self.has_seen(&item.hash) || self.requested.contains_key(&item.hash) {
+                continue;
+            }
+            self.requested.insert(item.hash.clone(), (peer, now));
+            wanted.push(item);
+        }
+        wanted
+    }
+
+    /// Returns true if we asked the peer for this item, which is then no longer outstanding
+    pub fn take_request(&mut self, peer: &Uuid, hash: &str) -> bool {
+        match self.requested.get(hash) {
+            Some((requested_from, _)) if requested_from == peer => {
+                self.requested.remove(hash);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Forgets requests that were never answered, so the item can be fetched from the next peer to announce it
+    pub fn expire(&mut self, now: Instant) {
+        self.requested.retain(|_, (_, requested_at)| {
+            now.duration_since(*requested_at) <= INVENTORY_REQUEST_TIMEOUT
+        });
+    }
+
+    pub fn remove_peer(&mut self, peer: &Uuid) {
+        self.requested
+            .retain(|_, (requested_from, _)| requested_from != peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_items_requested_once_until_expired() {
+        let (first_peer, second_peer) = (Uuid::from_bytes([1; 16]), Uuid::from_bytes([2; 16]));
+        let items = vec![InventoryItem::block("a"), InventoryItem::block("b")];
+        let start = Instant::now();
+        let mut inventory = Inventory::new();
+        inventory.mark_seen("a");
+
+        let wanted = inventory.request(first_peer, items.clone(), start);
+        assert_eq!(wanted, vec![InventoryItem::block("b")]);
+        assert!(inventory
+            .request(second_peer, items.clone(), start)
+            .is_empty());
+        assert!(!inventory.take_request(&second_peer, "b"));
+
+        inventory.expire(start + INVENTORY_REQUEST_TIMEOUT + Duration::from_secs(1));
+        let wanted = inventory.request(second_peer, items, start);
+        assert_eq!(wanted, vec![InventoryItem::block("b")]);
+        assert!(inventory.take_request(&second_peer, "b"));
+    }
+}
@@ -0,0 +1,240 @@
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::{json, Map};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What is logged when no `log_level` is given. The WebSocket library is chatty at lower levels.
+pub const DEFAULT_LOG_LEVEL: &str = "info,ws=warn";
+
+/// How log lines are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// `time LEVEL target: message key=value ...`, for people
+    Human,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(format: &str) -> Result<LogFormat, ()> {
+        match format {
+            "human" | "text" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which records are logged: a level for every target, then levels for targets under a prefix,
+/// written like `info,node::sync=debug,ws=warn`. Targets are module paths unless a record names its own.
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| target == prefix || target.starts_with(&format!("{}::", prefix)))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = ();
+
+    fn from_str(spec: &str) -> Result<LogFilter, ()> {
+        let mut filter = LogFilter {
+            default: LevelFilter::Info,
+            targets: vec![],
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = level.trim().parse().map_err(|_e| ())?;
+                    filter.targets.push((target.trim().to_string(), level));
+                }
+                None => filter.default = directive.parse().map_err(|_e| ())?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// Writes records to standard error
+pub struct Logger {
+    filter: LogFilter,
+    format: LogFormat,
+}
+
+impl Logger {
+    /// Makes this the logger the `log` macros write to, for the rest of the process
+    pub fn init(filter: LogFilter, format: LogFormat) -> Result<(), SetLoggerError> {
+        log::set_max_level(filter.max_level());
+        log::set_boxed_logger(Box::new(Logger { filter, format }))
+    }
+
+    fn format(&self, record: &Record, now: SystemTime) -> String {
+        let mut fields = Fields(vec![]);
+        // a field that can't be visited is left out rather than losing the whole line
+        let _ = record.key_values().visit(&mut fields);
+        let time = timestamp(now);
+        match self.format {
+            LogFormat::Human => {
+                let mut line = format!(
+                    "{} {:<5} {}: {}",
+                    time,
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+                for (key, value) in fields.0 {
+                    let value = value.to_string();
+                    match value.contains(char::is_whitespace) || value.is_empty() {
+                        true => line.push_str(&format!(" {}={:?}", key, value)),
+                        false => line.push_str(&format!(" {}={}", key, value)),
+                    }
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut object = Map::new();
+                object.insert(String::from("time"), json!(time));
+                object.insert(String::from("level"), json!(record.level().as_str()));
+                object.insert(String::from("target"), json!(record.target()));
+                object.insert(String::from("message"), json!(record.args().to_string()));
+                for (key, value) in fields.0 {
+                    let value = match (value.to_u64(), value.to_i64(), value.to_bool()) {
+                        (Some(number), _, _) => json!(number),
+                        (_, Some(number), _) => json!(number),
+                        (_, _, Some(boolean)) => json!(boolean),
+                        _ => json!(value.to_string()),
+                    };
+                    object.insert(key, value);
+                }
+                serde_json::Value::Object(object).to_string()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = self.format(record, SystemTime::now());
+            let _ = writeln!(io::stderr().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// A record's fields, in the order they were given
+struct Fields<'kvs>(Vec<(String, Value<'kvs>)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'kvs> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
+
+/// The time in UTC as RFC 3339 with milliseconds, like `2020-01-31T23:59:59.999Z`
+fn timestamp(now: SystemTime) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_today) = ((seconds / 86_400) as i64, seconds % 86_400);
+
+    // days since the epoch to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_today / 3_600,
+        seconds_today % 3_600 / 60,
+        seconds_today % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::time::Duration;
+
+    #[test]
+    fn test_records_formatted_with_fields() {
+        let filter: LogFilter = "warn,node::sync=debug,node::sync::headers=error"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.level("node::sync"), LevelFilter::Debug);
+        assert_eq!(filter.level("node::synchronise"), LevelFilter::Warn);
+        assert_eq!(filter.level("node::sync::headers"), LevelFilter::Error);
+        assert!("loud".parse::<LogFilter>().is_err());
+
+        let fields = [("peer", "a b"), ("block", "00ff")];
+        let height = [("height", 7u32)];
+        let sources: [&dyn kv::Source; 2] = [&fields, &height];
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("node::node")
+            .args(format_args!("Connected block"))
+            .key_values(&sources)
+            .build();
+        let now = UNIX_EPOCH + Duration::from_millis(951_782_400_250);
+
+        let human = Logger {
+            filter: filter.clone(),
+            format: LogFormat::Human,
+        };
+        assert_eq!(
+            human.format(&record, now),
+            "2000-02-29T00:00:00.250Z INFO  node::node: Connected block peer=\"a b\" block=00ff height=7"
+        );
+        let json = Logger {
+            filter,
+            format: LogFormat::Json,
+        };
+        let line: serde_json::Value = serde_json::from_str(&json.format(&record, now)).unwrap();
+        assert_eq!(line["message"], "Connected block");
+        assert_eq!(line["height"], 7);
+        assert_eq!(line["block"], "00ff");
+    }
+}
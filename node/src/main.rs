@@ -1,23 +1,51 @@
-use std::cell::Cell;
 use std::env;
+use std::fs;
 use std::process;
-use std::rc::Rc;
 use std::sync::Arc;
 use std::thread;
 
 extern crate ws;
-use ws::{connect, listen};
+use log::{error, info};
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use tokio::runtime::Builder as RuntimeBuilder;
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+use ws::{Builder, Settings};
 
+use processor::Processor;
+use transport::{Dialer, Transport};
+
+mod addrman;
 mod client;
 mod config;
+mod connections;
 mod decoder;
+mod download;
 mod encoder;
+mod handshake;
+mod identity;
+mod inventory;
+mod logger;
+mod mempool;
+mod metrics;
+mod miner;
 mod node;
+mod orphans;
 mod peerlist;
+mod processor;
 mod protocol_message;
+mod reputation;
+mod rest;
+mod rpc;
 mod server;
-
-static ROOT_NODE: &str = "127.0.0.1:7878";
+#[cfg(test)]
+mod simulator;
+mod sync;
+mod tcp;
+mod tls;
+mod transport;
+mod version;
+mod websocket;
 
 fn main() {
     let config = config::Config::new(env::args()).unwrap_or_else(|err| {
@@ -25,27 +53,209 @@ fn main() {
         process::exit(1)
     });
 
-    let node = node::Node::new(config.address.to_string());
+    logger::Logger::init(config.log_filter.clone(), config.log_format).unwrap_or_else(|err| {
+        eprintln!("Problem starting logging: {}", err);
+        process::exit(1)
+    });
+    info!(
+        network:% = config.network,
+        data_dir:% = config.data_dir.display();
+        "Starting node"
+    );
+    fs::create_dir_all(&config.data_dir).unwrap_or_else(|err| {
+        error!(
+            "Problem creating data directory {}: {}",
+            config.data_dir.display(),
+            err
+        );
+        process::exit(1)
+    });
+
+    // bans, keys and addresses are kept per port, so several nodes can share a data directory
+    let ban_list = config
+        .data_dir
+        .join(format!("banlist-{}.json", config.port));
+    let reputation =
+        reputation::Reputation::load(ban_list, config.ban_duration).unwrap_or_else(|err| {
+            error!("Problem reading ban list: {}", err);
+            process::exit(1)
+        });
+
+    let key_file = config.data_dir.join(format!("node-{}.key", config.port));
+    let identity = identity::Identity::load_or_generate(&key_file).unwrap_or_else(|err| {
+        error!("Problem reading node key: {}", err);
+        process::exit(1)
+    });
 
-    let cloned_node = Arc::clone(&node);
-    let listening_thread = thread::spawn(move || {
-        let count = Rc::new(Cell::new(0));
-        listen(config.address, |out| {
-            let cloned_again = Arc::clone(&cloned_node);
-            server::Server::new(out, count.clone(), cloned_again)
-        })
-        .unwrap();
+    let address_file = config.data_dir.join(format!("peers-{}.json", config.port));
+    let mut addresses = addrman::AddressManager::load(address_file).unwrap_or_else(|err| {
+        error!("Problem reading known peer addresses: {}", err);
+        process::exit(1)
     });
+    for seed in config.seeds.iter() {
+        if *seed != config.external {
+            addresses.add(*seed, 0);
+        }
+    }
 
-    if config.address != ROOT_NODE.parse().unwrap() {
-        let mut url = String::from("ws://");
-        url.push_str(&ROOT_NODE.parse::<String>().unwrap());
-        connect(url, |out| {
-            let another_clone = Arc::clone(&node);
-            client::Client::new(out, another_clone)
-        })
-        .unwrap();
+    let tls = if config.require_encryption {
+        let tls = tls::Tls::new(&identity).unwrap_or_else(|err| {
+            error!("Problem creating TLS certificate: {}", err);
+            process::exit(1)
+        });
+        Some(Arc::new(tls))
+    } else {
+        None
+    };
+
+    let node = node::Node::new(
+        config.network,
+        config.external.to_string(),
+        reputation,
+        addresses,
+        identity,
+    )
+    .unwrap_or_else(|err| {
+        error!("Problem creating node: {}", err);
+        process::exit(1)
+    })
+    .with_mining(config.mining)
+    .with_reward_address(config.reward_address.clone());
+
+    // the node is owned by the chain-processing task, connections only pass it messages
+    let mut builder = RuntimeBuilder::new();
+    if let Some(threads) = config.threads {
+        builder.core_threads(threads);
     }
+    let mut runtime = builder.build().unwrap_or_else(|err| {
+        error!("Problem starting runtime: {}", err);
+        process::exit(1)
+    });
+    let (handle, events) = processor::channel();
+
+    let (address, encrypt_server) = (config.address, config.require_encryption);
+    // WebSocket instances run on their own threads, each stopped through its sender
+    let (dialer, websockets): (Box<dyn Dialer>, Vec<_>) = match config.transport {
+        Transport::WebSocket => {
+            // outbound connections share one client instance, separate from the one accepting connections
+            let client_handle = handle.clone();
+            let client_tls = tls.clone();
+            let client = Builder::new()
+                .build(move |out| {
+                    client::Client::new(out, client_handle.clone(), client_tls.clone())
+                })
+                .unwrap();
+            let dialer =
+                websocket::WebSocketDialer::new(client.broadcaster(), config.require_encryption);
+            let client_sender = client.broadcaster();
+            let client_thread = thread::spawn(move || {
+                client.run().unwrap();
+            });
+
+            let listening_handle = handle.clone();
+            let server_tls = tls.clone();
+            let server = Builder::new()
+                .with_settings(Settings {
+                    encrypt_server,
+                    ..Settings::default()
+                })
+                .build(move |out| {
+                    let cloned_handle = listening_handle.clone();
+                    server::Server::new(out, cloned_handle, server_tls.clone())
+                })
+                .unwrap()
+                .bind(address)
+                .unwrap_or_else(|err| {
+                    error!("Problem listening on {}: {}", address, err);
+                    process::exit(1)
+                });
+            let server_sender = server.broadcaster();
+            let listening_thread = thread::spawn(move || {
+                server.run().unwrap();
+            });
+            (
+                Box::new(dialer),
+                vec![
+                    (server_sender, listening_thread),
+                    (client_sender, client_thread),
+                ],
+            )
+        }
+        Transport::Tcp => {
+            let listener = TcpListener::bind(&config.address).unwrap_or_else(|err| {
+                error!("Problem listening on {}: {}", config.address, err);
+                process::exit(1)
+            });
+            runtime.spawn(tcp::listen(listener, handle.clone()));
+            (Box::new(tcp::TcpDialer::new(handle.clone())), vec![])
+        }
+    };
+    // local tools query the node over JSON-RPC rather than speaking the peer protocol
+    let rpc_server = rpc::serve(&config.rpc_address, handle.clone()).unwrap_or_else(|err| {
+        error!("Problem serving RPC on {}: {}", config.rpc_address, err);
+        process::exit(1)
+    });
+    runtime.spawn(rpc_server);
+    let rest_server = rest::serve(&config.rest_address, handle.clone()).unwrap_or_else(|err| {
+        error!("Problem serving REST on {}: {}", config.rest_address, err);
+        process::exit(1)
+    });
+    runtime.spawn(rest_server);
+    let metrics_server =
+        metrics::serve(&config.metrics_address, handle.clone()).unwrap_or_else(|err| {
+            error!(
+                "Problem serving metrics on {}: {}",
+                config.metrics_address, err
+            );
+            process::exit(1)
+        });
+    runtime.spawn(metrics_server);
+
+    // peers are dialled as the node ticks, starting with the seeds
+    // proof of work is searched for on its own thread, so connections aren't kept waiting meanwhile
+    let miner = miner::Miner::spawn(handle.clone()).unwrap_or_else(|err| {
+        error!("Problem starting the miner: {}", err);
+        process::exit(1)
+    });
+    runtime.spawn(processor::start(
+        Processor::new(node.with_miner(miner)).with_dialer(dialer),
+        events,
+    ));
+
+    // the node runs until it is interrupted or terminated
+    let (signal, signals) = runtime
+        .block_on(signals().into_future().map_err(|(err, _)| err))
+        .unwrap_or_else(|err| {
+            error!("Problem listening for signals: {}", err);
+            process::exit(1)
+        });
+    info!(signal = signal.unwrap_or_default(); "Stopping, signal again to stop immediately");
+    runtime.spawn(
+        signals
+            .for_each(|_| -> std::io::Result<()> { process::exit(1) })
+            .map_err(|err| error!("Problem listening for signals: {}", err)),
+    );
+
+    // peers are told we're going and the node's files are flushed before the listeners stop
+    let flushed = runtime.block_on(handle.shutdown());
+    for (sender, thread) in websockets {
+        if sender.shutdown().is_ok() {
+            thread.join().unwrap();
+        }
+    }
+    runtime.shutdown_now().wait().unwrap();
+    match flushed {
+        Ok(()) => info!("Stopped"),
+        Err(err) => {
+            error!("Problem saving the node's files: {}", err);
+            process::exit(1)
+        }
+    }
+}
 
-    listening_thread.join().unwrap();
+/// SIGINT and SIGTERM, as they arrive
+fn signals() -> impl Stream<Item = i32, Error = std::io::Error> {
+    let interrupt = Signal::new(SIGINT).flatten_stream();
+    let terminate = Signal::new(SIGTERM).flatten_stream();
+    interrupt.select(terminate)
 }
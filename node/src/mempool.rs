@@ -0,0 +1,72 @@
+use redistribution::{Block, BlockData};
+
+use crate::inventory;
+use std::collections::VecDeque;
+
+/// The most transactions held waiting for a block, the oldest are dropped beyond this
+pub const MAX_MEMPOOL_TRANSACTIONS: usize = 5000;
+
+/// The most transactions mined into one block, the rest wait for the next
+pub const MAX_BLOCK_TRANSACTIONS: usize = 500;
+
+/// Transactions we have heard about which are not yet in a block on our chain
+#[derive(Debug, Default)]
+pub struct Mempool {
+    transactions: VecDeque<BlockData>,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    /// Returns false if the transaction was already waiting
+    pub fn add(&mut self, transaction: BlockData) -> bool {
+        if self.transactions.contains(&transaction) {
+            return false;
+        }
+        if self.transactions.len() >= MAX_MEMPOOL_TRANSACTIONS {
+            self.transactions.pop_front();
+        }
+        self.transactions.push_back(transaction);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// The size of every waiting transaction together
+    pub fn bytes(&self) -> usize {
+        self.transactions
+            .iter()
+            .map(|transaction| transaction.len())
+            .sum()
+    }
+
+    /// Every waiting transaction, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &BlockData> {
+        self.transactions.iter()
+    }
+
+    pub fn remove(&mut self, transaction: &BlockData) {
+        self.transactions.retain(|waiting| waiting != transaction);
+    }
+
+    pub fn get_by_hash(&self, hash: &str) -> Option<&BlockData> {
+        self.transactions
+            .iter()
+            .find(|transaction| inventory::transaction_hash(transaction) == hash)
+    }
+
+    /// Drops any transactions a newly connected block has confirmed
+    pub fn remove_confirmed(&mut self, block: &Block) {
+        let entries = block.entries();
+        self.transactions
+            .retain(|transaction| !entries.contains(transaction));
+    }
+}
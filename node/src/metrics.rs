@@ -0,0 +1,287 @@
+use futures::{future, Future};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use redistribution::Block;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::decoder::Decoder;
+use crate::node::Node;
+use crate::processor::NodeHandle;
+use crate::protocol_message::ProtocolMessage;
+use crate::transport::Connection;
+
+/// Messages and their bytes, counted by direction then message type across every connection.
+/// Shared with the connections it meters, which count what is sent as it is sent.
+#[derive(Debug, Default)]
+struct Traffic {
+    counts: Mutex<BTreeMap<(&'static str, String), Count>>,
+}
+
+#[derive(Debug, Default)]
+struct Count {
+    messages: u64,
+    bytes: u64,
+}
+
+impl Traffic {
+    fn record(&self, direction: &'static str, protocol: &ProtocolMessage, bytes: usize) {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts
+            .entry((direction, format!("{:?}", protocol)))
+            .or_default();
+        count.messages += 1;
+        count.bytes += bytes as u64;
+    }
+}
+
+/// Counts every message sent over a connection before passing it on
+struct MeteredConnection {
+    connection: Arc<dyn Connection>,
+    traffic: Arc<Traffic>,
+}
+
+impl Connection for MeteredConnection {
+    fn send(&self, mut data: Vec<u8>) -> Result<()> {
+        if let Ok(protocol) = Decoder::protocol(&mut data[..]) {
+            self.traffic.record("sent", &protocol, data.len());
+        }
+        self.connection.send(data)
+    }
+
+    fn close(&self, reason: String) -> Result<()> {
+        self.connection.close(reason)
+    }
+}
+
+/// What the node measures as it runs, beyond what can be read from its state when scraped
+#[derive(Debug, Default)]
+pub struct Metrics {
+    traffic: Arc<Traffic>,
+    blocks_validated: u64,
+    validation_time: Duration,
+    /// Hashes a second while mining the last block mined here
+    hash_rate: f64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Wraps a new connection so the messages sent over it are counted
+    pub fn meter(&self, connection: Arc<dyn Connection>) -> Arc<dyn Connection> {
+        Arc::new(MeteredConnection {
+            connection,
+            traffic: Arc::clone(&self.traffic),
+        })
+    }
+
+    pub fn received(&self, protocol: &ProtocolMessage, bytes: usize) {
+        self.traffic.record("received", protocol, bytes);
+    }
+
+    /// Records how long a block from a peer took to validate and connect
+    pub fn block_validated(&mut self, took: Duration) {
+        self.blocks_validated += 1;
+        self.validation_time += took;
+    }
+
+    /// Records how long mining a block took. Nonces are tried from 0, so it took one more hash than its nonce.
+    pub fn block_mined(&mut self, block: &Block, took: Duration) {
+        let hashes = block.nonce as f64 + 1.0;
+        self.hash_rate = hashes / took.as_secs_f64().max(f64::EPSILON);
+    }
+}
+
+/// The node's metrics in the Prometheus text format
+pub fn render(node: &Node) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        let _ = writeln!(out, "# HELP redistribution_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE redistribution_{} {}", name, kind);
+        for (suffix, value) in samples {
+            let _ = writeln!(out, "redistribution_{}{} {}", name, suffix, value);
+        }
+    };
+    let gauge = |value: String| vec![(String::new(), value)];
+
+    let height = node.blockchain.len() - 1;
+    metric(
+        "height",
+        "gauge",
+        "Height of the chain's tip",
+        gauge(height.to_string()),
+    );
+    metric(
+        "accumulated_work",
+        "gauge",
+        "Work done on the chain, summed over every block",
+        gauge(node.blockchain.get_accumulated_difficulty().to_string()),
+    );
+
+    let inbound = node.connections.inbound_count();
+    let outbound = node.connections.peers().len() - inbound;
+    metric(
+        "peers",
+        "gauge",
+        "Peers connected after a completed handshake",
+        vec![
+            (String::from("{direction=\"inbound\"}"), inbound.to_string()),
+            (
+                String::from("{direction=\"outbound\"}"),
+                outbound.to_string(),
+            ),
+        ],
+    );
+
+    let traffic = node.metrics.traffic.counts.lock().unwrap();
+    let by_message = |value: fn(&Count) -> u64| {
+        traffic
+            .iter()
+            .map(|((direction, message), count)| {
+                let labels = format!("{{direction=\"{}\",message=\"{}\"}}", direction, message);
+                (labels, value(count).to_string())
+            })
+            .collect()
+    };
+    metric(
+        "messages_total",
+        "counter",
+        "Messages exchanged with peers",
+        by_message(|count| count.messages),
+    );
+    metric(
+        "message_bytes_total",
+        "counter",
+        "Bytes of messages exchanged with peers, before any encryption or framing",
+        by_message(|count| count.bytes),
+    );
+
+    metric(
+        "mempool_transactions",
+        "gauge",
+        "Transactions waiting for a block",
+        gauge(node.mempool.len().to_string()),
+    );
+    metric(
+        "mempool_bytes",
+        "gauge",
+        "Size of the transactions waiting for a block",
+        gauge(node.mempool.bytes().to_string()),
+    );
+    metric(
+        "block_validation_seconds",
+        "summary",
+        "Time taken to validate and connect blocks from peers",
+        vec![
+            (
+                String::from("_sum"),
+                node.metrics.validation_time.as_secs_f64().to_string(),
+            ),
+            (
+                String::from("_count"),
+                node.metrics.blocks_validated.to_string(),
+            ),
+        ],
+    );
+    metric(
+        "syncing",
+        "gauge",
+        "Whether the node is catching up with a peer's chain, holding back mining until it has",
+        gauge(u8::from(node.is_syncing()).to_string()),
+    );
+    metric(
+        "orphan_blocks",
+        "gauge",
+        "Blocks held until their parent arrives",
+        gauge(node.orphans.len().to_string()),
+    );
+    metric(
+        "hash_rate",
+        "gauge",
+        "Hashes a second while mining the last block mined here",
+        gauge(node.metrics.hash_rate.to_string()),
+    );
+    out
+}
+
+/// Serves the node's metrics on `address` for Prometheus to scrape from `/metrics`
+pub fn serve(
+    address: &SocketAddr,
+    handle: NodeHandle,
+) -> Result<impl Future<Item = (), Error = ()>> {
+    let server = Server::try_bind(address).map_err(Error::other)?;
+    info!(address:% = address; "Metrics listening");
+    Ok(server
+        .serve(move || {
+            let handle = handle.clone();
+            service_fn(move |request| respond(request, handle.clone()))
+        })
+        .map_err(|e| error!(error:% = e; "Metrics server failed")))
+}
+
+fn respond(
+    request: Request<Body>,
+    handle: NodeHandle,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    if request.method() != Method::GET {
+        return Box::new(future::ok(status(StatusCode::METHOD_NOT_ALLOWED)));
+    }
+    if request.uri().path() != "/metrics" {
+        return Box::new(future::ok(status(StatusCode::NOT_FOUND)));
+    }
+    Box::new(handle.call(|node| render(node)).map(|metrics| {
+        let mut response = Response::new(Body::from(metrics));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        );
+        response
+    }))
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    #[test]
+    fn test_metrics_count_chain_and_traffic() {
+        let mut simulator = Simulator::new(2, 5);
+        simulator.connect(1, 0);
+        simulator.run_for(Duration::from_secs(30));
+        simulator.mine(0, "local");
+        simulator.run_for(Duration::from_secs(5));
+        simulator.mine(1, "measured");
+        simulator.run_for(Duration::from_secs(5));
+
+        // one block mined here and one received, which is the one validated
+        let (_, height) = simulator.tip(0);
+        assert_eq!(height, 2);
+        let metrics = simulator.call(0, |node| render(node));
+        assert!(metrics.contains("\nredistribution_height 2\n"));
+        assert!(metrics.contains("\nredistribution_peers{direction=\"inbound\"} 1\n"));
+        assert!(metrics.contains("\nredistribution_block_validation_seconds_count 1\n"));
+        assert!(metrics.contains("\nredistribution_syncing 0\n"));
+        assert!(metrics.contains(
+            "redistribution_messages_total{direction=\"received\",message=\"Version\"} 1\n"
+        ));
+        assert!(metrics
+            .contains("redistribution_messages_total{direction=\"sent\",message=\"VerAck\"} 1\n"));
+        let mined = simulator.call(1, |node| render(node));
+        assert!(!mined.contains("\nredistribution_hash_rate 0\n"));
+    }
+}
@@ -0,0 +1,136 @@
+use log::{debug, warn};
+use redistribution::BlockTemplate;
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use crate::processor::NodeHandle;
+
+/// A template to mine, and the flag that gives up on it
+type Job = (BlockTemplate, Arc<AtomicBool>);
+
+/// Searches for proof of work on its own thread, so the chain-processing task carries on meanwhile.
+/// Each block found is sent back to the node as an event, and each new job replaces the last.
+#[derive(Debug)]
+pub struct Miner {
+    jobs: Option<mpsc::Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+    /// The flag cancelling the job in progress, and the tip it builds on
+    current: Option<(Arc<AtomicBool>, String)>,
+}
+
+impl Miner {
+    pub fn spawn(handle: NodeHandle) -> Result<Miner> {
+        let (jobs, received) = mpsc::channel::<Job>();
+        let thread = thread::Builder::new()
+            .name(String::from("miner"))
+            .spawn(move || {
+                for (template, cancelled) in received {
+                    let started = Instant::now();
+                    let block = match template.mine(&cancelled) {
+                        Some(block) => block,
+                        None => continue,
+                    };
+                    if let Err(e) = handle.mined(block, started.elapsed()) {
+                        warn!(error:% = e; "Failed to pass on a mined block");
+                        return;
+                    }
+                }
+            })?;
+        Ok(Miner {
+            jobs: Some(jobs),
+            thread: Some(thread),
+            current: None,
+        })
+    }
+
+    /// Starts mining a template, giving up on whatever was being mined before
+    pub fn mine(&mut self, template: BlockTemplate) {
+        self.cancel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let tip = template.previous_hash.clone();
+        if let Some(jobs) = &self.jobs {
+            if jobs.send((template, Arc::clone(&cancelled))).is_ok() {
+                self.current = Some((cancelled, tip));
+            }
+        }
+    }
+
+    pub fn cancel(&mut self) {
+        if let Some((cancelled, tip)) = self.current.take() {
+            debug!(tip = tip.as_str(); "Cancelled mining");
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The tip the job in progress builds on, if there is one
+    pub fn mining_on(&self) -> Option<&str> {
+        self.current.as_ref().map(|(_, tip)| tip.as_str())
+    }
+
+    /// Forgets the job in progress once its block has arrived
+    pub fn finished(&mut self, tip: &str) {
+        if self.mining_on() == Some(tip) {
+            self.current = None;
+        }
+    }
+
+    /// Gives up on any job in progress and waits for the thread to end
+    pub fn stop(&mut self) {
+        self.cancel();
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Miner thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for Miner {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{self, Event};
+    use futures::{Future, Stream};
+    use redistribution::{Blockchain, Network};
+
+    #[test]
+    fn test_blocks_mined_off_the_node_and_jobs_replaced() {
+        let blockchain = Blockchain::new(Network::Regtest);
+        let template = |data: &str| {
+            blockchain
+                .next_block_template(&[data.to_string()], None)
+                .unwrap()
+        };
+        let (handle, events) = processor::channel();
+        let mut miner = Miner::spawn(handle).unwrap();
+
+        // a job that can't finish is given up on when the next one starts
+        let mut impossible = template("never");
+        impossible.difficulty = 255;
+        miner.mine(impossible);
+        miner.mine(template("mined"));
+        assert_eq!(
+            miner.mining_on(),
+            Some(blockchain.get_latest_block().unwrap().hash.as_str())
+        );
+
+        let event = events.into_future().wait().ok().unwrap().0;
+        let block = match event {
+            Some(Event::Mined { block, .. }) => block,
+            _ => panic!("expected a mined block"),
+        };
+        assert_eq!(block.entries(), vec![String::from("mined")]);
+        miner.finished(&block.previous_hash);
+        assert_eq!(miner.mining_on(), None);
+        miner.stop();
+    }
+}
@@ -1,64 +1,794 @@
-use redistribution::Blockchain;
-use serde::{Deserialize, Serialize};
+use log::{debug, info, warn};
+use redistribution::{
+    transaction_id, Address, Block, BlockData, BlockHeader, Blockchain, ChainParams, Encodable,
+    Ledger, Network, Transaction,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind, Result};
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use uuid::Uuid;
 
+use crate::addrman::{self, AddressEntry, AddressManager};
+use crate::connections::{self, Connections, PeerInfo};
 use crate::decoder::{DecodedType, Decoder};
+use crate::download;
 use crate::encoder::Encoder;
+use crate::identity::Identity;
+use crate::inventory::{self, Inventory, InventoryItem, InventoryType};
+use crate::mempool::{Mempool, MAX_BLOCK_TRANSACTIONS};
+use crate::metrics::Metrics;
+use crate::miner::Miner;
+use crate::orphans::{self, OrphanPool};
 use crate::peerlist;
 
 use crate::protocol_message::ProtocolMessage;
+use crate::reputation::{self, Misbehaviour, Reputation};
+use crate::sync::{self, ChainSync};
+use crate::transport::Connection;
+use crate::version::{self, Version};
 use peerlist::PeerList;
 
 #[derive(Debug)]
 pub struct Message {
-    pub broadcast: bool,
-    pub connect: Option<SocketAddr>,
     pub raw_message: Option<Vec<u8>>,
+    /// Messages addressed to particular peers rather than the connection being answered
+    pub direct: Vec<(Uuid, Vec<u8>)>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 pub struct Node {
     pub id: Uuid,
-    blockchain: Blockchain,
+    pub blockchain: Blockchain,
     pub peerlist: PeerList,
     address: String,
+    #[serde(skip)]
+    sync: Option<ChainSync>,
+    #[serde(skip)]
+    pub connections: Connections,
+    /// The outputs our chain has not spent, kept up to date as blocks connect
+    #[serde(skip)]
+    pub ledger: Ledger,
+    /// The height of the block on our chain carrying each entry, by the entry's hash
+    #[serde(skip)]
+    confirmed: HashMap<String, u32>,
+    #[serde(skip)]
+    pub mempool: Mempool,
+    #[serde(skip)]
+    pub orphans: OrphanPool,
+    #[serde(skip)]
+    inventory: Inventory,
+    #[serde(skip)]
+    reputation: Reputation,
+    #[serde(skip)]
+    identity: Identity,
+    #[serde(skip)]
+    pub addresses: AddressManager,
+    #[serde(skip)]
+    pub metrics: Metrics,
+    /// Whether transactions submitted to us are mined here, rather than passed to the peers that mine
+    #[serde(skip)]
+    mining: bool,
+    /// Who the blocks mined here pay their reward to, if anyone
+    #[serde(skip)]
+    reward_address: Option<Address>,
+    /// Mines blocks off the chain-processing task. Without one, as in the simulator,
+    /// blocks are mined in place so that runs stay deterministic.
+    #[serde(skip)]
+    miner: Option<Miner>,
+    /// Where timeouts read the time from, so a simulated network can control it
+    #[serde(skip)]
+    clock: fn() -> Instant,
 }
 
 impl Node {
-    pub fn new(address: String) -> Arc<Mutex<Node>> {
-        Arc::new(Mutex::new(Node {
-            id: PeerList::get_new_peer_id(address.as_bytes()),
-            blockchain: Blockchain::new(),
+    pub fn new(
+        network: Network,
+        address: String,
+        reputation: Reputation,
+        addresses: AddressManager,
+        identity: Identity,
+    ) -> Result<Node> {
+        let mut node = Node {
+            id: identity.node_id()?,
+            blockchain: Blockchain::new(network),
             peerlist: PeerList::new(),
             address,
-        }))
+            sync: None,
+            connections: Connections::new(),
+            ledger: Ledger::new(),
+            confirmed: HashMap::new(),
+            mempool: Mempool::new(),
+            orphans: OrphanPool::new(),
+            inventory: Inventory::new(),
+            reputation,
+            identity,
+            addresses,
+            metrics: Metrics::new(),
+            mining: true,
+            reward_address: None,
+            miner: None,
+            clock: Instant::now,
+        };
+        node.blocks_connected_from(0);
+        Ok(node)
+    }
+
+    /// Writes out what the node keeps between runs: the addresses it knows and the peers it has banned.
+    /// The chain itself is only kept in memory, and synced again from peers on the next run.
+    pub fn flush(&mut self) -> Result<()> {
+        self.addresses.save()?;
+        self.reputation.save(SystemTime::now())
+    }
+
+    /// The time according to the node's clock
+    pub fn now(&self) -> Instant {
+        (self.clock)()
+    }
+
+    /// Whether we are catching up with a peer's chain, during which nothing is mined here
+    pub fn is_syncing(&self) -> bool {
+        self.sync.is_some()
+    }
+
+    pub fn with_mining(self, mining: bool) -> Node {
+        Node { mining, ..self }
+    }
+
+    pub fn with_reward_address(self, reward_address: Option<Address>) -> Node {
+        Node {
+            reward_address,
+            ..self
+        }
+    }
+
+    pub fn with_miner(self, miner: Miner) -> Node {
+        Node {
+            miner: Some(miner),
+            ..self
+        }
+    }
+
+    /// Gives up on any block being mined and stops the miner, as the node is shutting down
+    pub fn stop_mining(&mut self) {
+        if let Some(mut miner) = self.miner.take() {
+            miner.stop();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(self, clock: fn() -> Instant) -> Node {
+        Node { clock, ..self }
+    }
+
+    /// Our version, carrying a challenge for the peer to sign
+    pub fn version(&self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let best_height = self.blockchain.get_latest_block()?.index;
+        let services = match self.mining {
+            true => version::NODE_NETWORK | version::NODE_MINING,
+            false => version::NODE_NETWORK,
+        };
+        let version = Version::new(
+            best_height,
+            self.params().genesis_hash.to_string(),
+            services,
+            &self.identity.public_key()?,
+            challenge,
+        );
+        let message = self.encode(ProtocolMessage::Version, &version)?;
+        Ok(message)
+    }
+
+    /// Acknowledges a peer's version by signing its challenge
+    pub fn verack(&self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let signature = hex::encode(self.identity.sign(challenge)?);
+        let message = self.encode(ProtocolMessage::VerAck, &signature)?;
+        Ok(message)
+    }
+
+    /// A message to a peer, marked with our network's magic and our ID
+    fn encode<T: Encodable>(&self, protocol: ProtocolMessage, data: &T) -> Result<Vec<u8>> {
+        Encoder::encode(&self.params().magic, protocol, self.id, data)
+    }
+
+    pub fn params(&self) -> &'static ChainParams {
+        self.blockchain.params()
+    }
+
+    /// The messages sent to a peer we dialled once the handshake with it completes
+    pub fn join_network(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![
+            // initial set-up involves a request to be added to the networ
+            self.add_me()?,
+            // then discovering and adding peers
+            self.get_peers()?,
+            self.get_headers()?,
+            self.get_addr()?,
+        ])
     }
 
     pub fn add_me(&mut self) -> Result<Vec<u8>> {
-        let message = Encoder::encode(ProtocolMessage::AddMe, self.id, &self.address)?;
+        let message = self.encode(ProtocolMessage::AddMe, &self.address)?;
         Ok(message)
     }
 
     pub fn get_peers(&mut self) -> Result<Vec<u8>> {
-        let message = Encoder::encode(ProtocolMessage::GetPeers, self.id, &String::new())?;
+        let message = self.encode(ProtocolMessage::GetPeers, &String::new())?;
         Ok(message)
     }
 
-    pub fn send_transactions(&self) -> Result<Vec<u8>> {
-        let transaction = String::from("hello"); // TODO: this should be actual data!
-        let message = Encoder::encode(ProtocolMessage::AddTransaction, self.id, &transaction)?;
+    pub fn get_addr(&self) -> Result<Vec<u8>> {
+        let message = self.encode(ProtocolMessage::GetAddr, &String::new())?;
         Ok(message)
     }
 
-    pub fn get_chain(&mut self) -> Result<Vec<u8>> {
-        let message = Encoder::encode(ProtocolMessage::GetBlocks, self.id, &String::new())?;
+    pub fn get_headers(&mut self) -> Result<Vec<u8>> {
+        let locator = self.blockchain.get_block_locator();
+        let message = self.encode(ProtocolMessage::GetHeaders, &locator)?;
         Ok(message)
     }
 
+    /// Registers a peer we have completed a handshake with, making it available to download blocks from.
+    /// `address` is the one we dialled if we opened the connection.
+    /// When both sides dial each other at once, both keep the connection opened by the lower node ID.
+    /// A peer connecting to us once we are full evicts the least useful peer that connected to us,
+    /// or is turned away if every one of them has been useful.
+    pub fn add_connection(
+        &mut self,
+        peer: Uuid,
+        connection: Arc<dyn Connection>,
+        address: Option<SocketAddr>,
+        remote: Option<IpAddr>,
+        services: u64,
+    ) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        // the address worked even if the connection turns out to be a duplicate
+        if let Some(address) = address {
+            let last_seen = reputation::seconds_since_epoch(SystemTime::now())?;
+            self.addresses.mark_connected(address, last_seen)?;
+        }
+        if self.connections.contains(&peer) {
+            let dialled_by_lower = match address {
+                Some(_) => self.id < peer,
+                None => peer < self.id,
+            };
+            if self.connections.is_outbound(&peer) == address.is_some() || !dialled_by_lower {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    "Already connected to peer",
+                ));
+            }
+            self.connections
+                .disconnect(&peer, "Replaced by another connection")?;
+        } else if address.is_some() {
+            if self.connections.outbound_addresses().len() >= addrman::TARGET_OUTBOUND {
+                return Err(Error::new(
+                    ErrorKind::ConnectionRefused,
+                    "Too many outbound connections",
+                ));
+            }
+        } else if self.connections.inbound_count() >= connections::MAX_INBOUND {
+            match self.connections.least_useful_inbound() {
+                Some(evicted) => {
+                    info!(peer:% = evicted, replacement:% = peer; "Evicting peer to make room for another");
+                    self.connections
+                        .disconnect(&evicted, "Evicted to make room for another peer")?;
+                }
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::ConnectionRefused,
+                        "Too many inbound connections",
+                    ))
+                }
+            }
+        }
+        self.connections
+            .add(peer, connection, address, remote, services, (self.clock)());
+        // the address we dialled reached the peer, so it can be listed
+        if let Some(address) = address {
+            self.peerlist.verify(peer, address);
+        }
+        self.request_blocks()
+    }
+
+    /// Forgets a peer that has disconnected, passing any blocks we were waiting on from it to other peers.
+    /// Nothing changes if the connection had already been replaced by another to the same peer.
+    pub fn remove_connection(
+        &mut self,
+        peer: &Uuid,
+        connection: &Arc<dyn Connection>,
+    ) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        if !self.connections.remove(peer, connection) {
+            return Ok(vec![]);
+        }
+        if self.peerlist.remove(peer) {
+            debug!(peer:% = peer, peers = self.peerlist.peers.len(); "Removed peer from peerlist");
+        }
+        self.inventory.remove_peer(peer);
+        match self.sync.take() {
+            // without the sync peer we can't learn the rest of its headers
+            Some(chain_sync) if chain_sync.peer == *peer && chain_sync.more_headers() => {
+                warn!(peer:% = peer; "Lost sync peer, abandoning sync");
+                self.mine_pending()
+            }
+            Some(mut chain_sync) => {
+                chain_sync.remove_peer(peer);
+                self.sync = Some(chain_sync);
+                self.request_blocks()
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Describes every connected peer, sorted by ID
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        let mut peers = self.connections.info((self.clock)());
+        for peer in peers.iter_mut() {
+            if peer.address.is_none() {
+                peer.address = self.peerlist.peers.get(&peer.id).cloned();
+            }
+        }
+        peers.sort_by_key(|peer| peer.id);
+        peers
+    }
+
+    /// Chooses addresses to dial, so we keep `TARGET_OUTBOUND` connections to peers we chose ourselves
+    /// rather than relying on whoever connects to us
+    pub fn select_outbound(&mut self) -> Vec<SocketAddr> {
+        let now = (self.clock)();
+        let outbound = self.connections.outbound_addresses();
+        let connecting = outbound.len() + self.addresses.pending(now);
+        if connecting >= addrman::TARGET_OUTBOUND {
+            return vec![];
+        }
+        // peers that connected to us are skipped too, using the address they asked to be added with
+        let mut connected: HashSet<SocketAddr> = outbound.into_iter().collect();
+        for peer in self.connections.peers() {
+            if let Some(address) = self.peerlist.peers.get(&peer) {
+                connected.insert(*address);
+            }
+        }
+        let selected =
+            self.addresses
+                .select(addrman::TARGET_OUTBOUND - connecting, &connected, now);
+        for address in selected.iter() {
+            self.addresses.mark_attempt(*address, now);
+        }
+        selected
+    }
+
+    /// Whether connections from or to an address are refused, as a banned peer connected from it
+    pub fn is_address_banned(&self, address: &IpAddr) -> bool {
+        self.reputation
+            .is_address_banned(address, SystemTime::now())
+    }
+
+    /// Remembers a peer's address, unless it is our own or banned
+    fn learn_address(&mut self, address: SocketAddr, last_seen: u64) {
+        if address.to_string() != self.address && !self.is_address_banned(&address.ip()) {
+            self.addresses.add(address, last_seen);
+        }
+    }
+
+    /// Called periodically to hand requests that have timed out to other peers,
+    /// drop orphans whose parents never arrived, and check our peers are still there
+    pub fn tick(&mut self) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        let now = (self.clock)();
+        self.orphans.expire(&self.blockchain, now);
+        self.inventory.expire(now);
+        let mut direct = self.keep_alive(now)?;
+        if let Some(chain_sync) = &mut self.sync {
+            chain_sync.expire_requests(now);
+            direct.extend(self.request_blocks()?);
+        }
+        Ok(direct)
+    }
+
+    /// Disconnects peers that haven't answered a ping in time, returning pings for those due another
+    fn keep_alive(&mut self, now: Instant) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        for peer in self.connections.timed_out(now) {
+            info!(peer:% = peer; "Peer did not answer a ping, disconnecting");
+            self.connections.disconnect(&peer, "Ping timed out")?;
+        }
+        let mut pings = vec![];
+        for (peer, nonce) in self.connections.pings_due(now) {
+            pings.push((peer, self.encode(ProtocolMessage::Ping, &nonce)?));
+        }
+        Ok(pings)
+    }
+
+    /// Shares out the blocks still to download between every connected peer with spare capacity
+    fn request_blocks(&mut self) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        let peers = self.connections.peers();
+        let assignments = match &mut self.sync {
+            Some(chain_sync) => chain_sync.assign_requests(&peers, (self.clock)()),
+            None => return Ok(vec![]),
+        };
+        let mut requests = vec![];
+        for (peer, hashes) in assignments {
+            let items: Vec<InventoryItem> = hashes
+                .iter()
+                .map(|hash| InventoryItem::block(hash))
+                .collect();
+            let message = self.encode(ProtocolMessage::GetData, &items)?;
+            requests.push((peer, message));
+        }
+        Ok(requests)
+    }
+
+    /// Ends a completed sync, then connects any orphans that now follow our tip
+    fn finish_sync_if_complete(&mut self) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        match self.sync.take() {
+            Some(chain_sync) if chain_sync.is_complete() => {
+                let sync_peer = chain_sync.peer;
+                if let Some(replaced_from) = chain_sync.finish(&mut self.blockchain) {
+                    info!(peer:% = sync_peer; "Switched to a fork with more accumulated work");
+                    self.chain_replaced(replaced_from);
+                }
+                let tip = self.blockchain.get_latest_block()?;
+                info!(peer:% = sync_peer, height = tip.index, block = tip.hash.as_str(); "Synced");
+                let mut direct = self.connect_orphans()?;
+                direct.extend(self.mine_pending()?);
+                // headers other peers sent during the sync were ignored, so they are asked again
+                for peer in self.connections.peers() {
+                    if peer != sync_peer {
+                        direct.push((peer, self.get_headers()?));
+                    }
+                }
+                Ok(direct)
+            }
+            chain_sync => {
+                self.sync = chain_sync;
+                Ok(vec![])
+            }
+        }
+    }
+
+    /// Connects orphans whose parent is our tip, and then any of theirs in turn,
+    /// returning the announcements for each one connected
+    fn connect_orphans(&mut self) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        let mut relays = vec![];
+        if self.sync.is_some() {
+            return Ok(relays);
+        }
+        loop {
+            let tip = self.blockchain.get_latest_block()?.hash.clone();
+            let children = self.orphans.take_children(&tip);
+            // of several orphans competing for the tip, the first valid one wins and the rest are dropped
+            let started = Instant::now();
+            let connected = children
+                .into_iter()
+                .find(|(_, block)| self.blockchain.add_block(block.clone()).is_ok());
+            match connected {
+                Some((peer, block)) => {
+                    self.metrics.block_validated(started.elapsed());
+                    self.block_connected(&block);
+                    info!(
+                        peer:% = peer,
+                        height = block.index,
+                        block = block.hash.as_str();
+                        "Connected orphan block"
+                    );
+                    relays.extend(self.announce(vec![InventoryItem::block(&block.hash)], &peer)?);
+                }
+                None => return Ok(relays),
+            }
+        }
+    }
+
+    /// Connects a block sent by a peer if it extends our tip,
+    /// announcing it to our other peers only if it was new and valid
+    fn receive_new_block(&mut self, peer: Uuid, block: Block) -> Result<Message> {
+        let mut message = Message {
+            raw_message: None,
+            direct: vec![],
+        };
+        if self.blockchain.get_block_by_hash(&block.hash).is_some() {
+            return Ok(message);
+        }
+        // connecting blocks beneath the sync would break it, so the sync peer is asked to extend it instead
+        if let Some(chain_sync) = &mut self.sync {
+            if chain_sync.request_more_headers() {
+                let (peer, locator) = (
+                    chain_sync.peer,
+                    chain_sync.get_block_locator(&self.blockchain),
+                );
+                let data = self.encode(ProtocolMessage::GetHeaders, &locator)?;
+                message.direct.push((peer, data));
+            }
+            return Ok(message);
+        }
+
+        let tip = self.blockchain.get_latest_block()?;
+        let tip_height = tip.index;
+        if block.previous_hash == tip.hash {
+            let started = Instant::now();
+            self.blockchain
+                .add_block(block.clone())
+                .map_err(|e| Misbehaviour::InvalidBlock.error(e))?;
+            self.metrics.block_validated(started.elapsed());
+            self.block_connected(&block);
+            self.connections.mark_useful(&peer, (self.clock)());
+            info!(
+                peer:% = peer,
+                height = block.index,
+                block = block.hash.as_str();
+                "Connected new block"
+            );
+
+            message.direct = self.announce(vec![InventoryItem::block(&block.hash)], &peer)?;
+            message.direct.extend(self.connect_orphans()?);
+            message.direct.extend(self.remine_if_tip_moved()?);
+            return Ok(message);
+        }
+
+        // a block we can't connect is only remembered once it is shown to be mined as it claims,
+        // so a peer can't fill the orphan pool with made-up blocks or pass them off as real ones
+        self.blockchain
+            .check_proof_of_work(&block)
+            .map_err(|e| Misbehaviour::InvalidBlock.error(e))?;
+        self.inventory.mark_seen(&block.hash);
+        if self
+            .blockchain
+            .get_block_by_hash(&block.previous_hash)
+            .is_none()
+        {
+            // we are missing its parents, so hold it and ask the peer for the headers leading up to it
+            if self.orphans.contains(&block.hash) {
+                return Ok(message);
+            }
+            if block.index.abs_diff(tip_height) <= orphans::MAX_ORPHAN_DISTANCE
+                && !self.orphans.add(peer, block, (self.clock)())
+            {
+                return Err(Misbehaviour::Spam.error("Too many orphan blocks from peer"));
+            }
+            message.direct.push((peer, self.get_headers()?));
+        }
+        // otherwise it competes with a block we already have, and we keep the one we saw first
+        Ok(message)
+    }
+
+    /// Adds a transaction to the mempool, then has the mempool mined into a new block on our tip,
+    /// see `mine_pending`. Nothing is mined if the transaction was already waiting,
+    /// or while syncing, as moving our tip would break the sync. The mempool is mined once it ends.
+    /// A node that doesn't mine passes the transaction to every peer that does instead.
+    pub fn add_transaction(&mut self, data: BlockData) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        self.check_transaction(&data)?;
+        if !self.mining {
+            // only peers that mine are asked to, and they don't pass it on, so it can't go round in circles
+            let miners = self.connections.miners();
+            if miners.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    "No connected peer mines transactions",
+                ));
+            }
+            self.mempool.add(data.clone());
+            let message = self.encode(ProtocolMessage::AddTransaction, &data)?;
+            return Ok(miners
+                .into_iter()
+                .map(|peer| (peer, message.clone()))
+                .collect());
+        }
+        if !self.mempool.add(data.clone()) {
+            return Ok(vec![]);
+        }
+        self.mine_pending()
+    }
+
+    /// Mines the waiting transactions still valid on our tip into a new block, replacing any block
+    /// the miner was working on. Those no longer valid, such as ones spent by a block from a peer, are dropped.
+    /// Nothing is mined by a node that doesn't mine, while syncing, or with nothing waiting.
+    /// Without a miner the block is mined in place, and its announcements returned.
+    fn mine_pending(&mut self) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        if let Some(miner) = &mut self.miner {
+            miner.cancel();
+        }
+        if !self.mining || self.sync.is_some() {
+            return Ok(vec![]);
+        }
+        let mut ledger = self.ledger.clone();
+        let mut entries = vec![];
+        let mut invalid = vec![];
+        for data in self.mempool.iter().take(MAX_BLOCK_TRANSACTIONS) {
+            // later transactions may spend earlier ones, so each is applied as it is taken
+            match Transaction::from_data(data) {
+                Some(transaction) => match ledger.validate(&transaction) {
+                    Ok(()) => {
+                        ledger.apply(&transaction_id(data), &transaction);
+                        entries.push(data.clone());
+                    }
+                    Err(_e) => invalid.push(data.clone()),
+                },
+                None => entries.push(data.clone()),
+            }
+        }
+        for data in invalid.iter() {
+            debug!(transaction = inventory::transaction_hash(data).as_str(); "Dropped transaction no longer valid");
+            self.mempool.remove(data);
+        }
+        if entries.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let template = self
+            .blockchain
+            .next_block_template(&entries, self.reward_address.as_deref())?;
+        match &mut self.miner {
+            Some(miner) => {
+                miner.mine(template);
+                Ok(vec![])
+            }
+            None => {
+                let started = Instant::now();
+                match template.mine(&AtomicBool::new(false)) {
+                    Some(new_block) => self.connect_mined(new_block, started.elapsed()),
+                    None => Ok(vec![]),
+                }
+            }
+        }
+    }
+
+    /// Connects a block the miner found, then mines whatever is still waiting.
+    /// Returns the block's announcements.
+    pub fn block_mined(&mut self, block: Block, took: Duration) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        if let Some(miner) = &mut self.miner {
+            miner.finished(&block.previous_hash);
+        }
+        let hash = block.hash.clone();
+        let mut announcements = self.connect_mined(block, took)?;
+        let connected = self.blockchain.get_latest_block()?.hash == hash;
+        if connected && !self.mempool.is_empty() {
+            announcements.extend(self.mine_pending()?);
+        }
+        Ok(announcements)
+    }
+
+    /// Connects a block mined here if it still extends our tip,
+    /// as a block from a peer or a sync may have replaced the tip while it was mined
+    fn connect_mined(&mut self, block: Block, took: Duration) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        let tip = self.blockchain.get_latest_block()?;
+        if self.sync.is_some() || block.previous_hash != tip.hash {
+            debug!(block = block.hash.as_str(); "Dropped block mined on a replaced tip");
+            return Ok(vec![]);
+        }
+        self.metrics.block_mined(&block, took);
+        self.blockchain.add_block(block.clone())?;
+        self.block_connected(&block);
+
+        let id = self.id;
+        self.announce(vec![InventoryItem::block(&block.hash)], &id)
+    }
+
+    /// Restarts a block the miner was working on a tip we have since moved past,
+    /// so what it carried that is still waiting is mined on the new tip
+    fn remine_if_tip_moved(&mut self) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        let tip = self.blockchain.get_latest_block()?.hash.clone();
+        match self.miner.as_ref().and_then(Miner::mining_on) {
+            Some(mining_on) if mining_on != tip => self.mine_pending(),
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Checks a transaction could be mined on our tip. Block data that isn't a transaction is let through,
+    /// as blocks may carry anything, but rewards aren't, as only a block's miner adds one.
+    /// Peers aren't penalised for an invalid transaction, which may only have been spent on another tip.
+    pub fn check_transaction(&self, data: &BlockData) -> Result<()> {
+        match Transaction::from_data(data) {
+            Some(transaction) => self
+                .ledger
+                .validate(&transaction)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Penalises a peer for a message we couldn't handle, disconnecting it if that gets it banned.
+    /// Returns true if the peer was banned.
+    pub fn misbehaving(&mut self, peer: &Uuid, error: &Error) -> Result<bool> {
+        let misbehaviour = match Misbehaviour::of(error) {
+            Some(misbehaviour) => misbehaviour,
+            None => return Ok(false),
+        };
+        let remote = self.connections.remote(peer);
+        if self
+            .reputation
+            .penalise(peer, remote, misbehaviour, SystemTime::now())?
+        {
+            warn!(peer:% = peer, error:% = error; "Banned peer for misbehaviour");
+            if let Some(remote) = remote {
+                self.addresses.forget(remote);
+            }
+            self.connections
+                .disconnect(peer, "Banned for misbehaviour")?;
+            return Ok(true);
+        }
+        info!(
+            peer:% = peer,
+            misbehaviour:? = misbehaviour,
+            error:% = error,
+            score = self.reputation.score(peer, SystemTime::now());
+            "Peer misbehaved"
+        );
+        Ok(false)
+    }
+
+    /// The block on our chain carrying the entry with this hash, and the entry
+    pub fn confirmed_entry(&self, hash: &str) -> Option<(&Block, BlockData)> {
+        let height = *self.confirmed.get(hash)?;
+        let block = self.blockchain.get_block_at_index(height as usize)?;
+        let entry = block
+            .entries()
+            .into_iter()
+            .find(|entry| inventory::transaction_hash(entry) == hash)?;
+        Some((block, entry))
+    }
+
+    /// Rebuilds what is kept about our chain once the blocks from `height` have been replaced by a fork's
+    fn chain_replaced(&mut self, height: usize) {
+        self.ledger = Ledger::new();
+        for block in self.blockchain.iter().take(height) {
+            self.ledger.apply_block(block);
+        }
+        self.confirmed
+            .retain(|_, confirmed_at| (*confirmed_at as usize) < height);
+        self.blocks_connected_from(height);
+    }
+
+    /// Keeps what is kept about our chain up to date with its blocks from `height` to the tip
+    fn blocks_connected_from(&mut self, height: usize) {
+        let blocks: Vec<Block> = self.blockchain.iter().skip(height).cloned().collect();
+        for block in blocks.iter() {
+            self.block_connected(block);
+        }
+    }
+
+    /// Keeps the ledger, the index of confirmed entries, the mempool and seen-set up to date
+    /// with a block connected to our chain
+    fn block_connected(&mut self, block: &Block) {
+        self.ledger.apply_block(block);
+        self.mempool.remove_confirmed(block);
+        self.inventory.mark_seen(&block.hash);
+        for entry in block.entries() {
+            let hash = inventory::transaction_hash(&entry);
+            self.inventory.mark_seen(&hash);
+            self.confirmed.insert(hash, block.index);
+        }
+    }
+
+    /// Announces items to every connected peer except the one we got them from
+    fn announce(&mut self, items: Vec<InventoryItem>, from: &Uuid) -> Result<Vec<(Uuid, Vec<u8>)>> {
+        for item in items.iter() {
+            self.inventory.mark_seen(&item.hash);
+        }
+        let data = self.encode(ProtocolMessage::Inventory, &items)?;
+        Ok(self.relay(&data, from))
+    }
+
+    /// Whether an announced item is one we don't have yet
+    fn is_missing(&self, item: &InventoryItem) -> bool {
+        match item.kind {
+            InventoryType::Block => {
+                self.blockchain.get_block_by_hash(&item.hash).is_none()
+                    && !self.orphans.contains(&item.hash)
+            }
+            InventoryType::Transaction => self.mempool.get_by_hash(&item.hash).is_none(),
+        }
+    }
+
+    /// Addresses a message to every connected peer except the one it came from
+    fn relay(&self, data: &[u8], from: &Uuid) -> Vec<(Uuid, Vec<u8>)> {
+        self.connections
+            .peers()
+            .into_iter()
+            .filter(|peer| peer != from)
+            .map(|peer| (peer, data.to_vec()))
+            .collect()
+    }
+
     pub fn handle_message(&mut self, message: &mut Vec<u8>) -> Result<Message> {
         if message.len() == 0 {
             return Err(Error::new(
@@ -69,34 +799,80 @@ impl Node {
         let opcode = Decoder::protocol(&mut message[..]); // TODO: Make buffer as long as headers - then allocate dynamic array buffer for the data
 
         match opcode {
+            Ok(ProtocolMessage::Version) => {
+                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::Version);
+                let decoded_type = decoder.decode_json()?;
+                match decoded_type {
+                    DecodedType::Version(version) => {
+                        if decoder.peer_id() == self.id {
+                            return Err(Error::new(
+                                ErrorKind::ConnectionRefused,
+                                "Connected to ourselves",
+                            ));
+                        }
+                        if self
+                            .reputation
+                            .is_banned(&decoder.peer_id(), SystemTime::now())
+                        {
+                            return Err(Error::new(ErrorKind::ConnectionRefused, "Peer is banned"));
+                        }
+                        version.check_compatible(self.params().genesis_hash)?;
+                        info!(
+                            peer:% = decoder.peer_id(),
+                            user_agent = version.user_agent.as_str(),
+                            height = version.best_height;
+                            "Peer running"
+                        );
+                        Ok(Message {
+                            raw_message: Some(self.verack(&version.challenge()?)?),
+                            direct: vec![],
+                        })
+                    }
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Wrong decoding type used in Version command",
+                    )),
+                }
+            }
+            Ok(ProtocolMessage::VerAck) => Ok(Message {
+                raw_message: None,
+                direct: vec![],
+            }),
             Ok(ProtocolMessage::AddMe) => {
                 let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::AddMe);
                 let decoder_type = decoder.decode_json()?;
                 match decoder_type {
                     DecodedType::NewPeer(peer_ip) => {
-                        let new_key = self.peerlist.peers.insert(decoder.peer_id(), peer_ip); // TODO: this should return error if peer already exists (ID is taken)
+                        let last_seen = reputation::seconds_since_epoch(SystemTime::now())?;
+                        self.learn_address(peer_ip, last_seen);
+                        // the handshake proved the ID is the peer's own, so it can only be moving address
+                        let new_key = self.peerlist.verify(decoder.peer_id(), peer_ip);
                         match new_key {
                             Some(_) => {
                                 // If we already had the key - no need to rebroadcast
                                 Ok(Message {
-                                    broadcast: false,
-                                    connect: None,
                                     raw_message: None,
+                                    direct: vec![],
                                 })
                             }
                             None => {
-                                println!("Updated peerlist: {:?}", self.peerlist.peers);
+                                debug!(
+                                    peer:% = decoder.peer_id(),
+                                    address:% = peer_ip,
+                                    peers = self.peerlist.peers.len();
+                                    "Added peer to peerlist"
+                                );
 
-                                let message = Encoder::encode(
-                                    ProtocolMessage::UpdatePeer,
-                                    decoder.peer_id(),
-                                    &peer_ip.to_string(),
-                                )?;
+                                // the peer is announced once, by the node it asked to be added by
+                                let peer = decoder.peer_id();
+                                let mut new_peer = PeerList::new();
+                                new_peer.peers.insert(peer, peer_ip);
+                                let message =
+                                    self.encode(ProtocolMessage::UpdatePeer, &new_peer)?;
 
                                 Ok(Message {
-                                    broadcast: true,
-                                    connect: None,
-                                    raw_message: Some(message),
+                                    raw_message: None,
+                                    direct: self.relay(&message, &peer),
                                 })
                             }
                         }
@@ -113,32 +889,18 @@ impl Node {
 
                 match decoder_type {
                     DecodedType::UpdatePeer(peer_id, peer_ip) => {
-                        let new_key = self.peerlist.peers.insert(peer_id, peer_ip); // TODO: this should return error if peer already exists (ID is taken)
-                        match new_key {
-                            Some(_) => {
-                                // If we already had the key - no need to rebroadcast
-                                Ok(Message {
-                                    broadcast: false,
-                                    connect: None,
-                                    raw_message: None,
-                                })
-                            }
-                            None => {
-                                println!("Updated peerlist: {:?}", self.peerlist.peers);
-
-                                let message = Encoder::encode(
-                                    ProtocolMessage::UpdatePeer,
-                                    decoder.peer_id(),
-                                    &peer_ip.to_string(),
-                                )?;
-
-                                Ok(Message {
-                                    broadcast: true,
-                                    connect: Some(peer_ip),
-                                    raw_message: Some(message),
-                                })
-                            }
+                        // only the node the peer joined through announces it, so this isn't passed on.
+                        // it is dialled if outbound selection picks its address
+                        let mut reported = PeerList::new();
+                        reported.peers.insert(peer_id, peer_ip);
+                        let last_seen = reputation::seconds_since_epoch(SystemTime::now())?;
+                        for address in self.peerlist.merge(reported, decoder.peer_id(), self.id) {
+                            self.learn_address(address, last_seen);
                         }
+                        Ok(Message {
+                            raw_message: None,
+                            direct: vec![],
+                        })
                     }
                     _ => Err(Error::new(
                         ErrorKind::Other,
@@ -150,12 +912,10 @@ impl Node {
                 let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::GetPeers);
                 let peer = decoder.peer_id();
                 if self.peerlist.peers.contains_key(&peer) {
-                    let message =
-                        Encoder::encode(ProtocolMessage::PeerList, self.id, &self.peerlist)?;
+                    let message = self.encode(ProtocolMessage::PeerList, &self.peerlist)?;
                     Ok(Message {
-                        broadcast: false,
-                        connect: None,
                         raw_message: Some(message),
+                        direct: vec![],
                     })
                 } else {
                     // TODO: Handle unrecognised peer again
@@ -171,46 +931,229 @@ impl Node {
                 let peers = decoder.decode_json()?;
                 match peers {
                     DecodedType::PeerList(peerlist) => {
-                        println!("Received peers: {:?}", peerlist);
-                        self.peerlist = peerlist;
+                        if peerlist.peers.len() > addrman::MAX_ADDR_PER_MESSAGE {
+                            return Err(Misbehaviour::Spam.error("Too many peers in one message"));
+                        }
+                        debug!(
+                            peer:% = decoder.peer_id(),
+                            peers = peerlist.peers.len();
+                            "Received peerlist"
+                        );
+                        // the sender's word isn't taken for anyone else, so they are only dialled
+                        let last_seen = reputation::seconds_since_epoch(SystemTime::now())?;
+                        for address in self.peerlist.merge(peerlist, decoder.peer_id(), self.id) {
+                            self.learn_address(address, last_seen);
+                        }
                         Ok(Message {
-                            broadcast: false,
-                            connect: None,
                             raw_message: None,
+                            direct: vec![],
                         })
                     }
                     _ => Err(Error::new(ErrorKind::Other, "Did not decode PeerList")),
                 }
             }
-            Ok(ProtocolMessage::GetBlocks) => {
-                let message =
-                    Encoder::encode(ProtocolMessage::SendBlockchain, self.id, &self.blockchain)?;
-                Ok(Message {
-                    broadcast: false,
-                    connect: None,
-                    raw_message: Some(message),
-                })
+            Ok(ProtocolMessage::GetHeaders) => {
+                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::GetHeaders);
+                let decoded_type = decoder.decode_json()?;
+                match decoded_type {
+                    DecodedType::BlockHashes(locator) => {
+                        // the genesis block is always shared, the handshake made sure of that
+                        let ancestor_index = match self.blockchain.find_common_ancestor(&locator) {
+                            Some(ancestor) => ancestor.index as usize,
+                            None => 0,
+                        };
+                        let headers: Vec<BlockHeader> = (ancestor_index + 1..self.blockchain.len())
+                            .take(sync::MAX_HEADERS)
+                            .filter_map(|index| self.blockchain.get_block_at_index(index))
+                            .map(|block| block.header())
+                            .collect();
+                        let message = self.encode(ProtocolMessage::Headers, &headers)?;
+                        Ok(Message {
+                            raw_message: Some(message),
+                            direct: vec![],
+                        })
+                    }
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Wrong decoding type used in GetHeaders command",
+                    )),
+                }
             }
-            Ok(ProtocolMessage::SendBlockchain) => {
-                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::SendBlockchain);
-                let decoded = decoder.decode_json()?;
-                match decoded {
-                    DecodedType::Blockchain(blockchain) => {
-                        if Blockchain::is_chain_valid(&blockchain) {
-                            println!("Received new chain: {:?}", blockchain);
-                            self.blockchain = blockchain;
-                            Ok(Message {
-                                broadcast: false,
-                                connect: None,
-                                raw_message: None,
-                            })
+            Ok(ProtocolMessage::Headers) => {
+                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::Headers);
+                let decoded_type = decoder.decode_json()?;
+                match decoded_type {
+                    DecodedType::Headers(headers) => {
+                        let peer = decoder.peer_id();
+                        let mut chain_sync = match self.sync.take() {
+                            Some(chain_sync) if chain_sync.peer == peer => chain_sync,
+                            Some(chain_sync) => {
+                                // headers come from a single peer, bodies are fetched from everyone
+                                self.sync = Some(chain_sync);
+                                return Ok(Message {
+                                    raw_message: None,
+                                    direct: vec![],
+                                });
+                            }
+                            // the peer has nothing we don't
+                            None if headers.is_empty() => {
+                                return Ok(Message {
+                                    raw_message: None,
+                                    direct: vec![],
+                                })
+                            }
+                            None => {
+                                // our tip can't move while syncing, so nothing mined on it could connect
+                                if let Some(miner) = &mut self.miner {
+                                    miner.cancel();
+                                }
+                                ChainSync::new(peer)
+                            }
+                        };
+                        chain_sync
+                            .add_headers(&self.blockchain, headers)
+                            .map_err(|e| Misbehaviour::InvalidHeaders.error(e))?;
+
+                        let raw_message = if chain_sync.more_headers() {
+                            let locator = chain_sync.get_block_locator(&self.blockchain);
+                            Some(self.encode(ProtocolMessage::GetHeaders, &locator)?)
                         } else {
-                            Err(Error::new(ErrorKind::InvalidData, "Recieved invalid chain"))
+                            None
+                        };
+                        self.sync = Some(chain_sync);
+                        let mut direct = self.request_blocks()?;
+                        direct.extend(self.finish_sync_if_complete()?);
+                        Ok(Message {
+                            raw_message,
+                            direct,
+                        })
+                    }
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Wrong decoding type used in Headers command",
+                    )),
+                }
+            }
+            Ok(ProtocolMessage::GetData) => {
+                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::GetData);
+                let decoded_type = decoder.decode_json()?;
+                match decoded_type {
+                    DecodedType::Inventory(items) => {
+                        if items.len() > inventory::MAX_INVENTORY {
+                            return Err(
+                                Misbehaviour::Spam.error("Too many inventory items in one message")
+                            );
+                        }
+                        let peer = decoder.peer_id();
+                        let (block_items, transaction_items): (
+                            Vec<InventoryItem>,
+                            Vec<InventoryItem>,
+                        ) = items
+                            .into_iter()
+                            .partition(|item| item.kind == InventoryType::Block);
+
+                        let raw_message = if block_items.is_empty() {
+                            None
+                        } else {
+                            let blocks: Vec<Block> = block_items
+                                .iter()
+                                .take(download::BLOCKS_PER_REQUEST)
+                                .filter_map(|item| self.blockchain.get_block_by_hash(&item.hash))
+                                .cloned()
+                                .collect();
+                            Some(self.encode(ProtocolMessage::Blocks, &blocks)?)
+                        };
+                        let mut direct = vec![];
+                        for item in transaction_items {
+                            if let Some(transaction) = self.mempool.get_by_hash(&item.hash) {
+                                let message =
+                                    self.encode(ProtocolMessage::Transaction, transaction)?;
+                                direct.push((peer, message));
+                            }
+                        }
+                        Ok(Message {
+                            raw_message,
+                            direct,
+                        })
+                    }
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Wrong decoding type used in GetData command",
+                    )),
+                }
+            }
+            Ok(ProtocolMessage::Blocks) => {
+                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::Blocks);
+                let decoded_type = decoder.decode_json()?;
+                match decoded_type {
+                    DecodedType::Blocks(blocks) => {
+                        let peer = decoder.peer_id();
+                        // blocks we fetched after an announcement, rather than for the sync
+                        let (announced, blocks): (Vec<Block>, Vec<Block>) = blocks
+                            .into_iter()
+                            .partition(|block| self.inventory.take_request(&peer, &block.hash));
+                        let mut direct = vec![];
+                        for block in announced {
+                            direct.extend(self.receive_new_block(peer, block)?.direct);
                         }
+                        if blocks.is_empty() {
+                            return Ok(Message {
+                                raw_message: None,
+                                direct,
+                            });
+                        }
+
+                        let mut chain_sync = match self.sync.take() {
+                            Some(chain_sync) => chain_sync,
+                            // a slow peer answering a request another peer answered before the sync ended
+                            None if blocks.iter().all(|block| {
+                                self.blockchain.get_block_by_hash(&block.hash).is_some()
+                            }) =>
+                            {
+                                return Ok(Message {
+                                    raw_message: None,
+                                    direct,
+                                })
+                            }
+                            None => {
+                                return Err(Misbehaviour::UnsolicitedData
+                                    .error("Received blocks that were not requested"))
+                            }
+                        };
+                        if let Err(e) = chain_sync.receive_blocks(blocks) {
+                            self.sync = Some(chain_sync);
+                            return Err(e);
+                        }
+                        self.connections.mark_useful(&peer, (self.clock)());
+                        // a block that doesn't match its header means the header chain was bad,
+                        // so the whole sync is abandoned and the peer that sent the headers is to blame
+                        let connected_from = self.blockchain.len();
+                        let connected = chain_sync.connect_blocks(&mut self.blockchain);
+                        self.blocks_connected_from(connected_from);
+                        if let Err(e) = connected {
+                            self.misbehaving(
+                                &chain_sync.peer,
+                                &Misbehaviour::InvalidHeaders.error(&e),
+                            )?;
+                            warn!(peer:% = chain_sync.peer, error:% = e; "Abandoning sync");
+                            direct.extend(self.mine_pending()?);
+                            return Ok(Message {
+                                raw_message: None,
+                                direct,
+                            });
+                        }
+                        self.sync = Some(chain_sync);
+
+                        direct.extend(self.request_blocks()?);
+                        direct.extend(self.finish_sync_if_complete()?);
+                        Ok(Message {
+                            raw_message: None,
+                            direct,
+                        })
                     }
                     _ => Err(Error::new(
                         ErrorKind::InvalidData,
-                        "Wrong decoding type used in SendBlockchain command",
+                        "Wrong decoding type used in Blocks command",
                     )),
                 }
             }
@@ -220,15 +1163,18 @@ impl Node {
                 let decoded_type = decoder.decode_json()?;
                 match decoded_type {
                     DecodedType::BlockData(data) => {
-                        let new_block = self.blockchain.generate_next_block(&data)?;
-
-                        let message =
-                            Encoder::encode(ProtocolMessage::NewBlock, self.id, &new_block)?;
-
+                        let direct = match self.add_transaction(data) {
+                            Ok(direct) => direct,
+                            // the peer isn't to blame for us having nobody to pass it on to
+                            Err(e) if e.kind() == ErrorKind::NotConnected => {
+                                debug!(peer:% = decoder.peer_id(), error:% = e; "Dropped transaction from peer");
+                                vec![]
+                            }
+                            Err(e) => return Err(e),
+                        };
                         Ok(Message {
-                            broadcast: true,
-                            connect: None,
-                            raw_message: Some(message),
+                            raw_message: None,
+                            direct,
                         })
                     }
                     _ => Err(Error::new(
@@ -237,17 +1183,145 @@ impl Node {
                     )),
                 }
             }
+            Ok(ProtocolMessage::Inventory) => {
+                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::Inventory);
+                let decoded_type = decoder.decode_json()?;
+                match decoded_type {
+                    DecodedType::Inventory(items) => {
+                        if items.len() > inventory::MAX_INVENTORY {
+                            return Err(
+                                Misbehaviour::Spam.error("Too many inventory items in one message")
+                            );
+                        }
+                        let peer = decoder.peer_id();
+                        let missing: Vec<InventoryItem> = items
+                            .into_iter()
+                            .filter(|item| self.is_missing(item))
+                            .collect();
+                        let wanted = self.inventory.request(peer, missing, (self.clock)());
+                        let raw_message = if wanted.is_empty() {
+                            None
+                        } else {
+                            Some(self.encode(ProtocolMessage::GetData, &wanted)?)
+                        };
+                        Ok(Message {
+                            raw_message,
+                            direct: vec![],
+                        })
+                    }
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Wrong decoding type used in Inventory command",
+                    )),
+                }
+            }
+            Ok(ProtocolMessage::Transaction) => {
+                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::Transaction);
+                let decoded_type = decoder.decode_json()?;
+                match decoded_type {
+                    DecodedType::BlockData(transaction) => {
+                        let peer = decoder.peer_id();
+                        let item = InventoryItem::transaction(&transaction);
+                        self.inventory.take_request(&peer, &item.hash);
+                        // relayed transactions wait in the mempool, only those submitted to us are mined straight away
+                        let direct = if !self.inventory.has_seen(&item.hash)
+                            && self.check_transaction(&transaction).is_ok()
+                            && self.mempool.add(transaction)
+                        {
+                            self.connections.mark_useful(&peer, (self.clock)());
+                            self.announce(vec![item], &peer)?
+                        } else {
+                            vec![]
+                        };
+                        Ok(Message {
+                            raw_message: None,
+                            direct,
+                        })
+                    }
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Wrong decoding type used in Transaction command",
+                    )),
+                }
+            }
             Ok(ProtocolMessage::NewBlock) => {
                 let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::NewBlock);
 
                 let decoded_type = decoder.decode_json()?;
-                println!("Received new block: {:?}", decoded_type);
+                match decoded_type {
+                    DecodedType::NewBlock(block) => {
+                        let peer = decoder.peer_id();
+                        self.receive_new_block(peer, block)
+                    }
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Wrong decoding type used in NewBlock command",
+                    )),
+                }
+            }
+            Ok(ProtocolMessage::GetAddr) => {
+                let message = self.encode(ProtocolMessage::Addr, &self.addresses.to_share())?;
                 Ok(Message {
-                    broadcast: false,
-                    connect: None,
-                    raw_message: None,
+                    raw_message: Some(message),
+                    direct: vec![],
                 })
             }
+            Ok(ProtocolMessage::Addr) => {
+                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::Addr);
+                let decoded_type = decoder.decode_json()?;
+                match decoded_type {
+                    DecodedType::Addresses(entries) => {
+                        if entries.len() > addrman::MAX_ADDR_PER_MESSAGE {
+                            return Err(
+                                Misbehaviour::Spam.error("Too many addresses in one message")
+                            );
+                        }
+                        // a peer can't vouch for an address more recently than now
+                        let now = reputation::seconds_since_epoch(SystemTime::now())?;
+                        for AddressEntry { address, last_seen } in entries {
+                            self.learn_address(address, last_seen.min(now));
+                        }
+                        Ok(Message {
+                            raw_message: None,
+                            direct: vec![],
+                        })
+                    }
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Wrong decoding type used in Addr command",
+                    )),
+                }
+            }
+            Ok(ProtocolMessage::Ping) => {
+                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::Ping);
+                match decoder.decode_json()? {
+                    DecodedType::Nonce(nonce) => Ok(Message {
+                        raw_message: Some(self.encode(ProtocolMessage::Pong, &nonce)?),
+                        direct: vec![],
+                    }),
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Wrong decoding type used in Ping command",
+                    )),
+                }
+            }
+            Ok(ProtocolMessage::Pong) => {
+                let mut decoder = Decoder::new(&mut message[..], ProtocolMessage::Pong);
+                match decoder.decode_json()? {
+                    DecodedType::Nonce(nonce) => {
+                        let peer = decoder.peer_id();
+                        self.connections.pong(&peer, nonce, (self.clock)());
+                        Ok(Message {
+                            raw_message: None,
+                            direct: vec![],
+                        })
+                    }
+                    _ => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Wrong decoding type used in Pong command",
+                    )),
+                }
+            }
             Err(e) => Err(e),
         }
     }
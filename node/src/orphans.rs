@@ -0,0 +1,172 @@
+use redistribution::{Block, Blockchain};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The most orphan blocks held at once, the oldest is dropped to make room beyond this
+pub const MAX_ORPHANS: usize = 100;
+
+/// The most orphan blocks held from a single peer, so one peer can't fill the pool
+pub const MAX_ORPHANS_PER_PEER: usize = 20;
+
+/// How far from our tip's height an orphan may be to be held. Blocks further away are only fetched by syncing.
+pub const MAX_ORPHAN_DISTANCE: u32 = 20;
+
+/// How long an orphan is held waiting for its parent
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+struct Orphan {
+    block: Block,
+    peer: Uuid,
+    received_at: Instant,
+}
+
+/// Announced blocks whose parent we don't have yet, keyed by the hash of that parent
+/// so they can be connected as soon as it arrives.
+#[derive(Debug, Default)]
+pub struct OrphanPool {
+    by_parent: HashMap<String, Vec<Orphan>>,
+    per_peer: HashMap<Uuid, usize>,
+    count: usize,
+}
+
+impl OrphanPool {
+    pub fn new() -> OrphanPool {
+        OrphanPool::default()
+    }
+
+    /// Holds a block from a peer until its parent arrives.
+    /// Returns false if we already hold it or the peer has sent too many orphans.
+    pub fn add(&mut self, peer: Uuid, block: Block, now: Instant) -> bool {
+        if self.contains(&block.hash)
+            || self.per_peer.get(&peer).cloned().unwrap_or(0) >= MAX_ORPHANS_PER_PEER
+        {
+            return false;
+        }
+        if self.count >= MAX_ORPHANS {
+            self.remove_oldest();
+        }
+        *self.per_peer.entry(peer).or_insert(0) += 1;
+        self.count += 1;
+        self.by_parent
+            .entry(block.previous_hash.clone())
+            .or_default()
+            .push(Orphan {
+                block,
+                peer,
+                received_at: now,
+            });
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.by_parent
+            .values()
+            .flatten()
+            .any(|orphan| orphan.block.hash == hash)
+    }
+
+    /// Removes and returns the orphans waiting on the given block, with the peer that announced each
+    pub fn take_children(&mut self, parent_hash: &str) -> Vec<(Uuid, Block)> {
+        let children = self.by_parent.remove(parent_hash).unwrap_or_default();
+        for orphan in children.iter() {
+            self.forget(&orphan.peer);
+        }
+        children
+            .into_iter()
+            .map(|orphan| (orphan.peer, orphan.block))
+            .collect()
+    }
+
+    /// Drops orphans that have waited too long, or that reached our chain some other way
+    pub fn expire(&mut self, blockchain: &Blockchain, now: Instant) {
+        let mut removed = vec![];
+        for orphans in self.by_parent.values_mut() {
+            orphans.retain(|orphan| {
+                let keep = now.duration_since(orphan.received_at) <= ORPHAN_EXPIRY
+                    && blockchain.get_block_by_hash(&orphan.block.hash).is_none();
+                if !keep {
+                    removed.push(orphan.peer);
+                }
+                keep
+            });
+        }
+        self.by_parent.retain(|_, orphans| !orphans.is_empty());
+        for peer in removed.iter() {
+            self.forget(peer);
+        }
+    }
+
+    fn remove_oldest(&mut self) {
+        let oldest = self
+            .by_parent
+            .iter()
+            .flat_map(|(parent, orphans)| {
+                orphans
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, orphan)| (orphan.received_at, parent.clone(), i))
+            })
+            .min();
+        if let Some((_, parent, i)) = oldest {
+            let orphans = self.by_parent.get_mut(&parent).unwrap();
+            let orphan = orphans.remove(i);
+            if orphans.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+            self.forget(&orphan.peer);
+        }
+    }
+
+    fn forget(&mut self, peer: &Uuid) {
+        self.count -= 1;
+        if let Some(count) = self.per_peer.get_mut(peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_peer.remove(peer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orphans_released_with_parent_and_limited_per_peer() {
+        let mut blockchain = Blockchain::new(redistribution::Network::Regtest);
+        let mut blocks = vec![];
+        for i in 1..=MAX_ORPHANS_PER_PEER + 2 {
+            let block = blockchain
+                .generate_next_block(&[i.to_string()], None)
+                .unwrap();
+            blockchain.add_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+        let (peer, other_peer) = (Uuid::from_bytes([1; 16]), Uuid::from_bytes([2; 16]));
+        let now = Instant::now();
+
+        let mut pool = OrphanPool::new();
+        for block in blocks.iter().skip(1).take(MAX_ORPHANS_PER_PEER) {
+            assert!(pool.add(peer, block.clone(), now));
+        }
+        let last = blocks.last().unwrap().clone();
+        assert!(!pool.add(peer, last.clone(), now));
+        assert!(pool.add(other_peer, last, now));
+
+        let children = pool.take_children(&blocks[0].hash);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].1.index, 2);
+        assert_eq!(pool.count, MAX_ORPHANS_PER_PEER);
+        assert!(pool.add(peer, blocks[0].clone(), now));
+
+        pool.expire(&blockchain, now);
+        assert_eq!(pool.count, 0);
+    }
+}
@@ -5,20 +5,70 @@ use std::io::{Error, ErrorKind, Result};
 use std::net::SocketAddr;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The most addresses held from other peers' reports, later reports are dropped beyond this
+pub const MAX_REPORTED: usize = 1000;
+
+/// A peer's address as another peer told us it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reported {
+    pub address: SocketAddr,
+    pub by: Uuid,
+}
+
+/// The peers we know by ID. Only peers whose ID a handshake has proved are listed in `peers`,
+/// which is what we share. What others tell us about the rest is held apart with who told us,
+/// and is only a lead to dial until we connect to the peer ourselves.
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct PeerList {
     pub peers: HashMap<uuid::Uuid, SocketAddr>,
+    #[serde(skip)]
+    reported: HashMap<Uuid, Reported>,
 }
 
 impl PeerList {
     pub fn new() -> PeerList {
-        PeerList {
-            peers: HashMap::new(),
+        PeerList::default()
+    }
+
+    /// Lists a peer whose ID a handshake has proved, returning the address it was listed at before
+    pub fn verify(&mut self, peer: Uuid, address: SocketAddr) -> Option<SocketAddr> {
+        self.reported.remove(&peer);
+        self.peers.insert(peer, address)
+    }
+
+    /// Merges the peers another peer has sent us, returning the addresses we hadn't heard of.
+    /// Nothing we already know is replaced, so a peer can't move others' entries or ours.
+    pub fn merge(&mut self, received: PeerList, by: Uuid, own: Uuid) -> Vec<SocketAddr> {
+        let mut new_addresses = vec![];
+        for (peer, address) in received.peers {
+            if peer == own
+                || self.peers.contains_key(&peer)
+                || self.reported.contains_key(&peer)
+                || self.reported.len() >= MAX_REPORTED
+            {
+                continue;
+            }
+            self.reported.insert(peer, Reported { address, by });
+            new_addresses.push(address);
         }
+        new_addresses
+    }
+
+    #[cfg(test)]
+    pub fn reported(&self, peer: &Uuid) -> Option<&Reported> {
+        self.reported.get(peer)
+    }
+
+    /// Forgets a peer that has disconnected, along with everything it reported,
+    /// returning whether it was listed
+    pub fn remove(&mut self, peer: &Uuid) -> bool {
+        self.reported.retain(|_, reported| reported.by != *peer);
+        self.peers.remove(peer).is_some()
     }
 
-    pub fn get_new_peer_id(peer_addr: &[u8]) -> uuid::Uuid {
-        Uuid::new_v5(&Uuid::NAMESPACE_OID, peer_addr)
+    /// Derives a node's ID from its public key, see `identity::node_id`
+    pub fn get_new_peer_id(public_key: &[u8]) -> uuid::Uuid {
+        Uuid::new_v5(&Uuid::NAMESPACE_OID, public_key)
     }
 }
 
@@ -35,7 +85,10 @@ impl Decodable for PeerList {
         match decoded_json_result {
             Ok(decoded_json) => {
                 let peers: HashMap<uuid::Uuid, SocketAddr> = serde_json::from_str(&decoded_json)?;
-                Ok(PeerList { peers })
+                Ok(PeerList {
+                    peers,
+                    ..PeerList::default()
+                })
             }
             Err(_) => Err(Error::new(
                 ErrorKind::InvalidData,
@@ -44,3 +97,37 @@ impl Decodable for PeerList {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_keeps_what_we_know() {
+        let id = |name: &str| Uuid::new_v5(&Uuid::nil(), name.as_bytes());
+        let address = |address: &str| address.parse::<SocketAddr>().unwrap();
+        let (own, sender, known, other) = (id("own"), id("sender"), id("known"), id("other"));
+        let mut peerlist = PeerList::new();
+        peerlist.verify(known, address("10.0.0.1:7878"));
+
+        let mut received = PeerList::new();
+        received.peers.insert(own, address("10.0.0.9:7878"));
+        received.peers.insert(known, address("10.0.0.9:7878"));
+        received.peers.insert(other, address("10.0.0.2:7878"));
+        let learnt = peerlist.merge(received, sender, own);
+        assert_eq!(learnt, vec![address("10.0.0.2:7878")]);
+        assert_eq!(peerlist.peers.len(), 1);
+        assert_eq!(peerlist.peers[&known], address("10.0.0.1:7878"));
+        assert_eq!(peerlist.reported(&other).unwrap().by, sender);
+
+        // reports are dropped with the peer that sent them, or once we connect to the peer reported
+        peerlist.remove(&sender);
+        assert_eq!(peerlist.reported(&other), None);
+        let mut received = PeerList::new();
+        received.peers.insert(other, address("10.0.0.2:7878"));
+        peerlist.merge(received, sender, own);
+        peerlist.verify(other, address("10.0.0.3:7878"));
+        assert_eq!(peerlist.reported(&other), None);
+        assert_eq!(peerlist.peers[&other], address("10.0.0.3:7878"));
+    }
+}
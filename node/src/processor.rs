@@ -0,0 +1,315 @@
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Stream};
+use log::{error, info, warn};
+use redistribution::Block;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+
+use crate::connections::MAX_INBOUND;
+use crate::node::Node;
+use crate::tls::SessionKey;
+use crate::transport::{Connection, Dialer, Session};
+
+/// How often the node checks for block requests that peers have failed to answer, and for silent peers
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a new connection has to complete the handshake before it is closed
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most connections opened to us at once, including those still handshaking,
+/// so connections that never complete a handshake can't exhaust our resources
+pub const MAX_INBOUND_SESSIONS: usize = 2 * MAX_INBOUND;
+
+/// How long peers have to acknowledge our closing their connections when the node shuts down
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type ConnectionId = usize;
+
+/// Work for the chain-processing task, sent by connections and by anything else that needs the node
+pub enum Event {
+    Opened {
+        id: ConnectionId,
+        connection: Arc<dyn Connection>,
+        session_key: SessionKey,
+        address: Option<SocketAddr>,
+        remote: Option<IpAddr>,
+    },
+    Received(ConnectionId, Vec<u8>),
+    Closed(ConnectionId),
+    Tick,
+    /// A block the miner found, and how long it took
+    Mined {
+        block: Block,
+        took: Duration,
+    },
+    /// Runs a closure against the node, so it can be queried from outside the task
+    Call(Box<dyn FnOnce(&mut Node) + Send>),
+    /// Closes every connection then flushes the node's files, answering once done
+    Shutdown(oneshot::Sender<Result<()>>),
+}
+
+/// Sends events to the chain-processing task. Every connection holds one,
+/// so each can read and write at its own pace while the node handles messages one at a time.
+#[derive(Clone)]
+pub struct NodeHandle {
+    events: mpsc::UnboundedSender<Event>,
+    next_connection: Arc<AtomicUsize>,
+}
+
+impl NodeHandle {
+    /// Starts a session on a newly opened connection, returning the ID to send its messages under.
+    /// `address` is the one we dialled, if we opened the connection, and `remote` the one the
+    /// connection came from, if the transport knows it.
+    pub fn open(
+        &self,
+        connection: Arc<dyn Connection>,
+        session_key: SessionKey,
+        address: Option<SocketAddr>,
+        remote: Option<IpAddr>,
+    ) -> Result<ConnectionId> {
+        let id = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        self.send(Event::Opened {
+            id,
+            connection,
+            session_key,
+            address,
+            remote,
+        })?;
+        Ok(id)
+    }
+
+    pub fn receive(&self, id: ConnectionId, data: Vec<u8>) -> Result<()> {
+        self.send(Event::Received(id, data))
+    }
+
+    pub fn close(&self, id: ConnectionId) -> Result<()> {
+        self.send(Event::Closed(id))
+    }
+
+    pub fn mined(&self, block: Block, took: Duration) -> Result<()> {
+        self.send(Event::Mined { block, took })
+    }
+
+    /// Runs a closure against the node, resolving to what it returns
+    pub fn call<R, F>(&self, f: F) -> impl Future<Item = R, Error = Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Node) -> R + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let sent = self.send(Event::Call(Box::new(move |node| {
+            let _ = sender.send(f(node));
+        })));
+        future::result(sent).and_then(|()| receiver.map_err(|_| stopped()))
+    }
+
+    /// Stops the node, resolving once its connections have closed and its files are flushed
+    pub fn shutdown(&self) -> impl Future<Item = (), Error = Error> {
+        let (sender, receiver) = oneshot::channel();
+        future::result(self.send(Event::Shutdown(sender)))
+            .and_then(|()| receiver.map_err(|_| stopped()))
+            .and_then(future::result)
+    }
+
+    fn send(&self, event: Event) -> Result<()> {
+        self.events.unbounded_send(event).map_err(|_| stopped())
+    }
+}
+
+/// The events waiting for the chain-processing task
+pub type Events = mpsc::UnboundedReceiver<Event>;
+
+/// Owns the node and every connection's session, handling events in the order they arrive
+pub struct Processor {
+    node: Node,
+    sessions: HashMap<ConnectionId, Session>,
+    /// Opens outbound connections, a node without one only accepts connections
+    dialer: Option<Box<dyn Dialer>>,
+    /// Set once the node is shutting down
+    shutdown: Option<Shutdown>,
+}
+
+struct Shutdown {
+    /// When to stop waiting for connections to close
+    deadline: Instant,
+    /// Who to tell once the node has finished shutting down, taken when it has
+    done: Option<oneshot::Sender<Result<()>>>,
+}
+
+/// Creates the handle for sending events to a node, before the node's task exists,
+/// so the transports it dials out with can hold the handle too
+pub fn channel() -> (NodeHandle, Events) {
+    let (events, received) = mpsc::unbounded();
+    let handle = NodeHandle {
+        events,
+        next_connection: Arc::new(AtomicUsize::new(0)),
+    };
+    (handle, received)
+}
+
+/// Creates the chain-processing task, handling events sent through the handle from `channel`
+pub fn start(mut processor: Processor, events: Events) -> impl Future<Item = (), Error = ()> {
+    let ticks = Interval::new(Instant::now() + TICK_INTERVAL, TICK_INTERVAL)
+        .map(|_| Event::Tick)
+        .map_err(|e| error!(error:% = e; "Tick timer failed"));
+    events.select(ticks).for_each(move |event| {
+        processor.process(event);
+        Ok(())
+    })
+}
+
+impl Processor {
+    pub fn new(node: Node) -> Processor {
+        Processor {
+            node,
+            sessions: HashMap::new(),
+            dialer: None,
+            shutdown: None,
+        }
+    }
+
+    pub fn with_dialer(self, dialer: Box<dyn Dialer>) -> Processor {
+        Processor {
+            dialer: Some(dialer),
+            ..self
+        }
+    }
+
+    pub fn process(&mut self, event: Event) {
+        if self.shutdown.is_some() {
+            return self.process_while_shutting_down(event);
+        }
+        match event {
+            Event::Opened {
+                id,
+                connection,
+                session_key,
+                address,
+                remote,
+            } => {
+                let connection = self.node.metrics.meter(connection);
+                let mut session =
+                    Session::new(connection, session_key, address, remote, self.node.now());
+                let inbound = self
+                    .sessions
+                    .values()
+                    .filter(|session| session.is_inbound())
+                    .count();
+                if session.is_inbound() && inbound >= MAX_INBOUND_SESSIONS {
+                    let e = Error::new(ErrorKind::ConnectionRefused, "Too many connections");
+                    return session.abort(&e);
+                }
+                match session.open(&mut self.node) {
+                    Ok(()) => {
+                        self.sessions.insert(id, session);
+                    }
+                    Err(e) => session.abort(&e),
+                }
+            }
+            Event::Received(id, data) => {
+                if let Some(session) = self.sessions.get_mut(&id) {
+                    if let Err(e) = session.receive(&mut self.node, data) {
+                        session.abort(&e);
+                    }
+                }
+            }
+            Event::Closed(id) => {
+                if let Some(mut session) = self.sessions.remove(&id) {
+                    session.close(&mut self.node);
+                }
+            }
+            Event::Tick => {
+                let now = self.node.now();
+                for session in self.sessions.values() {
+                    if session.handshake_expired(now, HANDSHAKE_TIMEOUT) {
+                        let e = Error::new(ErrorKind::TimedOut, "Handshake timed out");
+                        session.abort(&e);
+                    }
+                }
+                match self.node.tick() {
+                    Ok(requests) => {
+                        for (peer, data) in requests {
+                            if let Err(e) = self.node.connections.send(&peer, data) {
+                                warn!(peer:% = peer, error:% = e; "Failed to send block request");
+                            }
+                        }
+                    }
+                    Err(e) => warn!(error:% = e; "Failed to reassign block requests"),
+                }
+                if let Some(dialer) = &self.dialer {
+                    for address in self.node.select_outbound() {
+                        if let Err(e) = dialer.dial(address) {
+                            warn!(address:% = address, error:% = e; "Failed to connect");
+                        }
+                    }
+                }
+            }
+            Event::Mined { block, took } => match self.node.block_mined(block, took) {
+                Ok(announcements) => {
+                    for (peer, data) in announcements {
+                        if let Err(e) = self.node.connections.send(&peer, data) {
+                            warn!(peer:% = peer, error:% = e; "Failed to announce mined block");
+                        }
+                    }
+                }
+                Err(e) => warn!(error:% = e; "Failed to connect mined block"),
+            },
+            Event::Call(f) => f(&mut self.node),
+            Event::Shutdown(done) => {
+                info!(connections = self.sessions.len(); "Shutting down");
+                self.node.stop_mining();
+                for session in self.sessions.values() {
+                    session.shut_down();
+                }
+                self.shutdown = Some(Shutdown {
+                    deadline: self.node.now() + SHUTDOWN_TIMEOUT,
+                    done: Some(done),
+                });
+                self.finish_shutdown_if_closed();
+            }
+        }
+    }
+
+    /// Only lets connections finish closing. New connections are refused,
+    /// and nothing else reaches the node, whose miner has already stopped.
+    fn process_while_shutting_down(&mut self, event: Event) {
+        match event {
+            Event::Opened { connection, .. } => {
+                let _ = connection.close(String::from("Node is shutting down"));
+            }
+            Event::Closed(id) => {
+                if let Some(mut session) = self.sessions.remove(&id) {
+                    session.close(&mut self.node);
+                }
+            }
+            // calls are dropped, so their callers hear the node has stopped
+            Event::Received(..) | Event::Tick | Event::Mined { .. } | Event::Call(_) => {}
+            Event::Shutdown(done) => {
+                let e = Error::other("The node is already shutting down");
+                let _ = done.send(Err(e));
+            }
+        }
+        self.finish_shutdown_if_closed();
+    }
+
+    /// Flushes the node's files once every connection has closed, or peers have had long enough to close them
+    fn finish_shutdown_if_closed(&mut self) {
+        let now = self.node.now();
+        if let Some(shutdown) = &mut self.shutdown {
+            if self.sessions.is_empty() || now >= shutdown.deadline {
+                if let Some(done) = shutdown.done.take() {
+                    let _ = done.send(self.node.flush());
+                }
+            }
+        }
+    }
+}
+
+fn stopped() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "The node has stopped")
+}
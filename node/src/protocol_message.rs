@@ -5,11 +5,21 @@ pub enum ProtocolMessage {
     AddMe,
     GetPeers,
     NewBlock,
-    GetBlocks,
     PeerList,
     AddTransaction,
-    SendBlockchain,
     UpdatePeer,
+    Version,
+    VerAck,
+    GetData,
+    Blocks,
+    GetHeaders,
+    Headers,
+    Inventory,
+    Transaction,
+    GetAddr,
+    Addr,
+    Ping,
+    Pong,
 }
 
 impl ProtocolMessage {
@@ -19,11 +29,21 @@ impl ProtocolMessage {
             ProtocolMessage::AddMe => "0x01".as_bytes(),
             ProtocolMessage::GetPeers => "0x02".as_bytes(),
             ProtocolMessage::NewBlock => "0x03".as_bytes(),
-            ProtocolMessage::GetBlocks => "0x04".as_bytes(),
             ProtocolMessage::PeerList => "0x06".as_bytes(),
             ProtocolMessage::AddTransaction => "0x07".as_bytes(),
-            ProtocolMessage::SendBlockchain => "0x08".as_bytes(),
             ProtocolMessage::UpdatePeer => "Ox09".as_bytes(),
+            ProtocolMessage::Version => "0x0a".as_bytes(),
+            ProtocolMessage::VerAck => "0x0b".as_bytes(),
+            ProtocolMessage::GetData => "0x0d".as_bytes(),
+            ProtocolMessage::Blocks => "0x0e".as_bytes(),
+            ProtocolMessage::GetHeaders => "0x0f".as_bytes(),
+            ProtocolMessage::Headers => "0x10".as_bytes(),
+            ProtocolMessage::Inventory => "0x0c".as_bytes(),
+            ProtocolMessage::Transaction => "0x12".as_bytes(),
+            ProtocolMessage::GetAddr => "0x13".as_bytes(),
+            ProtocolMessage::Addr => "0x14".as_bytes(),
+            ProtocolMessage::Ping => "0x15".as_bytes(),
+            ProtocolMessage::Pong => "0x16".as_bytes(),
         }
     }
 
@@ -35,10 +55,8 @@ impl ProtocolMessage {
                 "Cannot construct Protocol opcode from incorrectly sized bytes",
             ));
         }
-        opcode.swap_with_slice(raw_bytes);
-        if opcode == ProtocolMessage::GetBlocks.as_bytes() {
-            return Ok(ProtocolMessage::GetBlocks);
-        } else if opcode == ProtocolMessage::AddMe.as_bytes() {
+        opcode.copy_from_slice(raw_bytes);
+        if opcode == ProtocolMessage::AddMe.as_bytes() {
             return Ok(ProtocolMessage::AddMe);
         } else if opcode == ProtocolMessage::GetPeers.as_bytes() {
             return Ok(ProtocolMessage::GetPeers);
@@ -48,10 +66,32 @@ impl ProtocolMessage {
             return Ok(ProtocolMessage::AddTransaction);
         } else if opcode == ProtocolMessage::PeerList.as_bytes() {
             return Ok(ProtocolMessage::PeerList);
-        } else if opcode == ProtocolMessage::SendBlockchain.as_bytes() {
-            return Ok(ProtocolMessage::SendBlockchain);
         } else if opcode == ProtocolMessage::UpdatePeer.as_bytes() {
             return Ok(ProtocolMessage::UpdatePeer);
+        } else if opcode == ProtocolMessage::Version.as_bytes() {
+            return Ok(ProtocolMessage::Version);
+        } else if opcode == ProtocolMessage::VerAck.as_bytes() {
+            return Ok(ProtocolMessage::VerAck);
+        } else if opcode == ProtocolMessage::GetData.as_bytes() {
+            return Ok(ProtocolMessage::GetData);
+        } else if opcode == ProtocolMessage::Blocks.as_bytes() {
+            return Ok(ProtocolMessage::Blocks);
+        } else if opcode == ProtocolMessage::GetHeaders.as_bytes() {
+            return Ok(ProtocolMessage::GetHeaders);
+        } else if opcode == ProtocolMessage::Headers.as_bytes() {
+            return Ok(ProtocolMessage::Headers);
+        } else if opcode == ProtocolMessage::Inventory.as_bytes() {
+            return Ok(ProtocolMessage::Inventory);
+        } else if opcode == ProtocolMessage::Transaction.as_bytes() {
+            return Ok(ProtocolMessage::Transaction);
+        } else if opcode == ProtocolMessage::GetAddr.as_bytes() {
+            return Ok(ProtocolMessage::GetAddr);
+        } else if opcode == ProtocolMessage::Addr.as_bytes() {
+            return Ok(ProtocolMessage::Addr);
+        } else if opcode == ProtocolMessage::Ping.as_bytes() {
+            return Ok(ProtocolMessage::Ping);
+        } else if opcode == ProtocolMessage::Pong.as_bytes() {
+            return Ok(ProtocolMessage::Pong);
         }
         Err(Error::new(ErrorKind::Other, "Unknown Protocol"))
     }
@@ -0,0 +1,293 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Peers whose penalty points reach this are disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// How long a ban lasts unless configured otherwise
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often a penalty point is forgiven, so long-lived peers aren't banned for the odd mistake
+pub const SCORE_DECAY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Things a peer can do wrong, each worth a number of penalty points
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Misbehaviour {
+    MalformedMessage,
+    InvalidBlock,
+    InvalidHeaders,
+    UnsolicitedData,
+    Spam,
+}
+
+impl Misbehaviour {
+    pub fn penalty(self) -> u32 {
+        match self {
+            Misbehaviour::MalformedMessage => 20,
+            Misbehaviour::InvalidBlock => 100,
+            Misbehaviour::InvalidHeaders => 50,
+            Misbehaviour::UnsolicitedData => 10,
+            Misbehaviour::Spam => 5,
+        }
+    }
+
+    /// An error which penalises the peer that caused it when it is returned from `Node::handle_message`
+    pub fn error<E: ToString>(self, reason: E) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            MisbehaviourError {
+                misbehaviour: self,
+                reason: reason.to_string(),
+            },
+        )
+    }
+
+    /// The misbehaviour behind an error from handling a peer's message.
+    /// Data that couldn't be decoded counts as a malformed message, any other failure is our own.
+    pub fn of(error: &Error) -> Option<Misbehaviour> {
+        match error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<MisbehaviourError>())
+        {
+            Some(misbehaviour_error) => Some(misbehaviour_error.misbehaviour),
+            None => match error.kind() {
+                ErrorKind::InvalidData | ErrorKind::UnexpectedEof => {
+                    Some(Misbehaviour::MalformedMessage)
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MisbehaviourError {
+    misbehaviour: Misbehaviour,
+    reason: String,
+}
+
+impl fmt::Display for MisbehaviourError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl error::Error for MisbehaviourError {}
+
+/// What a ban applies to. A banned peer's address is banned along with its ID,
+/// as a new ID is only a new key pair away.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Banned {
+    Peer(Uuid),
+    Address(IpAddr),
+}
+
+#[derive(Debug)]
+struct Score {
+    points: u32,
+    /// When the points last changed, in seconds since the unix epoch
+    updated: u64,
+}
+
+impl Score {
+    /// The points left once those forgiven since they last changed are taken off
+    fn at(&self, now: u64) -> u32 {
+        let forgiven = now.saturating_sub(self.updated) / SCORE_DECAY_INTERVAL.as_secs();
+        // never more than the points, so it still fits
+        u64::from(self.points).saturating_sub(forgiven) as u32
+    }
+}
+
+/// Penalty points for each peer and the peers and addresses banned for collecting too many.
+/// Bans are written to the ban list file as they are made, so they outlast a restart.
+#[derive(Debug)]
+pub struct Reputation {
+    scores: HashMap<Uuid, Score>,
+    /// When each ban ends, in seconds since the unix epoch
+    bans: HashMap<Banned, u64>,
+    ban_duration: Duration,
+    path: Option<PathBuf>,
+}
+
+impl Default for Reputation {
+    fn default() -> Reputation {
+        Reputation::new(DEFAULT_BAN_DURATION)
+    }
+}
+
+impl Reputation {
+    /// A reputation which isn't persisted
+    pub fn new(ban_duration: Duration) -> Reputation {
+        Reputation {
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+            ban_duration,
+            path: None,
+        }
+    }
+
+    /// Reads the ban list from a file, which doesn't need to exist yet
+    pub fn load(path: PathBuf, ban_duration: Duration) -> Result<Reputation> {
+        let bans = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Reputation {
+            scores: HashMap::new(),
+            bans,
+            ban_duration,
+            path: Some(path),
+        })
+    }
+
+    /// Adds a misbehaviour's penalty to a peer's score, banning it and the address it connects from
+    /// if that reaches the threshold. Returns true if the peer is now banned.
+    pub fn penalise(
+        &mut self,
+        peer: &Uuid,
+        address: Option<IpAddr>,
+        misbehaviour: Misbehaviour,
+        now: SystemTime,
+    ) -> Result<bool> {
+        let seconds = seconds_since_epoch(now)?;
+        self.scores.retain(|_, score| score.at(seconds) > 0);
+        let points = self.score(peer, now) + misbehaviour.penalty();
+        if points < BAN_THRESHOLD {
+            let score = Score {
+                points,
+                updated: seconds,
+            };
+            self.scores.insert(*peer, score);
+            return Ok(false);
+        }
+        self.scores.remove(peer);
+        let until = seconds + self.ban_duration.as_secs();
+        self.bans.insert(Banned::Peer(*peer), until);
+        // every node run on one machine shares its loopback address, so that isn't banned
+        if let Some(address) = address.filter(|address| !address.is_loopback()) {
+            self.bans.insert(Banned::Address(address), until);
+        }
+        self.save(now)?;
+        Ok(true)
+    }
+
+    pub fn score(&self, peer: &Uuid, now: SystemTime) -> u32 {
+        match (self.scores.get(peer), seconds_since_epoch(now)) {
+            (Some(score), Ok(now)) => score.at(now),
+            _ => 0,
+        }
+    }
+
+    pub fn is_banned(&self, peer: &Uuid, now: SystemTime) -> bool {
+        self.is_in_force(&Banned::Peer(*peer), now)
+    }
+
+    pub fn is_address_banned(&self, address: &IpAddr, now: SystemTime) -> bool {
+        self.is_in_force(&Banned::Address(*address), now)
+    }
+
+    fn is_in_force(&self, banned: &Banned, now: SystemTime) -> bool {
+        match (self.bans.get(banned), seconds_since_epoch(now)) {
+            (Some(until), Ok(now)) => *until > now,
+            _ => false,
+        }
+    }
+
+    /// Writes out the bans that haven't yet expired
+    pub fn save(&mut self, now: SystemTime) -> Result<()> {
+        let now = seconds_since_epoch(now)?;
+        self.bans.retain(|_, until| *until > now);
+        match &self.path {
+            Some(path) => fs::write(path, serde_json::to_string(&self.bans)?),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn seconds_since_epoch(time: SystemTime) -> Result<u64> {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => Ok(duration.as_secs()),
+        Err(_) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "System time is before the unix epoch",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_banned_at_threshold_and_ban_persisted() {
+        let path = std::env::temp_dir().join(format!("banlist-test-{}.json", std::process::id()));
+        let (peer, other_peer) = (Uuid::from_bytes([1; 16]), Uuid::from_bytes([2; 16]));
+        let now = SystemTime::now();
+        let ban_duration = Duration::from_secs(60);
+
+        let (address, other_address) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let mut reputation = Reputation::load(path.clone(), ban_duration).unwrap();
+        assert!(!reputation
+            .penalise(&peer, Some(address), Misbehaviour::InvalidHeaders, now)
+            .unwrap());
+        assert!(!reputation
+            .penalise(&other_peer, Some(other_address), Misbehaviour::Spam, now)
+            .unwrap());
+        assert!(reputation
+            .penalise(&peer, Some(address), Misbehaviour::InvalidHeaders, now)
+            .unwrap());
+
+        // a new ID doesn't get round a ban from the same address
+        let reputation = Reputation::load(path.clone(), ban_duration).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(reputation.is_banned(&peer, now));
+        assert!(reputation.is_address_banned(&address, now));
+        assert!(!reputation.is_banned(&other_peer, now));
+        assert!(!reputation.is_address_banned(&other_address, now));
+        assert!(!reputation.is_banned(&peer, now + ban_duration));
+        assert!(!reputation.is_address_banned(&address, now + ban_duration));
+    }
+
+    #[test]
+    fn test_scores_decay_over_time() {
+        let peer = Uuid::from_bytes([1; 16]);
+        let now = SystemTime::now();
+        let mut reputation = Reputation::default();
+        for _ in 0..3 {
+            reputation
+                .penalise(&peer, None, Misbehaviour::MalformedMessage, now)
+                .unwrap();
+        }
+        assert_eq!(reputation.score(&peer, now), 60);
+        let later = now + SCORE_DECAY_INTERVAL * 50;
+        assert_eq!(reputation.score(&peer, later), 10);
+
+        // penalties long apart never reach a ban
+        let mut at = now;
+        for _ in 0..20 {
+            at += SCORE_DECAY_INTERVAL * 20;
+            assert!(!reputation
+                .penalise(&peer, None, Misbehaviour::MalformedMessage, at)
+                .unwrap());
+        }
+    }
+
+    #[test]
+    fn test_misbehaviour_recovered_from_error() {
+        let error = Misbehaviour::InvalidBlock.error("Invalid block");
+        assert_eq!(error.to_string(), "Invalid block");
+        assert_eq!(Misbehaviour::of(&error), Some(Misbehaviour::InvalidBlock));
+        let error = Error::new(ErrorKind::NotFound, "Unable to locate last block");
+        assert_eq!(Misbehaviour::of(&error), None);
+    }
+}
@@ -0,0 +1,255 @@
+use futures::{future, Future};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use redistribution::{Block, BlockData};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use url::form_urlencoded;
+
+use crate::node::Node;
+use crate::processor::NodeHandle;
+
+/// How many blocks a page lists when no `limit` is given
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+/// The most blocks a page lists, whatever `limit` asks for
+pub const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, PartialEq)]
+pub struct RestError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl RestError {
+    fn new(status: StatusCode, message: &str) -> RestError {
+        RestError {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// A transaction and the block it was confirmed in, if it has been
+#[derive(Serialize)]
+struct Transaction<'a> {
+    id: &'a str,
+    data: &'a BlockData,
+    block: Option<&'a str>,
+    height: Option<u32>,
+}
+
+/// Serves the read-only explorer endpoints on `address`, each answered as JSON:
+///     /tip                  the latest block
+///     /blocks?from=&limit=  a page of blocks, newest first, from the height given or the tip
+///     /blocks/{height}      the block at a height
+///     /blocks/hash/{hash}   the block with a hash
+///     /tx/{id}              a transaction by its hash, confirmed or waiting in the mempool
+///     /address/{address}    an address's balance and unspent outputs
+pub fn serve(
+    address: &SocketAddr,
+    handle: NodeHandle,
+) -> Result<impl Future<Item = (), Error = ()>> {
+    let server = Server::try_bind(address).map_err(Error::other)?;
+    info!(address:% = address; "REST listening");
+    Ok(server
+        .serve(move || {
+            let handle = handle.clone();
+            service_fn(move |request| respond(request, handle.clone()))
+        })
+        .map_err(|e| error!(error:% = e; "REST server failed")))
+}
+
+fn respond(
+    request: Request<Body>,
+    handle: NodeHandle,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    if request.method() != Method::GET {
+        let error = RestError::new(StatusCode::METHOD_NOT_ALLOWED, "Only GET is supported");
+        return Box::new(future::ok(reply(Err(error))));
+    }
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().map(|query| query.to_string());
+    Box::new(
+        handle
+            .call(move |node| get(node, &path, query.as_deref()))
+            .then(|result| {
+                let result = result.unwrap_or_else(|e| {
+                    Err(RestError::new(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        &e.to_string(),
+                    ))
+                });
+                Ok(reply(result))
+            }),
+    )
+}
+
+fn reply(result: std::result::Result<Value, RestError>) -> Response<Body> {
+    let (status, body) = match result {
+        Ok(body) => (StatusCode::OK, body),
+        Err(error) => (error.status, json!({ "error": error.message })),
+    };
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+/// Answers a GET for `path` from the node
+pub fn get(node: &Node, path: &str, query: Option<&str>) -> std::result::Result<Value, RestError> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["tip"] => match node.blockchain.get_latest_block() {
+            Ok(block) => to_json(block),
+            Err(e) => Err(RestError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &e.to_string(),
+            )),
+        },
+        ["blocks"] => blocks(node, query.unwrap_or("")),
+        ["blocks", "hash", hash] => match node.blockchain.get_block_by_hash(hash) {
+            Some(block) => to_json(block),
+            None => Err(not_found("Block not found")),
+        },
+        ["blocks", height] => match height.parse() {
+            Ok(height) => match node.blockchain.get_block_at_index(height) {
+                Some(block) => to_json(block),
+                None => Err(not_found("Block not found")),
+            },
+            Err(_e) => Err(RestError::new(
+                StatusCode::BAD_REQUEST,
+                "Block height is not a number",
+            )),
+        },
+        ["tx", id] => transaction(node, id),
+        ["address", address] => Ok(json!({
+            "address": address,
+            "balance": node.ledger.balance(address),
+            "unspent": node.ledger.unspent(address),
+        })),
+        _ => Err(not_found("No such endpoint")),
+    }
+}
+
+/// A page of blocks counting down from `from`, with the height the next page starts at
+fn blocks(node: &Node, query: &str) -> std::result::Result<Value, RestError> {
+    let tip = node.blockchain.len() - 1;
+    let (mut from, mut limit) = (tip, DEFAULT_PAGE_SIZE);
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        let parsed = value.parse().map_err(|_e| {
+            RestError::new(StatusCode::BAD_REQUEST, &format!("{} is not a number", key))
+        });
+        match key.as_ref() {
+            "from" => from = parsed?,
+            "limit" => limit = parsed?,
+            _ => {}
+        }
+    }
+    let from = from.min(tip);
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let blocks: Vec<&Block> = (0..=from)
+        .rev()
+        .take(limit)
+        .filter_map(|height| node.blockchain.get_block_at_index(height))
+        .collect();
+    let next = (from + 1).checked_sub(limit + 1);
+    Ok(json!({ "blocks": blocks, "next": next }))
+}
+
+/// Looks for a transaction on our chain, newest first, then among those waiting for a block
+fn transaction(node: &Node, id: &str) -> std::result::Result<Value, RestError> {
+    let confirmed = node.confirmed_entry(id);
+    let transaction = match &confirmed {
+        Some((block, entry)) => Transaction {
+            id,
+            data: entry,
+            block: Some(&block.hash),
+            height: Some(block.index),
+        },
+        None => match node.mempool.get_by_hash(id) {
+            Some(data) => Transaction {
+                id,
+                data,
+                block: None,
+                height: None,
+            },
+            None => return Err(not_found("Transaction not found")),
+        },
+    };
+    to_json(&transaction)
+}
+
+fn to_json<T: Serialize>(value: &T) -> std::result::Result<Value, RestError> {
+    serde_json::to_value(value)
+        .map_err(|e| RestError::new(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))
+}
+
+fn not_found(message: &str) -> RestError {
+    RestError::new(StatusCode::NOT_FOUND, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addrman::AddressManager;
+    use crate::identity::Identity;
+    use crate::inventory;
+    use crate::reputation::Reputation;
+    use redistribution::{KeyPair, Network, BLOCK_REWARD};
+
+    #[test]
+    fn test_blocks_paged_from_tip() {
+        let identity = Identity::generate().unwrap();
+        let miner = KeyPair::generate().unwrap().address().unwrap();
+        let mut node = Node::new(
+            Network::Regtest,
+            "127.0.0.1:7878".to_string(),
+            Reputation::default(),
+            AddressManager::new(),
+            identity,
+        )
+        .unwrap()
+        .with_reward_address(Some(miner.clone()));
+        for i in 0..4 {
+            node.add_transaction(format!("transaction {}", i)).unwrap();
+        }
+
+        let heights = |page: &Value| -> Vec<u64> {
+            let blocks = page["blocks"].as_array().unwrap();
+            blocks
+                .iter()
+                .map(|b| b["index"].as_u64().unwrap())
+                .collect()
+        };
+        let page = get(&node, "/blocks", Some("limit=3")).unwrap();
+        assert_eq!(heights(&page), vec![4, 3, 2]);
+        assert_eq!(page["next"], json!(1));
+        let page = get(&node, "/blocks", Some("from=1&limit=3")).unwrap();
+        assert_eq!(heights(&page), vec![1, 0]);
+        assert_eq!(page["next"], Value::Null);
+
+        let tip = get(&node, "/tip", None).unwrap();
+        let hash = tip["hash"].as_str().unwrap();
+        assert_eq!(
+            get(&node, &format!("/blocks/hash/{}", hash), None),
+            Ok(tip.clone())
+        );
+        assert_eq!(get(&node, "/blocks/4", None), Ok(tip));
+        assert_eq!(
+            get(&node, "/blocks/5", None).unwrap_err().status,
+            StatusCode::NOT_FOUND
+        );
+
+        let id = inventory::transaction_hash(&"transaction 1".to_string());
+        let transaction = get(&node, &format!("/tx/{}", id), None).unwrap();
+        assert_eq!(transaction["height"], json!(2));
+        let address = get(&node, &format!("/address/{}", miner), None).unwrap();
+        assert_eq!(address["balance"], json!(4 * BLOCK_REWARD));
+    }
+}
@@ -0,0 +1,334 @@
+use futures::{future, Future, Stream};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{debug, error, info, warn};
+use redistribution::Block;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+
+use crate::inventory;
+use crate::node::Node;
+use crate::processor::NodeHandle;
+
+/// The largest request body accepted, calls are small so this only bounds what a caller can make us hold
+pub const MAX_REQUEST_LENGTH: usize = 1024 * 1024;
+
+/// The request body wasn't JSON
+pub const PARSE_ERROR: i64 = -32700;
+/// The request was JSON but not a call
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// No block has the hash or height asked for
+pub const BLOCK_NOT_FOUND: i64 = -5;
+/// The transaction can't be mined on our tip
+pub const TRANSACTION_REJECTED: i64 = -26;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    fn internal<E: fmt::Display>(error: E) -> RpcError {
+        RpcError::new(INTERNAL_ERROR, &error.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+impl RpcResponse {
+    fn new(id: Value, result: std::result::Result<Value, RpcError>) -> RpcResponse {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        RpcResponse {
+            jsonrpc: "2.0",
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+/// Serves JSON-RPC 2.0 calls POSTed to `address`.
+/// Each call is run by the chain-processing task between its other events, so sees the node as it is.
+pub fn serve(
+    address: &SocketAddr,
+    handle: NodeHandle,
+) -> Result<impl Future<Item = (), Error = ()>> {
+    let server = Server::try_bind(address).map_err(Error::other)?;
+    info!(address:% = address; "RPC listening");
+    Ok(server
+        .serve(move || {
+            let handle = handle.clone();
+            service_fn(move |request| respond(request, handle.clone()))
+        })
+        .map_err(|e| error!(error:% = e; "RPC server failed")))
+}
+
+fn respond(
+    request: Request<Body>,
+    handle: NodeHandle,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    if request.method() != Method::POST {
+        return Box::new(future::ok(status(StatusCode::METHOD_NOT_ALLOWED)));
+    }
+    let body = request
+        .into_body()
+        .map_err(Error::other)
+        .fold(Vec::new(), |mut body, chunk| {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_REQUEST_LENGTH {
+                return Err(Error::new(ErrorKind::InvalidData, "Request is too long"));
+            }
+            Ok(body)
+        });
+    Box::new(body.then(move |body| match body {
+        Ok(body) => future::Either::A(answer(&body, handle).map(reply)),
+        Err(e) => {
+            debug!(error:% = e; "Failed to read RPC request");
+            future::Either::B(future::ok(status(StatusCode::BAD_REQUEST)))
+        }
+    }))
+}
+
+/// Parses a call and runs it against the node
+fn answer(body: &[u8], handle: NodeHandle) -> impl Future<Item = RpcResponse, Error = Error> {
+    let request = serde_json::from_slice(body)
+        .map_err(|e| RpcError::new(PARSE_ERROR, &e.to_string()))
+        .and_then(|request: Value| {
+            serde_json::from_value(request)
+                .map_err(|e| RpcError::new(INVALID_REQUEST, &e.to_string()))
+        });
+    match request {
+        Ok(RpcRequest { method, params, id }) => {
+            future::Either::A(handle.call(move |node| call(node, &method, &params)).then(
+                move |result| {
+                    let result = result.unwrap_or_else(|e| Err(RpcError::internal(e)));
+                    Ok(RpcResponse::new(id, result))
+                },
+            ))
+        }
+        Err(error) => future::Either::B(future::ok(RpcResponse::new(Value::Null, Err(error)))),
+    }
+}
+
+fn reply(response: RpcResponse) -> Response<Body> {
+    match serde_json::to_vec(&response) {
+        Ok(json) => {
+            let mut reply = Response::new(Body::from(json));
+            reply
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            reply
+        }
+        Err(e) => {
+            warn!(error:% = e; "Failed to encode RPC response");
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
+
+/// Runs a single call against the node, returning its result
+pub fn call(node: &mut Node, method: &str, params: &Value) -> std::result::Result<Value, RpcError> {
+    match method {
+        "getblockcount" => Ok(json!(latest_block(node)?.index)),
+        "getbestblockhash" => Ok(json!(latest_block(node)?.hash)),
+        "getblock" => {
+            let block = match param(params, 0)? {
+                Value::Number(height) => height
+                    .as_u64()
+                    .and_then(|height| usize::try_from(height).ok())
+                    .and_then(|height| node.blockchain.get_block_at_index(height)),
+                Value::String(hash) => node.blockchain.get_block_by_hash(hash),
+                _ => {
+                    return Err(RpcError::new(
+                        INVALID_PARAMS,
+                        "Expected a block hash or height",
+                    ))
+                }
+            };
+            match block {
+                Some(block) => serde_json::to_value(block).map_err(RpcError::internal),
+                None => Err(RpcError::new(BLOCK_NOT_FOUND, "Block not found")),
+            }
+        }
+        "sendrawtransaction" => {
+            let transaction = match param(params, 0)? {
+                Value::String(transaction) => transaction.clone(),
+                _ => {
+                    return Err(RpcError::new(
+                        INVALID_PARAMS,
+                        "Expected the transaction as a string",
+                    ))
+                }
+            };
+            let hash = inventory::transaction_hash(&transaction);
+            let announcements = node.add_transaction(transaction).map_err(|e| {
+                if e.kind() == ErrorKind::InvalidInput {
+                    RpcError::new(TRANSACTION_REJECTED, &e.to_string())
+                } else {
+                    RpcError::internal(e)
+                }
+            })?;
+            for (peer, data) in announcements {
+                if let Err(e) = node.connections.send(&peer, data) {
+                    warn!(peer:% = peer, error:% = e; "Failed to announce transaction");
+                }
+            }
+            Ok(json!(hash))
+        }
+        "getbalance" => {
+            let address = address_param(params)?;
+            Ok(json!(node.ledger.balance(address)))
+        }
+        "getaddressinfo" => {
+            let address = address_param(params)?;
+            Ok(json!({
+                "address": address,
+                "balance": node.ledger.balance(address),
+                "received": node.ledger.has_received(address),
+            }))
+        }
+        "listunspent" => {
+            let address = address_param(params)?;
+            serde_json::to_value(node.ledger.unspent(address)).map_err(RpcError::internal)
+        }
+        "getpeerinfo" => serde_json::to_value(node.peer_info()).map_err(RpcError::internal),
+        "getmempoolinfo" => Ok(json!({
+            "size": node.mempool.len(),
+            "bytes": node.mempool.bytes(),
+        })),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            &format!("Method not found: {}", method),
+        )),
+    }
+}
+
+fn latest_block(node: &Node) -> std::result::Result<&Block, RpcError> {
+    node.blockchain
+        .get_latest_block()
+        .map_err(RpcError::internal)
+}
+
+/// A positional parameter
+fn param(params: &Value, index: usize) -> std::result::Result<&Value, RpcError> {
+    params
+        .get(index)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, &format!("Missing parameter {}", index)))
+}
+
+fn address_param(params: &Value) -> std::result::Result<&str, RpcError> {
+    param(params, 0)?
+        .as_str()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Expected an address"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addrman::AddressManager;
+    use crate::identity::Identity;
+    use crate::reputation::Reputation;
+    use redistribution::{KeyPair, Network, Transaction, BLOCK_REWARD};
+
+    #[test]
+    fn test_calls_answered_from_node() {
+        let identity = Identity::generate().unwrap();
+        let address = KeyPair::generate().unwrap().address().unwrap();
+        let mut node = Node::new(
+            Network::Regtest,
+            "127.0.0.1:7878".to_string(),
+            Reputation::default(),
+            AddressManager::new(),
+            identity,
+        )
+        .unwrap()
+        .with_reward_address(Some(address.clone()));
+        assert_eq!(call(&mut node, "getblockcount", &json!([])), Ok(json!(0)));
+
+        let hash = call(&mut node, "sendrawtransaction", &json!(["hello"])).unwrap();
+        assert_eq!(
+            hash,
+            json!(inventory::transaction_hash(&"hello".to_string()))
+        );
+        assert_eq!(call(&mut node, "getblockcount", &json!([])), Ok(json!(1)));
+
+        let best = call(&mut node, "getbestblockhash", &json!([])).unwrap();
+        let by_hash = call(&mut node, "getblock", &json!([best])).unwrap();
+        let by_height = call(&mut node, "getblock", &json!([1])).unwrap();
+        assert_eq!(by_hash, by_height);
+        let block: Block = serde_json::from_value(by_hash).unwrap();
+        assert_eq!(block.entries()[1], "hello");
+
+        assert_eq!(
+            call(&mut node, "getblock", &json!([2])).unwrap_err().code,
+            BLOCK_NOT_FOUND
+        );
+        assert_eq!(
+            call(&mut node, "getblock", &json!([])).unwrap_err().code,
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            call(&mut node, "getnothing", &json!([])).unwrap_err().code,
+            METHOD_NOT_FOUND
+        );
+
+        // the miner is paid for the block, and nobody else can claim a reward
+        let claim = Transaction::reward(address.clone(), 2);
+        assert_eq!(
+            call(
+                &mut node,
+                "sendrawtransaction",
+                &json!([claim.to_data().unwrap()])
+            )
+            .unwrap_err()
+            .code,
+            TRANSACTION_REJECTED
+        );
+        assert_eq!(
+            call(&mut node, "getbalance", &json!([address])),
+            Ok(json!(BLOCK_REWARD))
+        );
+    }
+}
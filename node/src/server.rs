@@ -1,87 +1,86 @@
-use std::cell::Cell;
-use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use log::{debug, info, warn};
+use openssl::ssl::SslStream;
+use std::sync::Arc;
 
 extern crate ws;
-use ws::{CloseCode, Error, ErrorKind, Handler, Handshake, Message, Result, Sender};
+use ws::util::TcpStream;
+use ws::{CloseCode, Error, Handler, Handshake, Message, Result, Sender};
 
-use crate::node;
+use crate::processor::{ConnectionId, NodeHandle};
+use crate::tls::{SessionKey, Tls};
+use crate::websocket::WebSocket;
 
 pub struct Server {
     out: Sender,
-    count: Rc<Cell<u32>>,
-    node: Arc<Mutex<node::Node>>,
+    handle: NodeHandle,
+    /// Encrypts the connection when peers are required to use TLS
+    tls: Option<Arc<Tls>>,
+    /// Filled in with the key from the peer's certificate, which the handshake checks
+    session_key: SessionKey,
+    /// Set once the session is open on the node
+    connection: Option<ConnectionId>,
 }
 
 impl Server {
-    pub fn new(out: Sender, count: Rc<Cell<u32>>, node: Arc<Mutex<node::Node>>) -> Server {
-        Server { out, count, node }
+    pub fn new(out: Sender, handle: NodeHandle, tls: Option<Arc<Tls>>) -> Server {
+        Server {
+            out,
+            handle,
+            tls,
+            session_key: SessionKey::default(),
+            connection: None,
+        }
     }
 }
 
 impl Handler for Server {
     fn on_open(&mut self, shake: Handshake) -> Result<()> {
-        // We have a new connection, so we increment the connection counter
-        println!("SERVER: Opening new connection to: {:?}", shake.peer_addr);
-        Ok(self.count.set(self.count.get() + 1))
+        debug!(address:? = shake.peer_addr; "Opened inbound connection");
+        let connection = WebSocket::new(self.out.clone());
+        let remote = shake.peer_addr.map(|address| address.ip());
+        let id = self
+            .handle
+            .open(Arc::new(connection), self.session_key.clone(), None, remote)?;
+        self.connection = Some(id);
+        Ok(())
     }
 
     fn on_message(&mut self, msg: Message) -> Result<()> {
-        let mut node = self.node.lock().unwrap();
-        let result = node.handle_message(&mut msg.into_data());
-        match result {
-            Ok(message) => {
-                match message.connect {
-                    Some(connection) => {
-                        let mut peer_url = String::from("ws://");
-                        peer_url.push_str(&connection.to_string());
-                        let url = url::Url::parse(&peer_url).unwrap();
-                        self.out.connect(url)?;
-                    }
-                    _ => {}
-                }
-                if message.broadcast {
-                    match message.raw_message {
-                        Some(data) => {
-                            return self.out.broadcast(data);
-                        }
-                        None => {
-                            return Err(Error::new(
-                                ErrorKind::Internal,
-                                "Asking to broadcast message with no data",
-                            ));
-                        }
-                    }
-                } else {
-                    match message.raw_message {
-                        Some(data) => {
-                            return self.out.send(data);
-                        }
-                        _ => {}
-                    }
-                }
-
-                Ok(())
-            }
-            Err(e) => Err(Error::from(e)),
+        match self.connection {
+            Some(id) => Ok(self.handle.receive(id, msg.into_data())?),
+            None => Ok(()),
         }
     }
 
     fn on_close(&mut self, code: CloseCode, reason: &str) {
         match code {
-            CloseCode::Normal => println!("The client is done with the connection."),
-            CloseCode::Away => println!("The client is leaving the site."),
+            CloseCode::Normal | CloseCode::Away => {
+                debug!(code:? = code; "Inbound connection closed")
+            }
             CloseCode::Abnormal => {
-                println!("Closing handshake failed! Unable to obtain closing status from client.")
+                debug!("Inbound connection closed without a closing handshake")
             }
-            _ => println!("The client encountered an error: {}", reason),
+            _ => info!(code:? = code, reason = reason; "Inbound connection closed with an error"),
         }
 
-        // The connection is going down, so we need to decrement the count
-        self.count.set(self.count.get() - 1)
+        if let Some(id) = self.connection.take() {
+            if let Err(e) = self.handle.close(id) {
+                warn!(error:% = e; "Failed to close connection");
+            }
+        }
     }
 
     fn on_error(&mut self, err: Error) {
-        println!("The server encountered an error: {:?}", err);
+        warn!(error:% = err; "WebSocket server error");
+    }
+
+    fn upgrade_ssl_server(&mut self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        match &self.tls {
+            Some(tls) => Ok(tls.acceptor(self.session_key.clone())?.accept(stream)?),
+            None => Err(Error::new(
+                ws::ErrorKind::Protocol,
+                "Encryption is not enabled for peer connections",
+            )),
+        }
     }
 }
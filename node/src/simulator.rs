@@ -0,0 +1,568 @@
+use futures::sync::oneshot;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::addrman::AddressManager;
+use crate::identity::Identity;
+use crate::node::Node;
+use crate::processor::{ConnectionId, Event, Processor, SHUTDOWN_TIMEOUT, TICK_INTERVAL};
+use crate::reputation::Reputation;
+use crate::tls::SessionKey;
+use crate::transport::{Connection, Dialer};
+
+type NodeIndex = usize;
+
+thread_local! {
+    /// The simulated time, read by every node of the simulation running on this thread
+    static NOW: Cell<Instant> = Cell::new(Instant::now());
+}
+
+fn now() -> Instant {
+    NOW.with(Cell::get)
+}
+
+/// Something waiting to happen on the simulated network
+enum Delivery {
+    Open {
+        node: NodeIndex,
+        id: ConnectionId,
+        connection: Arc<dyn Connection>,
+        address: Option<SocketAddr>,
+        remote: SocketAddr,
+    },
+    Message {
+        node: NodeIndex,
+        id: ConnectionId,
+        data: Vec<u8>,
+    },
+    Close {
+        node: NodeIndex,
+        id: ConnectionId,
+    },
+    Connect {
+        from: NodeIndex,
+        address: SocketAddr,
+    },
+}
+
+/// Everything in flight between the simulated nodes, ordered by when it arrives
+struct Network {
+    start: Instant,
+    /// Milliseconds since the simulation started
+    now: u64,
+    /// Keyed by arrival time, then by when it was sent so ties keep their order
+    in_flight: BTreeMap<(u64, u64), Delivery>,
+    sent: u64,
+    next_connection: ConnectionId,
+    latency: Duration,
+    drop_rate: f64,
+    /// Which side of a partition each node is on, messages between sides are lost
+    sides: Vec<usize>,
+    rng: StdRng,
+}
+
+impl Network {
+    fn schedule(&mut self, delay: Duration, delivery: Delivery) {
+        let at = self.now + delay.as_millis() as u64;
+        self.in_flight.insert((at, self.sent), delivery);
+        self.sent += 1;
+    }
+
+    fn advance(&mut self, to: u64) {
+        self.now = to;
+        NOW.with(|now| now.set(self.start + Duration::from_millis(to)));
+    }
+
+    fn next_arrival(&self) -> Option<u64> {
+        self.in_flight.keys().next().map(|(at, _)| *at)
+    }
+}
+
+/// One end of an in-memory link between two simulated nodes
+struct MemoryConnection {
+    network: Arc<Mutex<Network>>,
+    node: NodeIndex,
+    id: ConnectionId,
+    peer: NodeIndex,
+    peer_id: ConnectionId,
+}
+
+impl Connection for MemoryConnection {
+    fn send(&self, data: Vec<u8>) -> Result<()> {
+        let mut network = self.network.lock().unwrap();
+        let drop_rate = network.drop_rate;
+        if network.sides[self.node] != network.sides[self.peer] || network.rng.gen_bool(drop_rate) {
+            return Ok(());
+        }
+        let latency = network.latency;
+        network.schedule(
+            latency,
+            Delivery::Message {
+                node: self.peer,
+                id: self.peer_id,
+                data,
+            },
+        );
+        Ok(())
+    }
+
+    fn close(&self, _reason: String) -> Result<()> {
+        let mut network = self.network.lock().unwrap();
+        let latency = network.latency;
+        network.schedule(
+            Duration::from_secs(0),
+            Delivery::Close {
+                node: self.node,
+                id: self.id,
+            },
+        );
+        network.schedule(
+            latency,
+            Delivery::Close {
+                node: self.peer,
+                id: self.peer_id,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Dials other simulated nodes, dials to addresses without a node never complete
+struct MemoryDialer {
+    network: Arc<Mutex<Network>>,
+    from: NodeIndex,
+}
+
+impl Dialer for MemoryDialer {
+    fn dial(&self, address: SocketAddr) -> Result<()> {
+        let mut network = self.network.lock().unwrap();
+        network.schedule(
+            Duration::from_secs(0),
+            Delivery::Connect {
+                from: self.from,
+                address,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Runs several nodes in one process, connected by an in-memory network with
+/// controllable latency, partitions and message drops.
+///
+/// Time is simulated, so deliveries, ticks and timeouts happen in an order fixed by the latency and the seed.
+/// The nodes read the time from the thread running the simulation, so only one can run per thread.
+/// Node IDs and the nodes' own hash maps still vary between runs,
+/// so tests should check where the network ends up rather than the exact messages exchanged.
+pub struct Simulator {
+    nodes: Vec<Processor>,
+    addresses: Vec<SocketAddr>,
+    network: Arc<Mutex<Network>>,
+    next_tick: u64,
+}
+
+impl Simulator {
+    pub fn new(count: usize, seed: u64) -> Simulator {
+        Simulator::with_setup(count, seed, |_, node| node)
+    }
+
+    /// Like `new`, with each node passed through `setup` along with its index before it starts
+    pub fn with_setup(
+        count: usize,
+        seed: u64,
+        setup: impl Fn(NodeIndex, Node) -> Node,
+    ) -> Simulator {
+        let network = Network {
+            start: now(),
+            now: 0,
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            next_connection: 0,
+            latency: Duration::from_millis(50),
+            drop_rate: 0.0,
+            sides: vec![0; count],
+            rng: StdRng::seed_from_u64(seed),
+        };
+        let network = Arc::new(Mutex::new(network));
+        let mut nodes = vec![];
+        let mut addresses = vec![];
+        for i in 0..count {
+            let address: SocketAddr = format!("10.0.0.{}:7878", i + 1).parse().unwrap();
+            let identity = Identity::generate().unwrap();
+            let node = Node::new(
+                redistribution::Network::Regtest,
+                address.to_string(),
+                Reputation::default(),
+                AddressManager::new(),
+                identity,
+            )
+            .unwrap()
+            .with_clock(now);
+            let node = setup(i, node);
+            let dialer = MemoryDialer {
+                network: Arc::clone(&network),
+                from: i,
+            };
+            nodes.push(Processor::new(node).with_dialer(Box::new(dialer)));
+            addresses.push(address);
+        }
+        Simulator {
+            nodes,
+            addresses,
+            network,
+            next_tick: TICK_INTERVAL.as_millis() as u64,
+        }
+    }
+
+    /// The address a node is reached at, for other nodes to dial
+    pub fn address(&self, node: NodeIndex) -> SocketAddr {
+        self.addresses[node]
+    }
+
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.network.lock().unwrap().latency = latency;
+    }
+
+    /// The chance of each message being lost, from 0 to 1
+    pub fn set_drop_rate(&mut self, drop_rate: f64) {
+        self.network.lock().unwrap().drop_rate = drop_rate;
+    }
+
+    /// Cuts the given nodes off from the rest, without closing their connections
+    pub fn partition(&mut self, nodes: &[NodeIndex]) {
+        let mut network = self.network.lock().unwrap();
+        for (node, side) in network.sides.iter_mut().enumerate() {
+            *side = if nodes.contains(&node) { 1 } else { 0 };
+        }
+    }
+
+    pub fn heal(&mut self) {
+        let mut network = self.network.lock().unwrap();
+        network.sides.iter_mut().for_each(|side| *side = 0);
+    }
+
+    /// Opens a link between two nodes, the first asking to join the network through the second
+    pub fn connect(&mut self, from: NodeIndex, to: NodeIndex) {
+        let mut network = self.network.lock().unwrap();
+        let (from_id, to_id) = (network.next_connection, network.next_connection + 1);
+        network.next_connection += 2;
+        for (node, id, peer, peer_id, address) in [
+            (to, to_id, from, from_id, None),
+            (from, from_id, to, to_id, Some(self.addresses[to])),
+        ] {
+            let remote = self.addresses[peer];
+            let connection = MemoryConnection {
+                network: Arc::clone(&self.network),
+                node,
+                id,
+                peer,
+                peer_id,
+            };
+            network.schedule(
+                Duration::from_secs(0),
+                Delivery::Open {
+                    node,
+                    id,
+                    connection: Arc::new(connection),
+                    address,
+                    remote,
+                },
+            );
+        }
+    }
+
+    /// Delivers everything due in the next stretch of simulated time, ticking each node as it passes
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.network.lock().unwrap().now + duration.as_millis() as u64;
+        loop {
+            let next_arrival = self.network.lock().unwrap().next_arrival();
+            match next_arrival {
+                Some(at) if at <= self.next_tick && at <= end => self.deliver_next(),
+                _ if self.next_tick <= end => {
+                    self.network.lock().unwrap().advance(self.next_tick);
+                    self.next_tick += TICK_INTERVAL.as_millis() as u64;
+                    for node in self.nodes.iter_mut() {
+                        node.process(Event::Tick);
+                    }
+                }
+                _ => {
+                    self.network.lock().unwrap().advance(end);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Runs a closure against a node, returning what it returns
+    pub fn call<R, F>(&mut self, node: NodeIndex, f: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Node) -> R + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.nodes[node].process(Event::Call(Box::new(move |node| {
+            sender.send(f(node)).unwrap();
+        })));
+        receiver.recv().unwrap()
+    }
+
+    /// Has a node mine a block, announcing it to its peers
+    pub fn mine(&mut self, node: NodeIndex, data: &str) {
+        let data = data.to_string();
+        self.call(node, move |node| {
+            for (peer, message) in node.add_transaction(data).unwrap() {
+                node.connections.send(&peer, message).unwrap();
+            }
+        });
+    }
+
+    /// Shuts a node down, giving its peers as long as they are allowed to close their connections.
+    /// Returns what the node reports, if it finished shutting down.
+    pub fn shutdown(&mut self, node: NodeIndex) -> Option<Result<()>> {
+        let (sender, mut receiver) = oneshot::channel();
+        self.nodes[node].process(Event::Shutdown(sender));
+        self.run_for(SHUTDOWN_TIMEOUT);
+        receiver.try_recv().ok().flatten()
+    }
+
+    /// The hash and height of a node's best block
+    pub fn tip(&mut self, node: NodeIndex) -> (String, usize) {
+        self.call(node, |node| {
+            let block = node.blockchain.get_latest_block().unwrap();
+            (block.hash.clone(), block.index as usize)
+        })
+    }
+
+    fn deliver_next(&mut self) {
+        let delivery = {
+            let mut network = self.network.lock().unwrap();
+            let key = *network.in_flight.keys().next().unwrap();
+            network.advance(key.0);
+            network.in_flight.remove(&key).unwrap()
+        };
+        match delivery {
+            Delivery::Open {
+                node,
+                id,
+                connection,
+                address,
+                remote,
+            } => self.nodes[node].process(Event::Opened {
+                id,
+                connection,
+                session_key: SessionKey::default(),
+                address,
+                remote: Some(remote.ip()),
+            }),
+            Delivery::Message { node, id, data } => {
+                self.nodes[node].process(Event::Received(id, data))
+            }
+            Delivery::Close { node, id } => self.nodes[node].process(Event::Closed(id)),
+            Delivery::Connect { from, address } => {
+                if let Some(to) = self.addresses.iter().position(|a| *a == address) {
+                    self.connect(from, to);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory;
+    use std::io::ErrorKind;
+
+    const SETTLE: Duration = Duration::from_secs(180);
+
+    fn assert_converged(simulator: &mut Simulator, count: usize) -> (String, usize) {
+        let tip = simulator.tip(0);
+        for node in 1..count {
+            assert_eq!(
+                simulator.tip(node),
+                tip,
+                "node {} has a different tip",
+                node
+            );
+        }
+        tip
+    }
+
+    #[test]
+    fn test_joining_nodes_sync_the_chain() {
+        let mut simulator = Simulator::new(4, 1);
+        for i in 0..5 {
+            simulator.mine(0, &format!("block {}", i));
+        }
+        for node in 1..4 {
+            simulator.connect(node, 0);
+        }
+        simulator.run_for(SETTLE);
+
+        let (_, height) = assert_converged(&mut simulator, 4);
+        assert!(height >= 5);
+        // peers joining through the first node are introduced to each other
+        let peers = simulator.call(1, |node| node.connections.peers().len());
+        assert_eq!(peers, 3);
+    }
+
+    #[test]
+    fn test_transactions_submitted_while_syncing_are_mined_after() {
+        let mut simulator = Simulator::new(2, 7);
+        for i in 0..5 {
+            simulator.mine(0, &format!("block {}", i));
+        }
+        simulator.connect(1, 0);
+        let syncing = (0..1000).any(|_| {
+            simulator.run_for(Duration::from_millis(5));
+            simulator.call(1, |node| node.is_syncing())
+        });
+        assert!(syncing);
+        simulator.mine(1, "while syncing");
+        simulator.run_for(SETTLE);
+
+        assert_converged(&mut simulator, 2);
+        let mined = simulator.call(0, |node| {
+            node.blockchain
+                .iter()
+                .any(|block| block.entries().contains(&"while syncing".to_string()))
+        });
+        assert!(mined);
+        assert_eq!(simulator.call(1, |node| node.mempool.len()), 0);
+    }
+
+    #[test]
+    fn test_nodes_that_dont_mine_pass_transactions_to_miners() {
+        let mut simulator = Simulator::with_setup(3, 8, |i, node| node.with_mining(i == 2));
+        let alone = simulator.call(0, |node| node.add_transaction(String::from("too soon")));
+        assert_eq!(alone.unwrap_err().kind(), ErrorKind::NotConnected);
+
+        simulator.connect(0, 1);
+        simulator.connect(1, 2);
+        simulator.run_for(SETTLE);
+        simulator.mine(0, "passed on");
+        simulator.run_for(SETTLE);
+        let (hash, _) = assert_converged(&mut simulator, 3);
+        let data = simulator.call(0, move |node| {
+            node.blockchain.get_block_by_hash(&hash).unwrap().entries()
+        });
+        assert_eq!(data, vec!["passed on".to_string()]);
+    }
+
+    #[test]
+    fn test_nodes_discover_each_other_from_seeds() {
+        let mut simulator = Simulator::new(4, 4);
+        for i in 0..3 {
+            simulator.mine(0, &format!("block {}", i));
+        }
+        // every node is seeded with one address that is down, and the first node
+        let (down, seed) = ("10.0.0.99:7878".parse().unwrap(), simulator.address(0));
+        for node in 1..4 {
+            simulator.call(node, move |node| {
+                node.addresses.add(down, 0);
+                node.addresses.add(seed, 0);
+            });
+        }
+        simulator.run_for(SETTLE);
+
+        let (_, height) = assert_converged(&mut simulator, 4);
+        assert!(height >= 3);
+        for node in 0..4 {
+            let peers = simulator.call(node, |node| node.connections.peers().len());
+            assert_eq!(peers, 3, "node {} is missing peers", node);
+        }
+    }
+
+    #[test]
+    fn test_blocks_gossiped_to_every_node() {
+        let mut simulator = Simulator::new(5, 2);
+        simulator.set_latency(Duration::from_millis(200));
+        for node in 1..5 {
+            simulator.connect(node, 0);
+        }
+        simulator.run_for(SETTLE);
+        assert_converged(&mut simulator, 5);
+
+        // a block whose announcements are all lost is fetched as the parent of the next one.
+        // The loss is kept short, but pings lost with it can still disconnect peers until they redial.
+        simulator.set_drop_rate(1.0);
+        simulator.mine(3, "lost");
+        simulator.run_for(Duration::from_secs(30));
+        assert_ne!(simulator.tip(0), simulator.tip(3));
+
+        simulator.set_drop_rate(0.0);
+        simulator.mine(3, "gossip");
+        let (hash, _) = simulator.tip(3);
+        simulator.run_for(SETTLE);
+        assert_converged(&mut simulator, 5);
+        let data = simulator.call(0, move |node| {
+            let block = node.blockchain.get_block_by_hash(&hash).unwrap();
+            let parent = node
+                .blockchain
+                .get_block_by_hash(&block.previous_hash)
+                .unwrap();
+            (block.entries(), parent.entries())
+        });
+        assert_eq!(data, (vec!["gossip".to_string()], vec!["lost".to_string()]));
+    }
+
+    #[test]
+    fn test_partition_heals_to_the_longer_fork() {
+        let mut simulator = Simulator::new(3, 3);
+        for node in 1..3 {
+            simulator.connect(node, 0);
+        }
+        simulator.run_for(SETTLE);
+        assert_converged(&mut simulator, 3);
+
+        simulator.partition(&[2]);
+        simulator.mine(0, "majority 1");
+        simulator.mine(0, "majority 2");
+        simulator.mine(2, "minority 1");
+        simulator.run_for(SETTLE);
+        assert_ne!(simulator.tip(0), simulator.tip(2));
+
+        // nothing is resent when the partition heals, so the next block brings the minority across
+        simulator.heal();
+        simulator.mine(1, "majority 3");
+        simulator.run_for(SETTLE);
+        let (hash, _) = assert_converged(&mut simulator, 3);
+        let data = simulator.call(2, move |node| {
+            node.blockchain.get_block_by_hash(&hash).unwrap().entries()
+        });
+        assert_eq!(data, vec!["majority 3".to_string()]);
+        // what the minority confirmed was forgotten with its fork
+        let confirmed = simulator.call(2, |node| {
+            let hash = |entry: &str| inventory::transaction_hash(&entry.to_string());
+            (
+                node.confirmed_entry(&hash("minority 1")).is_some(),
+                node.confirmed_entry(&hash("majority 1")).is_some(),
+            )
+        });
+        assert_eq!(confirmed, (false, true));
+    }
+
+    #[test]
+    fn test_shutdown_closes_connections_and_refuses_new_ones() {
+        let mut simulator = Simulator::new(3, 6);
+        simulator.connect(1, 0);
+        simulator.connect(2, 0);
+        simulator.run_for(Duration::from_secs(10));
+        let id = simulator.call(0, |node| node.id);
+        let connected = move |node: &mut Node| node.connections.contains(&id);
+        assert!(simulator.call(1, connected));
+
+        assert!(simulator.shutdown(0).unwrap().is_ok());
+        assert!(!simulator.call(1, connected));
+        assert!(!simulator.call(2, connected));
+        simulator.connect(1, 0);
+        simulator.run_for(Duration::from_secs(10));
+        assert!(!simulator.call(1, connected));
+    }
+}
@@ -0,0 +1,186 @@
+use redistribution::{Block, BlockHeader, Blockchain};
+use std::io::{Error, ErrorKind, Result};
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::download::BlockDownloader;
+
+/// The most headers sent in a single `Headers` message
+pub const MAX_HEADERS: usize = 2000;
+
+/// Progress of a headers-first sync.
+///
+/// We send one peer our block locator and it replies with the headers following our common ancestor.
+/// Once the shape of its chain is known the block bodies are downloaded from every connected peer
+/// in parallel, and each block is validated against its header as it is connected.
+/// If the peer's chain forks below our tip the blocks are connected to a copy of our chain instead,
+/// which replaces ours at the end of the sync only if it carries more work.
+#[derive(Debug)]
+pub struct ChainSync {
+    pub peer: Uuid,
+    last_header: Option<BlockHeader>,
+    more_headers: bool,
+    downloader: BlockDownloader,
+    fork: Option<Blockchain>,
+}
+
+impl ChainSync {
+    pub fn new(peer: Uuid) -> ChainSync {
+        ChainSync {
+            peer,
+            last_header: None,
+            more_headers: false,
+            downloader: BlockDownloader::new(),
+            fork: None,
+        }
+    }
+
+    /// Checks the headers sent by the sync peer form a chain from one of our blocks
+    /// (or from the last headers it sent) and queues their blocks for download
+    pub fn add_headers(
+        &mut self,
+        blockchain: &Blockchain,
+        headers: Vec<BlockHeader>,
+    ) -> Result<()> {
+        if headers.len() > MAX_HEADERS {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Received more headers than requested",
+            ));
+        }
+        let full = headers.len() == MAX_HEADERS;
+        // overlapping requests or blocks announced while our request was in flight
+        // can mean we already have some of these
+        let headers: Vec<BlockHeader> = match &self.last_header {
+            Some(last_header) => headers
+                .into_iter()
+                .skip_while(|header| header.index <= last_header.index)
+                .collect(),
+            None => headers
+                .into_iter()
+                .skip_while(|header| blockchain.get_block_by_hash(&header.hash).is_some())
+                .collect(),
+        };
+        let mut previous = match (&self.last_header, headers.first()) {
+            (Some(last_header), _) => last_header.clone(),
+            (None, Some(first)) => match blockchain.get_block_by_hash(&first.previous_hash) {
+                Some(ancestor) => {
+                    if ancestor.index != blockchain.get_latest_block()?.index {
+                        self.fork = Some(blockchain.fork_at(ancestor.index as usize));
+                    }
+                    ancestor.header()
+                }
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Received headers do not connect to our chain",
+                    ))
+                }
+            },
+            (None, None) => {
+                self.more_headers = false;
+                return Ok(());
+            }
+        };
+        for header in headers.iter() {
+            if !header.follows(&previous) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Received headers do not form a chain",
+                ));
+            }
+            previous = header.clone();
+        }
+
+        self.more_headers = full;
+        self.last_header = headers.last().cloned().or(self.last_header.take());
+        self.downloader
+            .enqueue(headers.into_iter().map(|header| header.hash).collect());
+        Ok(())
+    }
+
+    /// Whether the sync peer filled its last `Headers` message, meaning it has more to send
+    pub fn more_headers(&self) -> bool {
+        self.more_headers
+    }
+
+    /// Called when a block we don't have is announced mid-sync, so the sync peer is asked again
+    /// for the headers following those it has sent. Returns false if a request is already outstanding.
+    pub fn request_more_headers(&mut self) -> bool {
+        if self.more_headers {
+            return false;
+        }
+        self.more_headers = true;
+        true
+    }
+
+    /// The locator to ask the sync peer for the headers following those it has already sent
+    pub fn get_block_locator(&self, blockchain: &Blockchain) -> Vec<String> {
+        let mut locator = match &self.fork {
+            Some(fork) => fork.get_block_locator(),
+            None => blockchain.get_block_locator(),
+        };
+        if let Some(last_header) = &self.last_header {
+            locator.insert(0, last_header.hash.clone());
+        }
+        locator
+    }
+
+    /// Requests for the blocks still to download, shared between the given peers
+    pub fn assign_requests(&mut self, peers: &[Uuid], now: Instant) -> Vec<(Uuid, Vec<String>)> {
+        self.downloader.assign(peers, now)
+    }
+
+    pub fn receive_blocks(&mut self, blocks: Vec<Block>) -> Result<()> {
+        self.downloader.receive(blocks)
+    }
+
+    /// Validates and connects every downloaded block that is next in line,
+    /// either to our chain or to the fork being synced
+    pub fn connect_blocks(&mut self, blockchain: &mut Blockchain) -> Result<()> {
+        while let Some(block) = self.downloader.next_block() {
+            let target = self.fork.as_ref().unwrap_or(blockchain);
+            // an announced block may have been connected while its download was in flight
+            if target.get_block_by_hash(&block.hash).is_some() {
+                continue;
+            }
+            match &mut self.fork {
+                Some(fork) => fork.add_block(block)?,
+                None => blockchain.add_block(block)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn expire_requests(&mut self, now: Instant) {
+        self.downloader.expire(now)
+    }
+
+    pub fn remove_peer(&mut self, peer: &Uuid) {
+        self.downloader.remove_peer(peer)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        !self.more_headers && self.downloader.is_done()
+    }
+
+    /// Completes the sync, adopting the fork if it has more accumulated work than our chain.
+    /// Returns the height our chain was replaced from, if it was.
+    pub fn finish(self, blockchain: &mut Blockchain) -> Option<usize> {
+        match self.fork {
+            Some(fork)
+                if fork.get_accumulated_difficulty() > blockchain.get_accumulated_difficulty() =>
+            {
+                let hash_at = |chain: &Blockchain, height| {
+                    chain.get_block_at_index(height).map(|b| b.hash.clone())
+                };
+                let replaced_from = (0..fork.len())
+                    .find(|height| hash_at(&fork, *height) != hash_at(blockchain, *height))
+                    .unwrap_or(fork.len());
+                *blockchain = fork;
+                Some(replaced_from)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use bytes::BytesMut;
+use futures::sync::{mpsc, oneshot};
+use log::{debug, error, info};
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::codec::{Decoder, Encoder};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+
+use crate::decoder::Headers;
+use crate::encoder::Encoding;
+use crate::processor::NodeHandle;
+use crate::tls::SessionKey;
+use crate::transport::{Connection, Dialer};
+
+/// The largest message body accepted, so a peer can't make us allocate without limit
+pub const MAX_MESSAGE_LENGTH: usize = 32 * 1024 * 1024;
+
+/// Splits a stream into messages using the length in each message's header,
+/// then checks the end marker is where that length says it should be.
+/// Outgoing messages are already encoded, so are written as they are.
+pub struct MessageCodec;
+
+impl Decoder for MessageCodec {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        if buffer.len() < Headers::Data as usize {
+            return Ok(None);
+        }
+        let mut length = [0; 16];
+        length.copy_from_slice(&buffer[Headers::MessageLength as usize..Headers::Data as usize]);
+        let length = match usize::try_from(u128::from_be_bytes(length)) {
+            Ok(length) if length <= MAX_MESSAGE_LENGTH => length,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Message length exceeds the maximum",
+                ))
+            }
+        };
+        let end_marker = Encoding::EndMessage.as_bytes();
+        let message_length = Headers::Data as usize + length + end_marker.len();
+        if buffer.len() < message_length {
+            buffer.reserve(message_length - buffer.len());
+            return Ok(None);
+        }
+        let message = buffer.split_to(message_length);
+        if !message.ends_with(end_marker) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Message does not end where its length says",
+            ));
+        }
+        Ok(Some(message.to_vec()))
+    }
+}
+
+impl Encoder for MessageCodec {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn encode(&mut self, message: Vec<u8>, buffer: &mut BytesMut) -> Result<()> {
+        buffer.extend_from_slice(&message);
+        Ok(())
+    }
+}
+
+/// A raw TCP connection, read and written by its own tasks
+pub struct TcpConnection {
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    /// Stops the reading task, after which the node forgets the connection and it is dropped
+    closed: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl Connection for TcpConnection {
+    fn send(&self, data: Vec<u8>) -> Result<()> {
+        self.outgoing
+            .unbounded_send(data)
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Connection has closed"))
+    }
+
+    fn close(&self, reason: String) -> Result<()> {
+        debug!(reason:% = reason; "Closing connection");
+        if let Some(closed) = self.closed.lock().unwrap().take() {
+            let _ = closed.send(());
+        }
+        Ok(())
+    }
+}
+
+/// Dials peers from within the runtime the node runs on
+pub struct TcpDialer {
+    handle: NodeHandle,
+}
+
+impl TcpDialer {
+    pub fn new(handle: NodeHandle) -> TcpDialer {
+        TcpDialer { handle }
+    }
+}
+
+impl Dialer for TcpDialer {
+    fn dial(&self, address: SocketAddr) -> Result<()> {
+        tokio::spawn(connect(address, self.handle.clone()));
+        Ok(())
+    }
+}
+
+/// Accepts connections from peers, each handled by its own tasks
+pub fn listen(listener: TcpListener, handle: NodeHandle) -> impl Future<Item = (), Error = ()> {
+    listener
+        .incoming()
+        .map_err(|e| error!(error:% = e; "Failed to accept connection"))
+        .for_each(move |stream| {
+            tokio::spawn(run(stream, handle.clone(), None));
+            Ok(())
+        })
+}
+
+/// Opens a connection to a peer and asks to join the network through it
+pub fn connect(address: SocketAddr, handle: NodeHandle) -> impl Future<Item = (), Error = ()> {
+    TcpStream::connect(&address)
+        .map_err(move |e| info!(address:% = address, error:% = e; "Failed to connect"))
+        .and_then(move |stream| run(stream, handle, Some(address)))
+}
+
+/// Reads messages from a peer until it disconnects or the node closes the connection,
+/// while a separate task writes whatever the node sends it.
+/// `address` is the one we dialled, if we opened the connection.
+fn run(
+    stream: TcpStream,
+    handle: NodeHandle,
+    address: Option<SocketAddr>,
+) -> impl Future<Item = (), Error = ()> {
+    let peer_addr = stream.peer_addr();
+    debug!(address:? = peer_addr; "Opening connection");
+    let (writer, reader) = MessageCodec.framed(stream).split();
+    let (outgoing, to_write) = mpsc::unbounded();
+    let (closed, on_closed) = oneshot::channel();
+    tokio::spawn(
+        writer
+            .send_all(to_write.map_err(|_| Error::new(ErrorKind::BrokenPipe, "Channel failed")))
+            .map(|_| ())
+            .map_err(|e| debug!(error:% = e; "Failed to write to peer")),
+    );
+
+    let connection = TcpConnection {
+        outgoing,
+        closed: Mutex::new(Some(closed)),
+    };
+    let remote = peer_addr.as_ref().ok().map(|address| address.ip());
+    let opened = handle.open(Arc::new(connection), SessionKey::default(), address, remote);
+    future::result(opened)
+        .and_then(|id| {
+            let receiving = handle.clone();
+            reader
+                .for_each(move |message| receiving.receive(id, message))
+                .select2(on_closed)
+                .then(move |result| {
+                    if let Err(future::Either::A((e, _))) = result {
+                        debug!(address:? = peer_addr, error:% = e; "Connection closed");
+                    }
+                    handle.close(id)
+                })
+        })
+        .map_err(|e| info!(error:% = e; "Connection failed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addrman::AddressManager;
+    use crate::encoder::Encoder as MessageEncoder;
+    use crate::identity::Identity;
+    use crate::node::Node;
+    use crate::processor::{self, Processor};
+    use crate::protocol_message::ProtocolMessage;
+    use crate::reputation::Reputation;
+    use redistribution::Network;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::Runtime;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_messages_split_by_length() {
+        let first = MessageEncoder::encode(
+            &Network::Regtest.params().magic,
+            ProtocolMessage::GetPeers,
+            Uuid::nil(),
+            &String::new(),
+        )
+        .unwrap();
+        let second = MessageEncoder::encode(
+            &Network::Regtest.params().magic,
+            ProtocolMessage::AddMe,
+            Uuid::nil(),
+            &"0x11".to_string(),
+        )
+        .unwrap();
+        let mut buffer = BytesMut::from([first.clone(), second.clone()].concat());
+        let mut codec = MessageCodec;
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(first.clone()));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(second));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+
+        buffer.extend_from_slice(&first[..first.len() - 1]);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(b"0");
+        assert!(codec.decode(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn test_nodes_connect_over_tcp() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut new_node = || {
+            let identity = Identity::generate().unwrap();
+            let node = Node::new(
+                Network::Regtest,
+                "127.0.0.1:0".to_string(),
+                Reputation::default(),
+                AddressManager::new(),
+                identity,
+            )
+            .unwrap();
+            let (handle, events) = processor::channel();
+            runtime.spawn(processor::start(Processor::new(node), events));
+            handle
+        };
+        let (server, client) = (new_node(), new_node());
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        runtime.spawn(listen(listener, server.clone()));
+        runtime.spawn(connect(address, client.clone()));
+
+        let server_id = server.call(|node| node.id).wait().unwrap();
+        let client_id = client.call(|node| node.id).wait().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let connected = loop {
+            let server_peers = server.call(|node| node.connections.peers());
+            let client_peers = client.call(|node| node.connections.peers());
+            let connected = server_peers.wait().unwrap().contains(&client_id)
+                && client_peers.wait().unwrap().contains(&server_id);
+            if connected || Instant::now() > deadline {
+                break connected;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert!(connected);
+    }
+}
@@ -0,0 +1,222 @@
+use openssl::asn1::Asn1Time;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{
+    ConnectConfiguration, SslAcceptor, SslConnector, SslMethod, SslVerifyMode, SslVersion,
+};
+use openssl::x509::{X509NameBuilder, X509StoreContextRef, X509};
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::identity::{self, Identity};
+
+/// How long our self-signed certificate is valid for
+const CERTIFICATE_DAYS: u32 = 3650;
+
+/// The public key from the certificate a peer presented when its connection was encrypted,
+/// shared between the TLS handshake and the handshake that authenticates the peer's node ID
+pub type SessionKey = Arc<Mutex<Option<Vec<u8>>>>;
+
+/// Encrypts connections between nodes with TLS 1.3.
+///
+/// Each node presents a self-signed certificate for its identity key, so there is no certificate
+/// authority to check. Instead the key behind the certificate must be the one the peer's node ID
+/// comes from, which ties the encrypted session to the peer that signs our challenge.
+pub struct Tls {
+    key: PKey<Private>,
+    certificate: X509,
+}
+
+impl Tls {
+    pub fn new(identity: &Identity) -> Result<Tls> {
+        let key = identity.private_key().clone();
+        let common_name = identity.node_id()?.to_string();
+        let certificate =
+            self_signed_certificate(&key, &common_name).map_err(identity::to_io_error)?;
+        Ok(Tls { key, certificate })
+    }
+
+    /// Encrypts connections peers open to us
+    pub fn acceptor(&self, session_key: SessionKey) -> Result<SslAcceptor> {
+        let mut builder =
+            SslAcceptor::mozilla_modern_v5(SslMethod::tls()).map_err(identity::to_io_error)?;
+        builder
+            .set_private_key(&self.key)
+            .map_err(identity::to_io_error)?;
+        builder
+            .set_certificate(&self.certificate)
+            .map_err(identity::to_io_error)?;
+        builder.set_verify_callback(
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            move |_, context| record_peer_key(context, &session_key),
+        );
+        Ok(builder.build())
+    }
+
+    /// Encrypts a connection we open to a peer.
+    /// Peers aren't known by host name, so neither the name nor SNI are used.
+    pub fn connector(&self, session_key: SessionKey) -> Result<ConnectConfiguration> {
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(identity::to_io_error)?;
+        builder
+            .set_min_proto_version(Some(SslVersion::TLS1_3))
+            .map_err(identity::to_io_error)?;
+        builder
+            .set_private_key(&self.key)
+            .map_err(identity::to_io_error)?;
+        builder
+            .set_certificate(&self.certificate)
+            .map_err(identity::to_io_error)?;
+        builder.set_verify_callback(SslVerifyMode::PEER, move |_, context| {
+            record_peer_key(context, &session_key)
+        });
+        let configuration = builder
+            .build()
+            .configure()
+            .map_err(identity::to_io_error)?
+            .verify_hostname(false)
+            .use_server_name_indication(false);
+        Ok(configuration)
+    }
+}
+
+/// The URL to reach a peer at, over TLS if connections are encrypted
+pub fn peer_url(address: &SocketAddr, encrypted: bool) -> url::Url {
+    let scheme = if encrypted { "wss" } else { "ws" };
+    url::Url::parse(&format!("{}://{}", scheme, address)).unwrap()
+}
+
+/// Accepts any certificate, as authentication happens in our own handshake,
+/// but remembers the peer's key so the handshake can check it
+fn record_peer_key(context: &mut X509StoreContextRef, session_key: &SessionKey) -> bool {
+    if context.error_depth() != 0 {
+        return true;
+    }
+    let public_key = context
+        .current_cert()
+        .and_then(|certificate| certificate.public_key().ok())
+        .and_then(|key| key.raw_public_key().ok());
+    match public_key {
+        Some(public_key) => {
+            *session_key.lock().unwrap() = Some(public_key);
+            true
+        }
+        None => false,
+    }
+}
+
+fn self_signed_certificate(
+    key: &PKey<Private>,
+    common_name: &str,
+) -> std::result::Result<X509, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", common_name)?;
+    let name = name.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(CERTIFICATE_DAYS)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    // Ed25519 signs the whole certificate itself rather than a digest of it
+    builder.sign(key, MessageDigest::null())?;
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addrman::AddressManager;
+    use crate::client::Client;
+    use crate::node::Node;
+    use crate::processor::{self, NodeHandle, Processor};
+    use crate::reputation::Reputation;
+    use crate::server::Server;
+    use futures::Future;
+    use redistribution::Network;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::Runtime;
+    use ws::{Builder, Settings};
+
+    fn new_node(runtime: &mut Runtime) -> (NodeHandle, Arc<Tls>) {
+        let identity = Identity::generate().unwrap();
+        let tls = Arc::new(Tls::new(&identity).unwrap());
+        let node = Node::new(
+            Network::Regtest,
+            "127.0.0.1:0".to_string(),
+            Reputation::default(),
+            AddressManager::new(),
+            identity,
+        )
+        .unwrap();
+        let (handle, events) = processor::channel();
+        runtime.spawn(processor::start(Processor::new(node), events));
+        (handle, tls)
+    }
+
+    #[test]
+    fn test_nodes_connect_over_tls() {
+        let mut runtime = Runtime::new().unwrap();
+        let (server_node, server_tls) = new_node(&mut runtime);
+        let (client_node, client_tls) = new_node(&mut runtime);
+
+        // the server blocks while it runs, so it is built on its own thread
+        let listening_node = server_node.clone();
+        let (bound, on_bound) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let server = Builder::new()
+                .with_settings(Settings {
+                    encrypt_server: true,
+                    ..Settings::default()
+                })
+                .build(|out| {
+                    Server::new(out, listening_node.clone(), Some(Arc::clone(&server_tls)))
+                })
+                .unwrap()
+                .bind("127.0.0.1:0")
+                .unwrap();
+            bound
+                .send((server.local_addr().unwrap(), server.broadcaster()))
+                .unwrap();
+            server.run().unwrap();
+        });
+        let (address, shutdown) = on_bound.recv().unwrap();
+
+        let connecting_node = client_node.clone();
+        let mut client = Builder::new()
+            .build(move |out| {
+                Client::new(out, connecting_node.clone(), Some(Arc::clone(&client_tls)))
+            })
+            .unwrap();
+        client.connect(peer_url(&address, true)).unwrap();
+        let client_shutdown = client.broadcaster();
+        let client_thread = thread::spawn(move || client.run().unwrap());
+
+        let server_id = server_node.call(|node| node.id).wait().unwrap();
+        let client_id = client_node.call(|node| node.id).wait().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let connected = loop {
+            let server_peers = server_node.call(|node| node.connections.peers());
+            let client_peers = client_node.call(|node| node.connections.peers());
+            let connected = server_peers.wait().unwrap().contains(&client_id)
+                && client_peers.wait().unwrap().contains(&server_id);
+            if connected || Instant::now() > deadline {
+                break connected;
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        shutdown.shutdown().unwrap();
+        client_shutdown.shutdown().unwrap();
+        server_thread.join().unwrap();
+        client_thread.join().unwrap();
+        assert!(connected);
+    }
+}
@@ -0,0 +1,212 @@
+use log::{info, warn};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::decoder::{DecodedType, Decoder};
+use crate::handshake::Handshake;
+use crate::node::Node;
+use crate::protocol_message::ProtocolMessage;
+use crate::reputation::Misbehaviour;
+use crate::tls::SessionKey;
+
+/// How nodes connect to each other. Every node in a network must use the same transport.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    /// WebSocket, which browsers can also speak
+    WebSocket,
+    /// Raw TCP, with messages delimited by the length in their header
+    Tcp,
+}
+
+/// One connection to a peer, whichever transport carries it.
+/// Sending only queues the data, so it never waits on the network.
+pub trait Connection: Send + Sync {
+    fn send(&self, data: Vec<u8>) -> Result<()>;
+
+    /// Closes the connection, the peer is forgotten once the close completes
+    fn close(&self, reason: String) -> Result<()>;
+}
+
+/// Opens connections to peers the node has chosen, over whichever transport it uses.
+/// Dialling only starts the connection, which is handed to the node once open.
+pub trait Dialer: Send {
+    fn dial(&self, address: SocketAddr) -> Result<()>;
+}
+
+/// The protocol spoken over a single connection: the handshake, then passing each message to the node
+/// and delivering its replies. Transports only need to hand over each message received.
+pub struct Session {
+    connection: Arc<dyn Connection>,
+    handshake: Handshake,
+    peer: Option<Uuid>,
+    /// The service flags from the peer's version
+    services: u64,
+    /// The address we dialled, if we opened the connection.
+    /// We ask to join the network through connections we open once the handshake completes.
+    address: Option<SocketAddr>,
+    /// The address the connection came from, if the transport knows it
+    remote: Option<IpAddr>,
+    opened_at: Instant,
+}
+
+impl Session {
+    pub fn new(
+        connection: Arc<dyn Connection>,
+        session_key: SessionKey,
+        address: Option<SocketAddr>,
+        remote: Option<IpAddr>,
+        opened_at: Instant,
+    ) -> Session {
+        Session {
+            connection,
+            handshake: Handshake::with_session_key(session_key),
+            peer: None,
+            services: 0,
+            address,
+            remote,
+            opened_at,
+        }
+    }
+
+    pub fn is_inbound(&self) -> bool {
+        self.address.is_none()
+    }
+
+    /// Whether the peer has had longer than `timeout` to complete the handshake and still hasn't
+    pub fn handshake_expired(&self, now: Instant, timeout: Duration) -> bool {
+        !self.handshake.is_complete() && now.duration_since(self.opened_at) > timeout
+    }
+
+    /// Sends our version once the connection is open,
+    /// as nothing else is exchanged until both sides have accepted each other's version.
+    /// Connections from or to a banned address are refused before anything is sent.
+    pub fn open(&mut self, node: &mut Node) -> Result<()> {
+        if let Some(remote) = self.remote.filter(|remote| node.is_address_banned(remote)) {
+            info!(address:% = remote; "Refusing connection with banned address");
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "Peer's address is banned",
+            ));
+        }
+        let challenge = self.handshake.new_challenge()?;
+        self.connection.send(node.version(&challenge)?)
+    }
+
+    pub fn receive(&mut self, node: &mut Node, mut data: Vec<u8>) -> Result<()> {
+        let protocol = match (Decoder::protocol(&mut data[..]), self.peer) {
+            (Ok(protocol), _) => protocol,
+            (Err(e), Some(peer)) => {
+                node.misbehaving(&peer, &e)?;
+                return Ok(());
+            }
+            (Err(e), None) => return Err(e),
+        };
+        node.metrics.received(&protocol, data.len());
+        // a node on another network isn't misbehaving, we just have nothing to say to each other
+        if !Decoder::has_magic(&data, &node.params().magic) {
+            return self
+                .connection
+                .close(String::from("Peer is on another network"));
+        }
+        if let Err(e) = self.handshake.check(&protocol, &mut data) {
+            return self.connection.close(e.to_string());
+        }
+
+        let result = node.handle_message(&mut data);
+        match result {
+            Ok(message) => {
+                if let Some(data) = message.raw_message {
+                    self.connection.send(data)?;
+                }
+
+                relay(node, message.direct);
+
+                if protocol == ProtocolMessage::Version {
+                    let mut decoder = Decoder::new(&mut data[..], ProtocolMessage::Version);
+                    self.peer = Some(decoder.peer_id());
+                    if let DecodedType::Version(version) = decoder.decode_json()? {
+                        self.services = version.services;
+                    }
+                }
+                if self.handshake.record(&protocol) {
+                    if let Some(peer) = self.peer {
+                        let connection = Arc::clone(&self.connection);
+                        let direct = node.add_connection(
+                            peer,
+                            connection,
+                            self.address,
+                            self.remote,
+                            self.services,
+                        )?;
+                        relay(node, direct);
+                    }
+                    if self.address.is_some() {
+                        for message in node.join_network()? {
+                            self.connection.send(message)?;
+                        }
+                    } else {
+                        // a peer connecting to us may be ahead of us too, such as after we lost our connections
+                        self.connection.send(node.get_headers()?)?;
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                if protocol == ProtocolMessage::Version {
+                    return self.connection.close(e.to_string());
+                }
+                // peers are penalised for bad messages rather than dropped, unless that gets them banned
+                match self.peer {
+                    Some(peer) if Misbehaviour::of(&e).is_some() => {
+                        node.misbehaving(&peer, &e)?;
+                        Ok(())
+                    }
+                    _ => Err(e),
+                }
+            }
+        }
+    }
+
+    /// Closes the connection after an error we couldn't recover from
+    pub fn abort(&self, error: &Error) {
+        info!(error:% = error; "Closing connection after error");
+        if let Err(e) = self.connection.close(error.to_string()) {
+            warn!(error:% = e; "Failed to close connection");
+        }
+    }
+
+    /// Closes the connection as the node is stopping
+    pub fn shut_down(&self) {
+        if let Err(e) = self.connection.close(String::from("Node is shutting down")) {
+            warn!(error:% = e; "Failed to close connection");
+        }
+    }
+
+    /// Forgets the peer once its connection has closed
+    pub fn close(&mut self, node: &mut Node) {
+        if let Some(peer) = self.peer.take() {
+            match node.remove_connection(&peer, &self.connection) {
+                Ok(direct) => {
+                    for (peer, data) in direct {
+                        if let Err(e) = node.connections.send(&peer, data) {
+                            warn!(peer:% = peer, error:% = e; "Failed to reassign requests");
+                        }
+                    }
+                }
+                Err(e) => warn!(peer:% = peer, error:% = e; "Failed to remove connection"),
+            }
+        }
+    }
+}
+
+/// Sends messages addressed to other peers. Another peer's connection failing is no reason to drop this one.
+fn relay(node: &Node, messages: Vec<(Uuid, Vec<u8>)>) {
+    for (peer, data) in messages {
+        if let Err(e) = node.connections.send(&peer, data) {
+            warn!(peer:% = peer, error:% = e; "Failed to relay message");
+        }
+    }
+}
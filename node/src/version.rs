@@ -0,0 +1,103 @@
+use redistribution::{Decodable, Encodable};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Result};
+
+/// The version of the wire protocol spoken by this node
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The oldest protocol version we are still able to talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Service flag - the node stores the full chain and can serve blocks to peers
+pub const NODE_NETWORK: u64 = 1;
+
+/// Service flag - the node mines the transactions peers pass it
+pub const NODE_MINING: u64 = 2;
+
+pub const USER_AGENT: &str = concat!("/redistribution-node:", env!("CARGO_PKG_VERSION"), "/");
+
+/// Sent by both sides as soon as a connection opens.
+/// No other messages are processed until each side has acknowledged the other's version,
+/// by signing the challenge it carries with the key pair behind its public key.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    pub version: u32,
+    pub best_height: u32,
+    pub genesis_hash: String,
+    pub services: u64,
+    pub user_agent: String,
+    /// Hex encoded public key the sender's node ID is derived from
+    pub public_key: String,
+    /// Hex encoded random bytes the receiver must sign in its `VerAck`
+    pub challenge: String,
+}
+
+impl Version {
+    pub fn new(
+        best_height: u32,
+        genesis_hash: String,
+        services: u64,
+        public_key: &[u8],
+        challenge: &[u8],
+    ) -> Version {
+        Version {
+            version: PROTOCOL_VERSION,
+            best_height,
+            genesis_hash,
+            services,
+            user_agent: USER_AGENT.to_string(),
+            public_key: hex::encode(public_key),
+            challenge: hex::encode(challenge),
+        }
+    }
+
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        hex::decode(&self.public_key)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Version public key is not hex"))
+    }
+
+    pub fn challenge(&self) -> Result<Vec<u8>> {
+        hex::decode(&self.challenge)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Version challenge is not hex"))
+    }
+
+    /// Checks the peer is running a protocol we understand on the same network as us
+    pub fn check_compatible(&self, genesis_hash: &str) -> Result<()> {
+        if self.genesis_hash != genesis_hash {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "Peer is on an incompatible network - genesis block does not match",
+            ));
+        }
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "Peer protocol version is no longer supported",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Encodable for Version {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let serialized = serde_json::to_string(&self)?;
+        Ok(serialized.into_bytes())
+    }
+}
+
+impl Decodable for Version {
+    fn decode(bytes: &Vec<u8>) -> Result<Self> {
+        let json_string_result = String::from_utf8(bytes.clone());
+        match json_string_result {
+            Ok(json_string) => {
+                let deserialized: Version = serde_json::from_str(&json_string)?;
+                Ok(deserialized)
+            }
+            Err(_) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unable to decode Version - bytes not valid utf8",
+            )),
+        }
+    }
+}
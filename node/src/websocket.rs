@@ -0,0 +1,56 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+
+extern crate ws;
+use ws::{CloseCode, Sender};
+
+use crate::tls;
+use crate::transport::{Connection, Dialer};
+
+/// A WebSocket connection, opened by either our `Client` or our `Server`
+pub struct WebSocket {
+    sender: Sender,
+}
+
+impl WebSocket {
+    pub fn new(sender: Sender) -> WebSocket {
+        WebSocket { sender }
+    }
+}
+
+impl Connection for WebSocket {
+    fn send(&self, data: Vec<u8>) -> Result<()> {
+        self.sender.send(data).map_err(to_io_error)
+    }
+
+    fn close(&self, reason: String) -> Result<()> {
+        self.sender
+            .close_with_reason(CloseCode::Policy, reason)
+            .map_err(to_io_error)
+    }
+}
+
+/// Dials peers through a WebSocket instance whose connections are handled by our `Client`
+pub struct WebSocketDialer {
+    sender: Sender,
+    encrypted: bool,
+}
+
+impl WebSocketDialer {
+    /// `sender` is the instance's broadcaster, as dialling isn't tied to an existing connection
+    pub fn new(sender: Sender, encrypted: bool) -> WebSocketDialer {
+        WebSocketDialer { sender, encrypted }
+    }
+}
+
+impl Dialer for WebSocketDialer {
+    fn dial(&self, address: SocketAddr) -> Result<()> {
+        self.sender
+            .connect(tls::peer_url(&address, self.encrypted))
+            .map_err(to_io_error)
+    }
+}
+
+fn to_io_error(e: ws::Error) -> Error {
+    Error::new(ErrorKind::BrokenPipe, e.to_string())
+}
@@ -21,6 +21,25 @@ pub struct Block {
     pub nonce: u128,
 }
 
+/// Everything in a block except its data, used to sync the shape of a chain before downloading it.
+/// The hash still commits to the data, so a body is checked against its header once it arrives.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub index: u32,
+    pub timestamp: Duration,
+    pub hash: String,
+    pub previous_hash: String,
+    pub difficulty: u32,
+    pub nonce: u128,
+}
+
+impl BlockHeader {
+    /// Whether this header directly extends `previous`
+    pub fn follows(&self, previous: &BlockHeader) -> bool {
+        previous.index + 1 == self.index && previous.hash == self.previous_hash
+    }
+}
+
 impl PartialEq for Block {
     fn eq(&self, other: &Self) -> bool {
         self.index == other.index
@@ -52,27 +71,29 @@ impl Block {
         }
     }
 
-    pub fn genesis_block() -> Block {
-        let timestamp = Duration::new(0, 0);
-        let difficulty: u32 = 0;
-        let nonce: u128 = 0;
-        let hash = calculate_hash(
-            &0,
-            &String::new(),
-            &timestamp,
-            &String::new(),
-            &difficulty,
-            &nonce,
-        );
-        Block::new(
-            0,
-            timestamp,
-            String::new(),
-            hash,
-            String::new(),
-            difficulty,
-            nonce,
-        )
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            hash: self.hash.clone(),
+            previous_hash: self.previous_hash.clone(),
+            difficulty: self.difficulty,
+            nonce: self.nonce,
+        }
+    }
+
+    /// What the block carries, in order. Mined blocks hold a JSON array of entries, their miner's reward
+    /// first if it has one; any other data, such as the genesis block's allocations, is a single entry.
+    pub fn entries(&self) -> Vec<BlockData> {
+        if self.data.is_empty() {
+            return vec![];
+        }
+        serde_json::from_str(&self.data).unwrap_or_else(|_e| vec![self.data.clone()])
+    }
+
+    /// The data of a block carrying `entries`
+    pub fn data_from_entries(entries: &[BlockData]) -> Result<BlockData> {
+        Ok(serde_json::to_string(entries)?)
     }
 
     pub fn calculate_hash_for_block(block: &Block) -> String {
@@ -1,6 +1,10 @@
+use crate::block::BlockData;
+use crate::chain_params::{ChainParams, Network};
+use crate::difficulty::{hash_matches_difficulty, next_difficulty};
 use crate::encoder;
-use crate::hasher;
+use crate::findblock::{find_block, BlockTemplate};
 use crate::timestamp;
+use crate::transaction::Transaction;
 use crate::Block;
 use encoder::{Decodable, Encodable};
 use serde::{Deserialize, Serialize};
@@ -10,26 +14,43 @@ use std::io::{Error, ErrorKind, Result};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Blockchain {
+    network: Network,
     blocks: VecDeque<Block>,
 }
 
 impl Blockchain {
-    pub fn new() -> Blockchain {
-        let genesis_block = Block::genesis_block();
+    pub fn new(network: Network) -> Blockchain {
+        Blockchain::starting_from(network, network.params().genesis_block())
+    }
+
+    /// A chain from a genesis block other than the network's, such as one just mined by a `Genesis`
+    pub fn starting_from(network: Network, genesis_block: Block) -> Blockchain {
         let mut blocks = VecDeque::new();
         blocks.push_back(genesis_block);
-        Blockchain { blocks }
+        Blockchain { network, blocks }
+    }
+
+    /// The rules of the network this chain belongs to
+    pub fn params(&self) -> &'static ChainParams {
+        self.network.params()
     }
 
     pub fn add_block(&mut self, block: Block) -> Result<()> {
         let last_block_result = self.blocks.back();
         match last_block_result {
             Some(last_block) => {
-                if Blockchain::is_valid_new_block(&block, last_block) {
-                    Ok(self.blocks.push_back(block))
-                } else {
-                    Err(Error::new(ErrorKind::InvalidData, "Invalid block"))
+                if !Blockchain::is_valid_new_block(&block, last_block) {
+                    return Err(Error::new(ErrorKind::InvalidData, "Invalid block"));
                 }
+                if block.difficulty != next_difficulty(self)?
+                    || !hash_matches_difficulty(&block.hash, &block.difficulty)?
+                {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Block is not mined to the chain's difficulty",
+                    ));
+                }
+                Ok(self.blocks.push_back(block))
             }
             None => Err(Error::new(
                 ErrorKind::InvalidData,
@@ -38,29 +59,68 @@ impl Blockchain {
         }
     }
 
-    pub fn generate_next_block(&self, block_data: &str) -> Result<Block> {
+    /// Checks a block we can't connect yet is mined as it claims, to a difficulty it could have so near our tip.
+    /// The difficulty moves a bit at most each adjustment, so can't have fallen further than the adjustments between them.
+    pub fn check_proof_of_work(&self, block: &Block) -> Result<()> {
+        if Block::calculate_hash_for_block(block) != block.hash {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Block hash does not match its contents",
+            ));
+        }
+        let tip = self.get_latest_block()?;
+        let adjustments = match self.params().difficulty_adjustment_interval {
+            0 => 0,
+            interval => block.index.abs_diff(tip.index) / interval + 1,
+        };
+        if block.difficulty < tip.difficulty.saturating_sub(adjustments)
+            || !hash_matches_difficulty(&block.hash, &block.difficulty)?
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Block is not mined to a difficulty near the chain's",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Mines a block carrying the entries on top of the chain, paying the block reward to `reward_to` if given
+    pub fn generate_next_block(
+        &self,
+        entries: &[BlockData],
+        reward_to: Option<&str>,
+    ) -> Result<Block> {
+        let template = self.next_block_template(entries, reward_to)?;
+        Ok(find_block(
+            template.index,
+            template.previous_hash,
+            template.timestamp,
+            template.data,
+            template.difficulty,
+        ))
+    }
+
+    /// The next block on our tip carrying the entries, left to be mined elsewhere
+    pub fn next_block_template(
+        &self,
+        entries: &[BlockData],
+        reward_to: Option<&str>,
+    ) -> Result<BlockTemplate> {
         let timestamp = timestamp::get_current_timestamp()?;
         let previous_block = self.get_latest_block()?;
-        let new_block_index = previous_block.index + 1;
-        let difficulty: u32 = 0;
-        let nonce: u128 = 0;
-        let hash = hasher::calculate_hash(
-            &new_block_index,
-            &previous_block.hash,
-            &timestamp,
-            block_data,
-            &difficulty,
-            &nonce,
-        );
-        Ok(Block::new(
-            new_block_index,
+        let index = previous_block.index + 1;
+        let mut carried = vec![];
+        if let Some(address) = reward_to {
+            carried.push(Transaction::reward(address.to_string(), index).to_data()?);
+        }
+        carried.extend(entries.iter().cloned());
+        Ok(BlockTemplate {
+            index,
+            previous_hash: previous_block.hash.clone(),
             timestamp,
-            block_data.to_string(),
-            hash,
-            previous_block.hash.clone(),
-            difficulty,
-            nonce,
-        ))
+            data: Block::data_from_entries(&carried)?,
+            difficulty: next_difficulty(self)?,
+        })
     }
 
     pub fn get_latest_block(&self) -> Result<&Block> {
@@ -78,10 +138,65 @@ impl Blockchain {
         self.blocks.get(index)
     }
 
+    pub fn get_block_by_hash(&self, hash: &str) -> Option<&Block> {
+        self.blocks.iter().rev().find(|block| block.hash == hash)
+    }
+
+    /// Every block from the genesis block to the tip
+    pub fn iter(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.iter()
+    }
+
     pub fn len(&self) -> usize {
         self.blocks.len()
     }
 
+    /// Hashes describing our chain to a peer, from the tip backwards.
+    /// The first ten are consecutive, then the step doubles, always ending with the genesis block,
+    /// so a peer can find our common ancestor in a small message however long the chains are.
+    pub fn get_block_locator(&self) -> Vec<String> {
+        let mut locator = vec![];
+        let mut index = self.blocks.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(self.blocks[index].hash.clone());
+            if index == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Finds the most recent block in a peer's locator that is also in our chain
+    pub fn find_common_ancestor(&self, locator: &[String]) -> Option<&Block> {
+        locator
+            .iter()
+            .filter_map(|hash| self.get_block_by_hash(hash))
+            .next()
+    }
+
+    /// Hashes of up to `limit` blocks following the block at `index`
+    pub fn get_hashes_after(&self, index: usize, limit: usize) -> Vec<String> {
+        self.blocks
+            .iter()
+            .skip(index + 1)
+            .take(limit)
+            .map(|block| block.hash.clone())
+            .collect()
+    }
+
+    /// A copy of this chain up to and including the block at `index`, used to build a competing fork
+    pub fn fork_at(&self, index: usize) -> Blockchain {
+        Blockchain {
+            network: self.network,
+            blocks: self.blocks.iter().take(index + 1).cloned().collect(),
+        }
+    }
+
     fn is_valid_new_block(new_block: &Block, previous_block: &Block) -> bool {
         if previous_block.index + 1 != new_block.index {
             return false;
@@ -93,15 +208,20 @@ impl Blockchain {
         true
     }
 
+    /// Whether the chain starts from its network's genesis block and every block follows the one before it
     pub fn is_chain_valid(blockchain: &Blockchain) -> bool {
-        // TODO: need to check genesis block somehow
-        blockchain
+        let starts_from_genesis = blockchain
             .blocks
-            .iter()
-            .skip(1)
-            .zip(blockchain.blocks.iter())
-            .map(|(block, last_block)| block.previous_hash == last_block.hash)
-            .fold(true, |x, y| x && y)
+            .front()
+            .is_some_and(|block| block.hash == blockchain.params().genesis_hash);
+        starts_from_genesis
+            && blockchain
+                .blocks
+                .iter()
+                .skip(1)
+                .zip(blockchain.blocks.iter())
+                .map(|(block, last_block)| block.previous_hash == last_block.hash)
+                .fold(true, |x, y| x && y)
     }
 
     pub fn determine_longest_chain<'a>(
@@ -170,24 +290,119 @@ mod tests {
 
     #[test]
     fn test_new_block_validity() {
-        let blockchain = Blockchain::new();
+        let blockchain = Blockchain::new(Network::Regtest);
         let genesis_block = blockchain.get_latest_block().unwrap();
-        let next_block = blockchain.generate_next_block("Test block data!").unwrap();
+        let next_block = blockchain
+            .generate_next_block(&[String::from("Test block data!")], None)
+            .unwrap();
         let block_is_valid = Blockchain::is_valid_new_block(&next_block, &genesis_block);
         assert_eq!(block_is_valid, true);
     }
 
     #[test]
     fn test_chain_validity() {
-        let mut blockchain = Blockchain::new();
-        let new_block1 = blockchain.generate_next_block("Block 1");
+        let mut blockchain = Blockchain::new(Network::Regtest);
+        let new_block1 = blockchain.generate_next_block(&[String::from("Block 1")], None);
         blockchain.add_block(new_block1.unwrap());
-        let new_block2 = blockchain.generate_next_block("Block 2");
+        let new_block2 = blockchain.generate_next_block(&[String::from("Block 2")], None);
         blockchain.add_block(new_block2.unwrap());
-        let new_block3 = blockchain.generate_next_block("Block 3");
+        let new_block3 = blockchain.generate_next_block(&[String::from("Block 3")], None);
         blockchain.add_block(new_block3.unwrap());
 
         let validity = Blockchain::is_chain_valid(&blockchain);
         assert_eq!(validity, true);
     }
+
+    #[test]
+    fn test_block_locator() {
+        let mut blockchain = Blockchain::new(Network::Regtest);
+        for i in 1..50 {
+            let block = blockchain
+                .generate_next_block(&[i.to_string()], None)
+                .unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        let locator = blockchain.get_block_locator();
+        let heights: Vec<u32> = locator
+            .iter()
+            .map(|hash| blockchain.get_block_by_hash(hash).unwrap().index)
+            .collect();
+        assert_eq!(
+            heights,
+            vec![49, 48, 47, 46, 45, 44, 43, 42, 41, 40, 38, 34, 26, 10, 0]
+        );
+    }
+
+    #[test]
+    fn test_common_ancestor_of_fork() {
+        let mut blockchain = Blockchain::new(Network::Regtest);
+        for i in 1..5 {
+            let block = blockchain
+                .generate_next_block(&[i.to_string()], None)
+                .unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        let mut fork = blockchain.fork_at(2);
+        let block = fork
+            .generate_next_block(&[String::from("fork")], None)
+            .unwrap();
+        fork.add_block(block).unwrap();
+
+        let ancestor = blockchain
+            .find_common_ancestor(&fork.get_block_locator())
+            .unwrap();
+        assert_eq!(ancestor.index, 2);
+        assert_eq!(
+            blockchain
+                .get_hashes_after(ancestor.index as usize, 10)
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_difficulty_rises_when_blocks_come_fast() {
+        let mut blockchain = Blockchain::new(Network::Testnet);
+        let params = blockchain.params();
+        for i in 1..=params.difficulty_adjustment_interval {
+            let block = blockchain
+                .generate_next_block(&[i.to_string()], None)
+                .unwrap();
+            assert_eq!(block.difficulty, params.initial_difficulty);
+            blockchain.add_block(block).unwrap();
+        }
+        // the interval took moments rather than ten minutes
+        let block = blockchain
+            .generate_next_block(&[String::from("faster")], None)
+            .unwrap();
+        assert_eq!(block.difficulty, params.initial_difficulty + 1);
+
+        let mut easier = block.clone();
+        easier.difficulty = params.initial_difficulty;
+        easier.hash = Block::calculate_hash_for_block(&easier);
+        assert!(blockchain.add_block(easier).is_err());
+        blockchain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_unconnected_blocks_checked_for_work() {
+        let blockchain = Blockchain::new(Network::Testnet);
+        let tip = blockchain.get_latest_block().unwrap();
+        let mine = |difficulty| {
+            find_block(
+                tip.index + 3,
+                String::from("unknown parent"),
+                tip.timestamp,
+                String::from("ahead"),
+                difficulty,
+            )
+        };
+        let mined = mine(tip.difficulty);
+        assert!(blockchain.check_proof_of_work(&mined).is_ok());
+
+        let mut forged = mined.clone();
+        forged.data = String::from("something else");
+        assert!(blockchain.check_proof_of_work(&forged).is_err());
+        assert!(blockchain.check_proof_of_work(&mine(0)).is_err());
+    }
 }
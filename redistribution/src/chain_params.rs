@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::block::Block;
+use crate::hasher::calculate_hash;
+
+/// Which chain a node follows. Nodes only talk to peers on the same one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Network {
+    pub fn params(self) -> &'static ChainParams {
+        match self {
+            Network::Mainnet => &MAINNET,
+            Network::Testnet => &TESTNET,
+            Network::Regtest => &REGTEST,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Network, ()> {
+        match name {
+            "mainnet" | "main" => Ok(Network::Mainnet),
+            "testnet" | "test" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Everything that makes one network's chain different from another's
+#[derive(Debug)]
+pub struct ChainParams {
+    pub network: Network,
+    /// Starts every message between nodes, so a node never mistakes a peer on another network for its own
+    pub magic: [u8; 4],
+    /// Where nodes listen for peers unless told otherwise
+    pub default_port: u16,
+    /// Where new nodes first look for peers
+    pub seeds: &'static [&'static str],
+    /// The genesis block's fields, as mined by a `Genesis`
+    pub genesis_timestamp: Duration,
+    pub genesis_data: &'static str,
+    pub genesis_nonce: u128,
+    /// The hash every chain on the network must start from. Peers whose genesis block differs are refused.
+    pub genesis_hash: &'static str,
+    /// Leading zero bits the genesis block and the first blocks after it are mined to,
+    /// before the difficulty is first adjusted
+    pub initial_difficulty: u32,
+    /// How long blocks should take to mine on average, which adjustments steer the difficulty towards
+    pub target_block_time: Duration,
+    /// How many blocks pass between difficulty adjustments, or 0 for the difficulty never to change
+    pub difficulty_adjustment_interval: u32,
+}
+
+impl ChainParams {
+    /// The block every chain on this network starts from, rebuilt from its fields
+    pub fn genesis_block(&self) -> Block {
+        let hash = calculate_hash(
+            &0,
+            "",
+            &self.genesis_timestamp,
+            self.genesis_data,
+            &self.initial_difficulty,
+            &self.genesis_nonce,
+        );
+        Block::new(
+            0,
+            self.genesis_timestamp,
+            self.genesis_data.to_string(),
+            hash,
+            String::new(),
+            self.initial_difficulty,
+            self.genesis_nonce,
+        )
+    }
+}
+
+// genesis blocks are mined with `redistribution-cli genesis`, which prints these fields
+static MAINNET: ChainParams = ChainParams {
+    network: Network::Mainnet,
+    magic: [0x52, 0x44, 0x42, 0xd9],
+    default_port: 7878,
+    seeds: &["127.0.0.1:7878"],
+    genesis_timestamp: Duration::from_secs(1767225600),
+    genesis_data: "",
+    genesis_nonce: 1408,
+    genesis_hash: "0009bf84d2ee5fea37748bc05103a649801ece7927d53ee345c14d684f0442e2",
+    initial_difficulty: 12,
+    target_block_time: Duration::from_secs(600),
+    difficulty_adjustment_interval: 10,
+};
+
+static TESTNET: ChainParams = ChainParams {
+    network: Network::Testnet,
+    magic: [0x52, 0x44, 0x42, 0x7e],
+    default_port: 17878,
+    seeds: &["127.0.0.1:17878"],
+    genesis_timestamp: Duration::from_secs(1767312000),
+    genesis_data: "",
+    genesis_nonce: 40,
+    genesis_hash: "002239d60a0a62d5b412aae20a0c8f0f926b28bb6a558a5e980b0035369c02c8",
+    initial_difficulty: 8,
+    target_block_time: Duration::from_secs(60),
+    difficulty_adjustment_interval: 10,
+};
+
+/// A network for tests, whose blocks are mined instantly and which never looks for peers itself
+static REGTEST: ChainParams = ChainParams {
+    network: Network::Regtest,
+    magic: [0x52, 0x44, 0x42, 0xfa],
+    default_port: 27878,
+    seeds: &[],
+    genesis_timestamp: Duration::from_secs(1767398400),
+    genesis_data: "",
+    genesis_nonce: 0,
+    genesis_hash: "7f8e5823de574eff678c16f943adbd9c30b046a1298ec4fab8d89867add821e2",
+    initial_difficulty: 0,
+    target_block_time: Duration::from_secs(1),
+    difficulty_adjustment_interval: 0,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difficulty::hash_matches_difficulty;
+
+    #[test]
+    fn test_genesis_blocks_match_their_hashes() {
+        for network in [Network::Mainnet, Network::Testnet, Network::Regtest].iter() {
+            let params = network.params();
+            let genesis_block = params.genesis_block();
+            assert_eq!(genesis_block.hash, params.genesis_hash, "{}", network);
+            assert!(
+                hash_matches_difficulty(&genesis_block.hash, &params.initial_difficulty).unwrap()
+            );
+        }
+    }
+}
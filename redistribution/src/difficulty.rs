@@ -1,5 +1,6 @@
 use std::io::{Error, ErrorKind, Result};
-use std::time::Duration;
+
+use crate::Blockchain;
 
 pub fn hash_matches_difficulty(hash: &String, difficulty: &u32) -> Result<bool> {
     let decoded_hex_result = hex::decode(hash);
@@ -30,38 +31,38 @@ pub fn hash_matches_difficulty(hash: &String, difficulty: &u32) -> Result<bool>
     }
 }
 
-// in seconds
-const BLOCK_GENERATION_INTERVAL_SECS: u64 = 600;
-
-// in blocks
-const DIFFICULTY_ADJUSTMENT_INTERVAL: u32 = 10;
-
-use crate::block;
-use crate::blockchain;
-
-fn get_difficulty(blockchain: blockchain::Blockchain) -> u32 {
-    let latest_block = blockchain.get_latest_block().unwrap();
-    if latest_block.index % DIFFICULTY_ADJUSTMENT_INTERVAL == 0 && latest_block.index != 0 {
-        return get_adjusted_difficulty(latest_block, &blockchain);
-    } else {
-        return latest_block.difficulty;
+/// The difficulty the block after the chain's tip must be mined to.
+/// It only changes every `difficulty_adjustment_interval` blocks, by one bit at a time,
+/// when the last interval's blocks came more than twice as fast or slow as the network's target.
+pub fn next_difficulty(chain: &Blockchain) -> Result<u32> {
+    let params = chain.params();
+    let latest_block = chain.get_latest_block()?;
+    let interval = params.difficulty_adjustment_interval;
+    if interval == 0 || latest_block.index == 0 || latest_block.index % interval != 0 {
+        return Ok(latest_block.difficulty);
     }
-}
-
-fn get_adjusted_difficulty(latest_block: &block::Block, chain: &blockchain::Blockchain) -> u32 {
-    let BLOCK_GENERATION_INTERVAL: Duration = Duration::new(BLOCK_GENERATION_INTERVAL_SECS, 0);
 
+    // timed from the first mined block, as the genesis block's timestamp is fixed before mining starts
     let previous_adjustment_block = chain
-        .get_block_at_index(chain.len() - DIFFICULTY_ADJUSTMENT_INTERVAL as usize)
-        .unwrap(); // TODO: handle
-    let time_expected = BLOCK_GENERATION_INTERVAL * DIFFICULTY_ADJUSTMENT_INTERVAL;
-    let time_taken = latest_block.timestamp - previous_adjustment_block.timestamp;
+        .get_block_at_index((latest_block.index - interval).max(1) as usize)
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                "Unable to locate the block of the previous adjustment",
+            )
+        })?;
+    let time_expected =
+        params.target_block_time * (latest_block.index - previous_adjustment_block.index);
+    let time_taken = latest_block
+        .timestamp
+        .checked_sub(previous_adjustment_block.timestamp)
+        .unwrap_or_default();
     if time_taken < time_expected / 2 {
-        return previous_adjustment_block.difficulty + 1;
-    } else if time_taken > time_expected / 2 {
-        return previous_adjustment_block.difficulty - 1;
+        Ok(latest_block.difficulty + 1)
+    } else if time_taken > time_expected * 2 {
+        Ok(latest_block.difficulty.saturating_sub(1))
     } else {
-        return previous_adjustment_block.difficulty;
+        Ok(latest_block.difficulty)
     }
 }
 
@@ -1,3 +1,5 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::io::{Error, ErrorKind, Result};
 use uuid::Uuid;
 
@@ -70,6 +72,29 @@ impl Decodable for Uuid {
     }
 }
 
+impl<T: Serialize> Encodable for Vec<T> {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let serialized = serde_json::to_string(&self)?;
+        Ok(serialized.into_bytes())
+    }
+}
+
+impl<T: DeserializeOwned> Decodable for Vec<T> {
+    fn decode(bytes: &Vec<u8>) -> Result<Self> {
+        let json_string_result = String::from_utf8(bytes.clone());
+        match json_string_result {
+            Ok(json_string) => {
+                let deserialized: Vec<T> = serde_json::from_str(&json_string)?;
+                Ok(deserialized)
+            }
+            Err(_) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unable to decode Vec - bytes not valid utf8",
+            )),
+        }
+    }
+}
+
 pub trait Encodable {
     fn encode(&self) -> Result<Vec<u8>>;
 }
@@ -4,38 +4,70 @@ use crate::hasher;
 use block::{Block, BlockData};
 use difficulty::hash_matches_difficulty;
 use hasher::calculate_hash;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-fn find_block(
+/// A block with everything but its nonce decided, ready to be mined
+#[derive(Clone, Debug)]
+pub struct BlockTemplate {
+    pub index: u32,
+    pub previous_hash: String,
+    pub timestamp: Duration,
+    pub data: BlockData,
+    pub difficulty: u32,
+}
+
+impl BlockTemplate {
+    /// Tries nonces until the block's hash has `difficulty` leading zero bits,
+    /// giving up once `cancelled` is set, such as when the tip it builds on is replaced
+    pub fn mine(self, cancelled: &AtomicBool) -> Option<Block> {
+        let mut nonce: u128 = 0;
+
+        loop {
+            if cancelled.load(Ordering::Relaxed) {
+                return None;
+            }
+            let hash: String = calculate_hash(
+                &self.index,
+                &self.previous_hash,
+                &self.timestamp,
+                &self.data,
+                &self.difficulty,
+                &nonce,
+            );
+
+            if hash_matches_difficulty(&hash, &self.difficulty).unwrap() {
+                return Some(Block {
+                    index: self.index,
+                    timestamp: self.timestamp,
+                    data: self.data,
+                    hash,
+                    previous_hash: self.previous_hash,
+                    difficulty: self.difficulty,
+                    nonce,
+                });
+            }
+            nonce += 1;
+        }
+    }
+}
+
+/// Mines a block, trying nonces until its hash has `difficulty` leading zero bits
+pub fn find_block(
     index: u32,
     previous_hash: String,
     timestamp: Duration,
     data: BlockData,
     difficulty: u32,
 ) -> Block {
-    let mut nonce: u128 = 0;
-
-    loop {
-        let hash: String = calculate_hash(
-            &index,
-            &previous_hash,
-            &timestamp,
-            &data,
-            &difficulty,
-            &nonce,
-        );
-
-        if hash_matches_difficulty(&hash, &difficulty).unwrap() {
-            break Block {
-                index,
-                timestamp,
-                data,
-                hash,
-                previous_hash,
-                difficulty,
-                nonce,
-            };
-        }
-        nonce += 1;
-    }
+    let template = BlockTemplate {
+        index,
+        previous_hash,
+        timestamp,
+        data,
+        difficulty,
+    };
+    template
+        .mine(&AtomicBool::new(false))
+        .expect("mining only stops early when cancelled")
 }
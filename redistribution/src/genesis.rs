@@ -0,0 +1,83 @@
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use crate::block::{Block, BlockData};
+use crate::findblock::find_block;
+use crate::keys::ADDRESS_LENGTH;
+use crate::transaction::{Output, Transaction};
+
+/// What a new network's first block is made from. Once mined, the block's fields and hash
+/// are written into the network's `ChainParams`, so every node starts from the same block.
+#[derive(Clone, Debug)]
+pub struct Genesis {
+    pub timestamp: Duration,
+    /// Outputs that exist before anything is mined, created by the genesis block's transaction
+    pub allocations: Vec<Output>,
+    /// Leading zero bits the genesis block and the blocks after it are mined to
+    pub difficulty: u32,
+}
+
+impl Genesis {
+    /// The data the genesis block carries: a transaction paying out the allocations, or nothing without any
+    pub fn data(&self) -> Result<BlockData> {
+        if self.allocations.is_empty() {
+            return Ok(BlockData::new());
+        }
+        for allocation in self.allocations.iter() {
+            let is_address = allocation.address.len() == ADDRESS_LENGTH * 2
+                && hex::decode(&allocation.address).is_ok();
+            if !is_address || allocation.amount == 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Allocation of {} to {} must pay something to an address",
+                        allocation.amount, allocation.address
+                    ),
+                ));
+            }
+        }
+        Transaction::new(vec![], self.allocations.clone()).to_data()
+    }
+
+    /// Mines the genesis block, which may take a while at a high difficulty
+    pub fn mine(&self) -> Result<Block> {
+        Ok(find_block(
+            0,
+            String::new(),
+            self.timestamp,
+            self.data()?,
+            self.difficulty,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difficulty::hash_matches_difficulty;
+    use crate::{Blockchain, KeyPair, Ledger, Network};
+
+    #[test]
+    fn test_allocations_spendable_from_genesis() {
+        let address = KeyPair::generate().unwrap().address().unwrap();
+        let genesis = Genesis {
+            timestamp: Duration::from_secs(1_767_225_600),
+            allocations: vec![Output {
+                address: address.clone(),
+                amount: 1_000_000,
+            }],
+            difficulty: 4,
+        };
+        let block = genesis.mine().unwrap();
+        assert!(hash_matches_difficulty(&block.hash, &4).unwrap());
+        assert_eq!(block.hash, Block::calculate_hash_for_block(&block));
+
+        // allocations aren't held to the block reward, which only limits mined blocks
+        let blockchain = Blockchain::starting_from(Network::Regtest, block);
+        assert_eq!(Ledger::scan(&blockchain).balance(&address), 1_000_000);
+
+        let mut unfunded = genesis.clone();
+        unfunded.allocations[0].address = String::from("nobody");
+        assert!(unfunded.mine().is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use openssl::pkey::{Id, PKey, Private, Public};
+use openssl::sha;
+use openssl::sign::{Signer, Verifier};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Result, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// How many bytes of a public key's hash make up its address
+pub const ADDRESS_LENGTH: usize = 20;
+
+/// Where outputs are paid, the hex of the start of the owning public key's hash
+pub type Address = String;
+
+/// An Ed25519 key pair, which owns the outputs paid to its address
+pub struct KeyPair {
+    key: PKey<Private>,
+}
+
+impl KeyPair {
+    pub fn generate() -> Result<KeyPair> {
+        let key = PKey::generate_ed25519().map_err(Error::other)?;
+        Ok(KeyPair { key })
+    }
+
+    /// The key pair for a raw 32 byte Ed25519 private key, as derived by a wallet
+    pub fn from_private_key(private_key: &[u8]) -> Result<KeyPair> {
+        let key =
+            PKey::private_key_from_raw_bytes(private_key, Id::ED25519).map_err(Error::other)?;
+        Ok(KeyPair { key })
+    }
+
+    pub fn from_pem(pem: &[u8]) -> Result<KeyPair> {
+        let key = PKey::private_key_from_pem(pem).map_err(Error::other)?;
+        Ok(KeyPair { key })
+    }
+
+    pub fn to_pem(&self) -> Result<Vec<u8>> {
+        self.key.private_key_to_pem_pkcs8().map_err(Error::other)
+    }
+
+    /// Writes the key pair to a new file only its owner can read, never replacing an existing one
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut file = private_file(path)?;
+        file.write_all(&self.to_pem()?)
+    }
+
+    pub fn private_key(&self) -> &PKey<Private> {
+        &self.key
+    }
+
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        self.key.raw_public_key().map_err(Error::other)
+    }
+
+    pub fn address(&self) -> Result<Address> {
+        Ok(address(&self.public_key()?))
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut signer = Signer::new_without_digest(&self.key).map_err(Error::other)?;
+        signer.sign_oneshot_to_vec(data).map_err(Error::other)
+    }
+}
+
+/// Creates a new file for secrets, readable only by its owner where permissions allow
+pub fn private_file(path: &Path) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
+
+/// The address outputs owned by a public key are paid to
+pub fn address(public_key: &[u8]) -> Address {
+    hex::encode(&sha::sha256(public_key)[..ADDRESS_LENGTH])
+}
+
+/// Checks a signature was made over the data by the key pair the public key belongs to
+pub fn verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let key = match PKey::<Public>::public_key_from_raw_bytes(public_key, Id::ED25519) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let verified = match Verifier::new_without_digest(&key) {
+        Ok(mut verifier) => verifier.verify_oneshot(signature, data).unwrap_or(false),
+        Err(_) => false,
+    };
+    verified
+}
@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+
+use crate::keys::{self, Address};
+use crate::transaction::{transaction_id, OutPoint, Output, Transaction, BLOCK_REWARD};
+use crate::{Block, Blockchain};
+
+/// An output not yet spent, with where to find it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Unspent {
+    pub out_point: OutPoint,
+    pub output: Output,
+}
+
+/// The outputs a chain has not yet spent, found by replaying the transactions its blocks carry.
+/// Entries that aren't valid transactions are still part of the chain, but move no value.
+#[derive(Clone, Debug, Default)]
+pub struct Ledger {
+    unspent: BTreeMap<OutPoint, Output>,
+    /// Every address ever paid, spent or not
+    received: HashSet<Address>,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    pub fn scan(blockchain: &Blockchain) -> Ledger {
+        let mut ledger = Ledger::new();
+        for block in blockchain.iter() {
+            ledger.apply_block(block);
+        }
+        ledger
+    }
+
+    /// Applies the valid transactions a block carries, which must be the next on the chain
+    pub fn apply_block(&mut self, block: &Block) {
+        for (position, entry) in block.entries().iter().enumerate() {
+            let transaction = match Transaction::from_data(entry) {
+                Some(transaction) => transaction,
+                None => continue,
+            };
+            let valid = match (block.index, position) {
+                // the genesis block's allocations are fixed by the network, so aren't limited to a block reward
+                (0, _) => transaction.is_reward(),
+                (_, 0) if transaction.is_reward() => validate_reward(&transaction).is_ok(),
+                _ => self.validate(&transaction).is_ok(),
+            };
+            if valid {
+                self.apply(&transaction_id(entry), &transaction);
+            }
+        }
+    }
+
+    /// Checks the transaction could be the next on this chain.
+    /// Rewards are refused, as only a block's miner may add one, when mining it.
+    pub fn validate(&self, transaction: &Transaction) -> Result<()> {
+        if transaction.is_reward() {
+            return Err(invalid("Rewards are only added by the miner of a block"));
+        }
+        let paid = outputs_total(transaction)?;
+
+        let mut spent = HashSet::new();
+        let mut available: u64 = 0;
+        for input in transaction.inputs.iter() {
+            if !spent.insert(&input.previous) {
+                return Err(invalid("Transaction spends an output twice"));
+            }
+            let output = self
+                .unspent
+                .get(&input.previous)
+                .ok_or_else(|| invalid("Transaction spends an unknown or spent output"))?;
+            let public_key =
+                hex::decode(&input.public_key).map_err(|_e| invalid("Public key is not hex"))?;
+            if keys::address(&public_key) != output.address {
+                return Err(invalid("Input is not signed by the output's owner"));
+            }
+            available = available
+                .checked_add(output.amount)
+                .ok_or_else(|| invalid("Transaction inputs overflow"))?;
+        }
+        if available < paid {
+            return Err(invalid("Transaction pays out more than it spends"));
+        }
+        if !transaction.verify_signatures() {
+            return Err(invalid("Transaction signature is invalid"));
+        }
+        Ok(())
+    }
+
+    /// Spends the transaction's inputs and adds its outputs, which must have been validated
+    pub fn apply(&mut self, id: &str, transaction: &Transaction) {
+        for input in transaction.inputs.iter() {
+            self.unspent.remove(&input.previous);
+        }
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            self.received.insert(output.address.clone());
+            let out_point = OutPoint {
+                transaction: id.to_string(),
+                output: index as u32,
+            };
+            self.unspent.insert(out_point, output.clone());
+        }
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.unspent
+            .values()
+            .filter(|output| output.address == address)
+            .map(|output| output.amount)
+            .sum()
+    }
+
+    /// Whether the address has ever been paid, even if it has spent everything since
+    pub fn has_received(&self, address: &str) -> bool {
+        self.received.contains(address)
+    }
+
+    pub fn unspent(&self, address: &str) -> Vec<Unspent> {
+        self.unspent
+            .iter()
+            .filter(|(_, output)| output.address == address)
+            .map(|(out_point, output)| Unspent {
+                out_point: out_point.clone(),
+                output: output.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Checks a block's first entry pays its miner no more than the block reward
+fn validate_reward(transaction: &Transaction) -> Result<()> {
+    if outputs_total(transaction)? > BLOCK_REWARD {
+        return Err(invalid("Reward is more than a block is worth"));
+    }
+    Ok(())
+}
+
+fn outputs_total(transaction: &Transaction) -> Result<u64> {
+    if transaction.outputs.is_empty() || transaction.outputs.iter().any(|o| o.amount == 0) {
+        return Err(invalid("Transaction must pay something to every output"));
+    }
+    transaction
+        .output_total()
+        .ok_or_else(|| invalid("Transaction outputs overflow"))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyPair;
+    use crate::Network;
+
+    fn pay(address: &str, amount: u64) -> Output {
+        Output {
+            address: address.to_string(),
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_outputs_spent_once_by_owner() {
+        let alice = KeyPair::generate().unwrap();
+        let bob = KeyPair::generate().unwrap();
+        let (alice_address, bob_address) = (alice.address().unwrap(), bob.address().unwrap());
+        let mut blockchain = Blockchain::new(Network::Regtest);
+        let mut add = |transaction: &Transaction, reward_to: Option<&str>| {
+            let block = blockchain
+                .generate_next_block(&[transaction.to_data().unwrap()], reward_to)
+                .unwrap();
+            blockchain.add_block(block.clone()).unwrap();
+            (
+                transaction_id(&block.entries()[0]),
+                Ledger::scan(&blockchain),
+            )
+        };
+
+        // a reward is only paid as the first entry of a block, to its miner
+        let claim = Transaction::reward(bob_address.clone(), 1);
+        let (reward, ledger) = add(&claim, Some(&alice_address));
+        assert_eq!(ledger.balance(&alice_address), BLOCK_REWARD);
+        assert_eq!(ledger.balance(&bob_address), 0);
+        assert!(ledger.validate(&claim).is_err());
+        let owned = vec![OutPoint {
+            transaction: reward,
+            output: 0,
+        }];
+
+        // only alice can spend her reward, and only once
+        let outputs = vec![pay(&bob_address, 30), pay(&alice_address, 15)];
+        let mut payment = Transaction::new(owned.clone(), outputs);
+        let mut forged = payment.clone();
+        forged.sign(&bob, &owned).unwrap();
+        assert!(ledger.validate(&forged).is_err());
+        assert_eq!(payment.sign(&alice, &owned).unwrap(), 1);
+        assert!(ledger.validate(&payment).is_ok());
+
+        let (_, ledger) = add(&payment, None);
+        assert_eq!(ledger.balance(&alice_address), 15);
+        assert_eq!(ledger.balance(&bob_address), 30);
+        assert!(ledger.validate(&payment).is_err());
+
+        // a block replaying the spent output moves nothing
+        let mut replay = Transaction::new(owned.clone(), vec![pay(&bob_address, 50)]);
+        replay.sign(&alice, &owned).unwrap();
+        let (_, ledger) = add(&replay, None);
+        assert_eq!(ledger.balance(&bob_address), 30);
+
+        // nor does a reward worth more than a block
+        let mut too_much = Transaction::reward(bob_address.clone(), 4);
+        too_much.outputs[0].amount = BLOCK_REWARD + 1;
+        let (_, ledger) = add(&too_much, None);
+        assert_eq!(ledger.balance(&bob_address), 30);
+    }
+}
@@ -1,13 +1,27 @@
 mod block;
 mod blockchain;
+mod chain_params;
 mod difficulty;
 mod encoder;
 mod findblock;
+mod genesis;
 mod hasher;
+mod keys;
+mod ledger;
 mod timestamp;
+mod transaction;
+mod wallet;
 
 pub use blockchain::Blockchain;
+pub use chain_params::{ChainParams, Network};
 pub use encoder::{Decodable, Encodable};
+pub use findblock::BlockTemplate;
+pub use genesis::Genesis;
+pub use keys::{address, verify, Address, KeyPair, ADDRESS_LENGTH};
+pub use ledger::{Ledger, Unspent};
+pub use transaction::{transaction_id, Input, OutPoint, Output, Transaction, BLOCK_REWARD};
+pub use wallet::{select_outputs, Chain, Wallet};
 
 pub use block::Block;
 pub use block::BlockData;
+pub use block::BlockHeader;
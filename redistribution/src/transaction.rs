@@ -0,0 +1,149 @@
+use openssl::sha;
+use serde::{Deserialize, Serialize};
+use std::io::Result;
+
+use crate::block::BlockData;
+use crate::keys::{self, Address, KeyPair};
+
+/// What the miner of a block is paid, by a transaction without inputs as the block's first entry.
+/// Any other transaction without inputs moves nothing.
+pub const BLOCK_REWARD: u64 = 50;
+
+/// An output of an earlier transaction, by that transaction's ID and the output's position in it
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutPoint {
+    pub transaction: String,
+    pub output: u32,
+}
+
+/// Spends an output, proving ownership with the key its address was derived from
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Input {
+    pub previous: OutPoint,
+    /// Hex of the raw Ed25519 public key
+    pub public_key: String,
+    /// Hex of the signature over the transaction with every input's key and signature left empty
+    pub signature: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Output {
+    pub address: Address,
+    pub amount: u64,
+}
+
+/// A transfer of value, carried as the JSON data of a block.
+/// Whatever the inputs hold beyond the outputs is burnt, as there is nobody to pay it to.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transaction {
+    pub inputs: Vec<Input>,
+    pub outputs: Vec<Output>,
+    /// Tells apart transactions that would otherwise be identical, such as two rewards to one address
+    pub nonce: u64,
+}
+
+impl Transaction {
+    /// An unsigned transaction spending `previous` into `outputs`
+    pub fn new(previous: Vec<OutPoint>, outputs: Vec<Output>) -> Transaction {
+        let inputs = previous
+            .into_iter()
+            .map(|previous| Input {
+                previous,
+                public_key: String::new(),
+                signature: String::new(),
+            })
+            .collect();
+        Transaction {
+            inputs,
+            outputs,
+            nonce: 0,
+        }
+    }
+
+    /// Pays the block reward for mining the block at `height`, which tells apart rewards to one address
+    pub fn reward(address: Address, height: u32) -> Transaction {
+        Transaction {
+            inputs: vec![],
+            outputs: vec![Output {
+                address,
+                amount: BLOCK_REWARD,
+            }],
+            nonce: u64::from(height),
+        }
+    }
+
+    pub fn is_reward(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Reads the transaction a block carries, if its data is one
+    pub fn from_data(data: &str) -> Option<Transaction> {
+        serde_json::from_str(data).ok()
+    }
+
+    pub fn to_data(&self) -> Result<BlockData> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// The total paid out, or None if it overflows
+    pub fn output_total(&self) -> Option<u64> {
+        self.outputs
+            .iter()
+            .try_fold(0u64, |total, output| total.checked_add(output.amount))
+    }
+
+    /// Signs the inputs spending `owned` outputs, which must be paid to the key pair's address,
+    /// returning how many it signed
+    pub fn sign(&mut self, key: &KeyPair, owned: &[OutPoint]) -> Result<usize> {
+        let public_key = hex::encode(key.public_key()?);
+        let signature = hex::encode(key.sign(&self.signing_data()?)?);
+        let mut signed = 0;
+        for input in self.inputs.iter_mut() {
+            if owned.contains(&input.previous) {
+                input.public_key = public_key.clone();
+                input.signature = signature.clone();
+                signed += 1;
+            }
+        }
+        Ok(signed)
+    }
+
+    /// Whether every input is signed by the public key it names
+    pub fn verify_signatures(&self) -> bool {
+        let signing_data = match self.signing_data() {
+            Ok(signing_data) => signing_data,
+            Err(_) => return false,
+        };
+        self.inputs.iter().all(|input| {
+            match (
+                hex::decode(&input.public_key),
+                hex::decode(&input.signature),
+            ) {
+                (Ok(public_key), Ok(signature)) => {
+                    keys::verify(&public_key, &signing_data, &signature)
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// What each input signs, the transaction without any keys or signatures so inputs can be
+    /// signed in any order. A key can't be swapped afterwards as it must match the spent address.
+    fn signing_data(&self) -> Result<Vec<u8>> {
+        let mut unsigned = self.clone();
+        for input in unsigned.inputs.iter_mut() {
+            input.public_key.clear();
+            input.signature.clear();
+        }
+        Ok(serde_json::to_vec(&unsigned)?)
+    }
+}
+
+/// The ID of the transaction carried as `data`, the hex of its hash
+pub fn transaction_id(data: &BlockData) -> String {
+    hex::encode(sha::sha256(data.as_bytes()))
+}
@@ -0,0 +1,534 @@
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sha;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+use crate::keys::{self, Address, KeyPair};
+use crate::ledger::{Ledger, Unspent};
+use crate::transaction::{OutPoint, Output, Transaction};
+use crate::Blockchain;
+
+/// How many random bytes a wallet is generated from, each one a word of its seed phrase
+pub const ENTROPY_LENGTH: usize = 16;
+
+/// How many never-paid addresses in a row end the search for a restored wallet's addresses
+pub const GAP_LIMIT: u32 = 20;
+
+/// PBKDF2 rounds turning a keystore's password into the key its secret is encrypted with
+pub const KEYSTORE_ITERATIONS: usize = 200_000;
+
+const KEYSTORE_VERSION: u32 = 1;
+const SEED_SALT: &[u8] = b"redistribution seed";
+const SEED_ITERATIONS: usize = 2048;
+const HARDENED: u32 = 0x8000_0000;
+
+/// Addresses handed out to be paid at, and those a wallet only pays its own change to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Chain {
+    Receiving = 0,
+    Change = 1,
+}
+
+/// A private key and chain code, from which hardened children are derived as in SLIP-0010 for Ed25519
+struct ExtendedKey {
+    key: Vec<u8>,
+    chain_code: Vec<u8>,
+}
+
+impl ExtendedKey {
+    fn master(seed: &[u8]) -> Result<ExtendedKey> {
+        ExtendedKey::hmac(b"ed25519 seed", seed)
+    }
+
+    fn child(&self, index: u32) -> Result<ExtendedKey> {
+        let mut data = vec![0];
+        data.extend_from_slice(&self.key);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        ExtendedKey::hmac(&self.chain_code, &data)
+    }
+
+    fn hmac(key: &[u8], data: &[u8]) -> Result<ExtendedKey> {
+        let key = PKey::hmac(key).map_err(Error::other)?;
+        let mut signer = Signer::new(MessageDigest::sha512(), &key).map_err(Error::other)?;
+        signer.update(data).map_err(Error::other)?;
+        let mut key = signer.sign_to_vec().map_err(Error::other)?;
+        let chain_code = key.split_off(32);
+        Ok(ExtendedKey { key, chain_code })
+    }
+}
+
+struct Derived {
+    key: KeyPair,
+    address: Address,
+}
+
+/// What a keystore file holds. Only the wallet's entropy is secret, and is encrypted with AES-256-GCM
+/// under a key stretched from the password. How many addresses have been derived is kept in the clear,
+/// authenticated with the entropy, so opening the wallet brings them back without a search.
+#[derive(Serialize, Deserialize)]
+struct Keystore {
+    version: u32,
+    receiving: u32,
+    change: u32,
+    iterations: usize,
+    salt: String,
+    nonce: String,
+    tag: String,
+    ciphertext: String,
+}
+
+impl Keystore {
+    fn authenticated_data(&self) -> Vec<u8> {
+        format!("{}:{}:{}", self.version, self.receiving, self.change).into_bytes()
+    }
+}
+
+/// A hierarchical deterministic wallet. Every key is derived from one seed phrase at m/0'/chain'/index',
+/// so the phrase alone restores them all. The phrase is this wallet's own encoding rather than BIP-39,
+/// a word for each byte of entropy followed by a checksum word.
+pub struct Wallet {
+    entropy: Vec<u8>,
+    account: ExtendedKey,
+    receiving: Vec<Derived>,
+    change: Vec<Derived>,
+}
+
+impl Wallet {
+    pub fn generate() -> Result<Wallet> {
+        let mut entropy = vec![0; ENTROPY_LENGTH];
+        rand_bytes(&mut entropy).map_err(Error::other)?;
+        Wallet::from_entropy(entropy)
+    }
+
+    /// Restores a wallet from its seed phrase, though only its first addresses until `discover` is run
+    pub fn from_phrase(phrase: &str) -> Result<Wallet> {
+        let mut entropy = phrase
+            .split_whitespace()
+            .map(
+                |word| match WORDS.iter().position(|w| *w == word.to_lowercase()) {
+                    Some(index) => Ok(index as u8),
+                    None => Err(invalid(&format!("Unknown word in seed phrase: {}", word))),
+                },
+            )
+            .collect::<Result<Vec<u8>>>()?;
+        if entropy.len() != ENTROPY_LENGTH + 1 {
+            return Err(invalid(&format!(
+                "A seed phrase has {} words",
+                ENTROPY_LENGTH + 1
+            )));
+        }
+        let checksum = entropy.pop();
+        if checksum != Some(checksum_of(&entropy)) {
+            return Err(invalid(
+                "Seed phrase checksum doesn't match, check the words",
+            ));
+        }
+        Wallet::from_entropy(entropy)
+    }
+
+    fn from_entropy(entropy: Vec<u8>) -> Result<Wallet> {
+        let mut seed = [0; 64];
+        pbkdf2_hmac(
+            &entropy,
+            SEED_SALT,
+            SEED_ITERATIONS,
+            MessageDigest::sha512(),
+            &mut seed,
+        )
+        .map_err(Error::other)?;
+        let account = ExtendedKey::master(&seed)?.child(0)?;
+        Ok(Wallet {
+            entropy,
+            account,
+            receiving: vec![],
+            change: vec![],
+        })
+    }
+
+    /// The words that restore this wallet, which should be written down and kept safe
+    pub fn phrase(&self) -> String {
+        let mut words: Vec<&str> = self
+            .entropy
+            .iter()
+            .map(|byte| WORDS[*byte as usize])
+            .collect();
+        words.push(WORDS[checksum_of(&self.entropy) as usize]);
+        words.join(" ")
+    }
+
+    /// Reads a keystore, failing if the password is wrong or the file was changed
+    pub fn open(path: &Path, password: &str) -> Result<Wallet> {
+        let keystore: Keystore = serde_json::from_slice(&fs::read(path)?)?;
+        if keystore.version != KEYSTORE_VERSION {
+            return Err(invalid("Unsupported keystore version"));
+        }
+        let decode = |field: &str| hex::decode(field).map_err(|_e| invalid("Keystore is not hex"));
+        let (salt, nonce, tag) = (
+            decode(&keystore.salt)?,
+            decode(&keystore.nonce)?,
+            decode(&keystore.tag)?,
+        );
+        let key = stretch(password, &salt, keystore.iterations)?;
+        let entropy = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&nonce),
+            &keystore.authenticated_data(),
+            &decode(&keystore.ciphertext)?,
+            &tag,
+        )
+        .map_err(|_e| {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                "Wrong password, or the keystore has been changed",
+            )
+        })?;
+
+        let mut wallet = Wallet::from_entropy(entropy)?;
+        for _ in 0..keystore.receiving {
+            wallet.derive(Chain::Receiving)?;
+        }
+        for _ in 0..keystore.change {
+            wallet.derive(Chain::Change)?;
+        }
+        Ok(wallet)
+    }
+
+    /// Writes the wallet to a keystore, replacing the file only once it is completely written
+    pub fn save(&self, path: &Path, password: &str) -> Result<()> {
+        let (mut salt, mut nonce, mut tag) = (vec![0; 16], vec![0; 12], vec![0; 16]);
+        rand_bytes(&mut salt).map_err(Error::other)?;
+        rand_bytes(&mut nonce).map_err(Error::other)?;
+        let mut keystore = Keystore {
+            version: KEYSTORE_VERSION,
+            receiving: self.receiving.len() as u32,
+            change: self.change.len() as u32,
+            iterations: KEYSTORE_ITERATIONS,
+            salt: hex::encode(&salt),
+            nonce: hex::encode(&nonce),
+            tag: String::new(),
+            ciphertext: String::new(),
+        };
+        let key = stretch(password, &salt, keystore.iterations)?;
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&nonce),
+            &keystore.authenticated_data(),
+            &self.entropy,
+            &mut tag,
+        )
+        .map_err(Error::other)?;
+        keystore.tag = hex::encode(&tag);
+        keystore.ciphertext = hex::encode(&ciphertext);
+
+        // the keystore is only as strong as its password, so other users mustn't get to guess at it
+        let partial = path.with_extension("partial");
+        if let Err(e) = fs::remove_file(&partial) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        keys::private_file(&partial)?.write_all(&serde_json::to_vec_pretty(&keystore)?)?;
+        fs::rename(&partial, path)
+    }
+
+    /// Derives the next address on a chain
+    pub fn derive(&mut self, chain: Chain) -> Result<Address> {
+        let index = self.chain(chain).len() as u32;
+        let private_key = self.account.child(chain as u32)?.child(index)?.key;
+        let key = KeyPair::from_private_key(&private_key)?;
+        let address = key.address()?;
+        self.chain(chain).push(Derived {
+            key,
+            address: address.clone(),
+        });
+        Ok(address)
+    }
+
+    fn chain(&mut self, chain: Chain) -> &mut Vec<Derived> {
+        match chain {
+            Chain::Receiving => &mut self.receiving,
+            Chain::Change => &mut self.change,
+        }
+    }
+
+    /// Every address derived so far, receiving addresses first
+    pub fn addresses(&self) -> Vec<&Address> {
+        self.receiving
+            .iter()
+            .chain(self.change.iter())
+            .map(|derived| &derived.address)
+            .collect()
+    }
+
+    /// Derives addresses on both chains until `GAP_LIMIT` in a row have never been paid,
+    /// which finds every address a restored wallet had used. Addresses derived before are kept.
+    pub fn discover<F>(&mut self, mut received: F) -> Result<()>
+    where
+        F: FnMut(&str) -> Result<bool>,
+    {
+        for chain in [Chain::Receiving, Chain::Change].iter() {
+            let derived = self.chain(*chain).len();
+            let mut unused = 0;
+            for index in 0..derived {
+                let address = self.chain(*chain)[index].address.clone();
+                unused = if received(&address)? { 0 } else { unused + 1 };
+            }
+            while unused < GAP_LIMIT {
+                let address = self.derive(*chain)?;
+                unused = if received(&address)? { 0 } else { unused + 1 };
+            }
+            let used = self.chain(*chain).len() - unused as usize;
+            self.chain(*chain).truncate(used.max(derived));
+        }
+        Ok(())
+    }
+
+    /// The outputs the wallet's addresses hold on a chain
+    pub fn scan(&self, blockchain: &Blockchain) -> Vec<Unspent> {
+        self.owned(&Ledger::scan(blockchain))
+    }
+
+    pub fn owned(&self, ledger: &Ledger) -> Vec<Unspent> {
+        self.addresses()
+            .into_iter()
+            .flat_map(|address| ledger.unspent(address))
+            .collect()
+    }
+
+    /// Pays `amount` to `to` from the wallet's `unspent` outputs, signed by the keys owning those spent.
+    /// Whatever the chosen outputs hold beyond the amount goes to a new change address,
+    /// so the wallet must be saved afterwards.
+    pub fn pay(&mut self, unspent: Vec<Unspent>, to: &str, amount: u64) -> Result<Transaction> {
+        if amount == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Nothing to pay"));
+        }
+        let available: u64 = unspent.iter().map(|u| u.output.amount).sum();
+        let (selected, total) = select_outputs(unspent, amount).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Wallet holds {}, not enough to pay {}", available, amount),
+            )
+        })?;
+        let addresses = self.addresses();
+        if let Some(foreign) = selected
+            .iter()
+            .find(|u| !addresses.contains(&&u.output.address))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Output paid to {} isn't the wallet's",
+                    foreign.output.address
+                ),
+            ));
+        }
+
+        let mut outputs = vec![Output {
+            address: to.to_string(),
+            amount,
+        }];
+        if total > amount {
+            outputs.push(Output {
+                address: self.derive(Chain::Change)?,
+                amount: total - amount,
+            });
+        }
+        let spent = selected.iter().map(|u| u.out_point.clone()).collect();
+        let mut transaction = Transaction::new(spent, outputs);
+        for derived in self.receiving.iter().chain(self.change.iter()) {
+            let owned: Vec<OutPoint> = selected
+                .iter()
+                .filter(|u| u.output.address == derived.address)
+                .map(|u| u.out_point.clone())
+                .collect();
+            if !owned.is_empty() {
+                transaction.sign(&derived.key, &owned)?;
+            }
+        }
+        Ok(transaction)
+    }
+}
+
+/// Chooses outputs covering `amount`, returning them with their total, or None if all of them fall short.
+/// The smallest output covering it alone is preferred, so large outputs aren't broken up for small payments,
+/// otherwise outputs are taken largest first so as few as possible are spent.
+pub fn select_outputs(mut unspent: Vec<Unspent>, amount: u64) -> Option<(Vec<Unspent>, u64)> {
+    unspent.sort_by_key(|u| u.output.amount);
+    if let Some(index) = unspent.iter().position(|u| u.output.amount >= amount) {
+        let output = unspent.swap_remove(index);
+        let total = output.output.amount;
+        return Some((vec![output], total));
+    }
+    let (mut selected, mut total) = (vec![], 0u64);
+    for output in unspent.into_iter().rev() {
+        total = total.saturating_add(output.output.amount);
+        selected.push(output);
+        if total >= amount {
+            return Some((selected, total));
+        }
+    }
+    None
+}
+
+fn stretch(password: &str, salt: &[u8], iterations: usize) -> Result<Vec<u8>> {
+    let mut key = vec![0; 32];
+    pbkdf2_hmac(
+        password.as_bytes(),
+        salt,
+        iterations,
+        MessageDigest::sha256(),
+        &mut key,
+    )
+    .map_err(Error::other)?;
+    Ok(key)
+}
+
+fn checksum_of(entropy: &[u8]) -> u8 {
+    sha::sha256(entropy)[0]
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// The seed phrase words, one for each byte value
+#[rustfmt::skip]
+const WORDS: [&str; 256] = [
+    "able", "acid", "actor", "adapt", "agent", "alarm", "album", "alley", "amber", "angle",
+    "ankle", "apple", "apron", "arena", "armor", "arrow", "atlas", "attic", "autumn", "award",
+    "badge", "baker", "bamboo", "banjo", "barrel", "basin", "basket", "beach", "beacon", "beetle",
+    "bench", "berry", "bicycle", "blanket", "blossom", "board", "bonnet", "border", "bottle",
+    "bracket", "branch", "bread", "breeze", "brick", "bridge", "bronze", "brush", "bubble",
+    "bucket", "budget", "buffalo", "bundle", "butter", "button", "cabin", "cactus", "camel",
+    "canal", "candle", "canoe", "canvas", "canyon", "carbon", "carpet", "castle", "cattle",
+    "cellar", "cement", "cherry", "chimney", "circle", "citrus", "clover", "cobalt", "cocoa",
+    "coffee", "comet", "copper", "coral", "cotton", "cradle", "crater", "crayon", "cricket",
+    "crystal", "cupboard", "curtain", "cushion", "dagger", "dancer", "delta", "desert", "dial",
+    "diamond", "dinner", "dolphin", "donkey", "dragon", "drawer", "dune", "eagle", "echo", "elbow",
+    "ember", "engine", "falcon", "feather", "ferry", "fiddle", "finch", "flute", "forest",
+    "fossil", "fountain", "fox", "garden", "garlic", "gazelle", "geyser", "ginger", "glacier",
+    "granite", "gravel", "guitar", "hammer", "harbor", "harvest", "hazel", "helmet", "hollow",
+    "honey", "horizon", "hornet", "igloo", "indigo", "island", "ivory", "jacket", "jaguar",
+    "jasmine", "jelly", "jungle", "kayak", "kernel", "kettle", "kitten", "koala", "ladder",
+    "lagoon", "lantern", "lemon", "lizard", "lobster", "locket", "lotus", "lumber", "magnet",
+    "mango", "maple", "marble", "meadow", "melon", "meteor", "mirror", "mitten", "mosaic",
+    "muffin", "museum", "napkin", "nectar", "needle", "nickel", "noodle", "nutmeg", "oasis",
+    "ocean", "olive", "onion", "orbit", "orchid", "otter", "oyster", "paddle", "palace", "panda",
+    "parrot", "pebble", "pepper", "pigeon", "pillow", "pilot", "pine", "planet", "pocket",
+    "pollen", "pony", "poppy", "potato", "pumpkin", "puzzle", "quarry", "quartz", "quill",
+    "rabbit", "radish", "raven", "ribbon", "river", "rocket", "saddle", "salmon", "sandal",
+    "scarf", "scooter", "shadow", "shovel", "silver", "sketch", "sparrow", "spider", "spinach",
+    "sponge", "spruce", "squirrel", "stable", "statue", "summit", "sunset", "swan", "tablet",
+    "teapot", "temple", "thistle", "thunder", "tiger", "timber", "tomato", "topaz", "tractor",
+    "trumpet", "tulip", "tunnel", "turnip", "umbrella", "valley", "velvet", "violin", "volcano",
+    "wagon", "walnut", "walrus", "willow", "window", "wizard", "yacht", "zebra",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Genesis, Network};
+    use std::env;
+    use std::time::Duration;
+
+    #[test]
+    fn test_restored_from_phrase_and_keystore() {
+        let mut wallet = Wallet::generate().unwrap();
+        let first = wallet.derive(Chain::Receiving).unwrap();
+        let change = wallet.derive(Chain::Change).unwrap();
+
+        let restored = Wallet::from_phrase(&wallet.phrase().to_uppercase()).unwrap();
+        assert_eq!(restored.phrase(), wallet.phrase());
+        let phrase = wallet.phrase();
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+        let checksum = words.pop().unwrap();
+        words.push(if checksum == WORDS[0] {
+            WORDS[1]
+        } else {
+            WORDS[0]
+        });
+        assert!(Wallet::from_phrase(&words.join(" ")).is_err());
+
+        let path = env::temp_dir().join(format!("keystore-{}.json", first));
+        wallet.save(&path, "password").unwrap();
+        assert!(Wallet::open(&path, "wrong").is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let opened = Wallet::open(&path, "password").unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(opened.addresses(), vec![&first, &change]);
+
+        // a restored wallet searches past addresses that were never paid
+        let mut restored = restored;
+        let mut checked = 0;
+        restored
+            .discover(|address| {
+                checked += 1;
+                Ok(checked == 5 || address == change)
+            })
+            .unwrap();
+        assert_eq!(restored.receiving.len(), 5);
+        assert_eq!(restored.change.len(), 1);
+    }
+
+    #[test]
+    fn test_pay_selects_outputs_and_returns_change() {
+        let mut wallet = Wallet::generate().unwrap();
+        let (first, second) = (
+            wallet.derive(Chain::Receiving).unwrap(),
+            wallet.derive(Chain::Receiving).unwrap(),
+        );
+        let allocations = [(&first, 10), (&second, 40), (&first, 30)]
+            .iter()
+            .map(|(address, amount)| Output {
+                address: address.to_string(),
+                amount: *amount,
+            })
+            .collect();
+        let genesis = Genesis {
+            timestamp: Duration::from_secs(1_767_225_600),
+            allocations,
+            difficulty: 0,
+        };
+        let blockchain = Blockchain::starting_from(Network::Regtest, genesis.mine().unwrap());
+        let amounts = |selected: Option<(Vec<Unspent>, u64)>| -> Vec<u64> {
+            selected
+                .unwrap()
+                .0
+                .iter()
+                .map(|u| u.output.amount)
+                .collect()
+        };
+        assert_eq!(
+            amounts(select_outputs(wallet.scan(&blockchain), 25)),
+            vec![30]
+        );
+        assert_eq!(
+            amounts(select_outputs(wallet.scan(&blockchain), 50)),
+            vec![40, 30]
+        );
+        assert!(select_outputs(wallet.scan(&blockchain), 81).is_none());
+
+        // spending from two addresses, both keys sign
+        let payment = wallet
+            .pay(wallet.scan(&blockchain), "elsewhere", 65)
+            .unwrap();
+        let ledger = Ledger::scan(&blockchain);
+        assert!(ledger.validate(&payment).is_ok());
+        assert_eq!(payment.outputs[1].address, wallet.change[0].address);
+        assert_eq!(payment.outputs[1].amount, 5);
+    }
+}